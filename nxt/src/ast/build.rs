@@ -1,12 +1,16 @@
+use super::string;
 use super::*;
-use parser::RawExpr;
+use parser::{node_span, RawExpr};
+use rnix::types::{
+    Dynamic, EntryHolder, Ident, IndexSet, InterpolPart, OpKind, TypedNode, UnaryOpKind, Wrapper,
+};
 use rnix::value::{self, ValueError};
 
 use codemap::File;
 use directories::BaseDirs;
 use hashbrown::hash_map::Entry;
 use hashbrown::HashMap;
-use rnix::parser::Types;
+use rnix::parser::{Node, Types};
 use rnix::value::Anchor;
 use rowan::TreeRoot;
 use std::path::Path;
@@ -20,7 +24,16 @@ pub struct Builder<'arenas, 'a> {
     /// The current scope stack.
     scopes: Vec<Scope>,
     /// Maps `Variable` IDs to their `VarInfo`.
-    variables: Vec<VarInfo<'arenas>>,
+    variables: &'a mut Variables<'arenas>,
+    /// The stack of lambdas whose body is currently being translated.
+    lambdas: Vec<LambdaScope>,
+    /// Maps variables to the number of lambdas they're nested in.
+    ///
+    /// Variables with level 0 are globals which are never captured.
+    levels: HashMap<Variable, usize>,
+    /// The variables bound to the namespaces of the enclosing `with`
+    /// expressions, outermost first.
+    withs: Vec<Variable>,
 }
 
 impl<'arenas, 'a> Builder<'arenas, 'a> {
@@ -33,44 +46,76 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
     ///   should be the directory containing the source file, or the current
     ///   working directory if no real file is processed.
     /// * `arenas`: Arenas to allocate AST nodes and data in.
-    pub fn new(file: &'a Arc<File>, search_path: &'a Path, arenas: &'arenas Arenas<'arenas>) -> Self {
-        let mut this = Self {
+    /// * `variables`: The variable table to register new variables in.
+    /// * `prelude`: The global variables visible in the file.
+    pub fn new(
+        file: &'a Arc<File>,
+        search_path: &'a Path,
+        arenas: &'arenas Arenas<'arenas>,
+        variables: &'a mut Variables<'arenas>,
+        prelude: &[(&'arenas str, Variable)],
+    ) -> Self {
+        let mut globals = Scope::empty();
+        let mut levels = HashMap::new();
+        for &(name, var) in prelude {
+            globals.entries.insert(StrTendril::from(name), var);
+            levels.insert(var, 0);
+        }
+
+        Self {
             arenas,
             file,
             search_path,
-            scopes: vec![Scope::empty()],
-            variables: vec![],
-        };
-
-        this.define_variable(VarInfo {
-            decl_span: file.span.subspan(0, 0),
-            name: "true",
-            expr: this
-                .arenas
-                .alloc(Expr::Value(this.arenas.alloc(Value::Bool(true)))),
-        }).unwrap();
-        this.define_variable(VarInfo {
-            decl_span: file.span.subspan(0, 0),
-            name: "false",
-            expr: this
-                .arenas
-                .alloc(Expr::Value(this.arenas.alloc(Value::Bool(false)))),
-        }).unwrap();
-        this
+            scopes: vec![globals],
+            variables,
+            lambdas: Vec::new(),
+            levels,
+            withs: Vec::new(),
+        }
     }
 
+    /// Translates the file's root expression.
+    ///
+    /// Variables named by `scope` are defined around the root expression and
+    /// returned.
     pub fn build<R: TreeRoot<Types>>(
         &mut self,
-        root: rnix::parser::Node<R>,
-    ) -> Result<&'arenas Expr<'arenas>, Error> {
-        self.translate_expr(root)
+        root: Node<R>,
+        scope: &[&str],
+    ) -> Result<(&'arenas Expr<'arenas>, &'arenas [Variable]), Error> {
+        // The file itself is evaluated like the body of a lambda without
+        // parameters. Since only globals are declared outside of it, it never
+        // captures anything.
+        self.lambdas.push(LambdaScope::new());
+        self.scopes.push(Scope::empty());
+        let scope = scope
+            .iter()
+            .map(|name| {
+                self.define_variable(VarInfo {
+                    decl_span: self.file.span.subspan(0, 0),
+                    name: self.arenas.alloc_str(name),
+                    expr: None,
+                }).unwrap_or_else(|()| {
+                    // The names are the keys of the `scopedImport` set, so
+                    // they're unique, and the scope was just created
+                    unreachable!("duplicate scope variable `{}`", name)
+                })
+            })
+            .collect::<Vec<_>>();
+        let root = self.translate_expr(root)?;
+        self.scopes.pop();
+        let file_scope = self.lambdas.pop().unwrap();
+        assert!(file_scope.captures.is_empty(), "top-level expression captures variables");
+
+        Ok((root, self.arenas.alloc_slice(&scope)))
     }
 
     fn translate_expr<R: TreeRoot<Types>>(
         &mut self,
-        expr: rnix::parser::Node<R>,
+        expr: Node<R>,
     ) -> Result<&'arenas Expr<'arenas>, Error> {
-        match RawExpr::from(expr) {
+        let span = node_span(self.file, &expr);
+        match RawExpr::from(self.file, expr)? {
             RawExpr::Value(v) => {
                 let value = v.to_value().map_err(|e| {
                     let msg = match e {
@@ -87,11 +132,10 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
                 let value = match value {
                     value::Value::Float(f) => Value::Float(f),
                     value::Value::Integer(i) => Value::Int(i),
-                    value::Value::Str {
-                        content,
-                        multiline: _,
+                    value::Value::Str { .. } => {
+                        return self.translate_string(v.node(), Vec::new());
                     }
-                    | value::Value::Path(Anchor::Uri, content) => Value::String(content.into()),
+                    value::Value::Path(Anchor::Uri, content) => Value::String(content.into()),
                     value::Value::Path(anchor, path) => match anchor {
                         Anchor::Absolute => Value::Path(path.into()),
                         // Turn relative paths absolute by prepending the search dir
@@ -111,8 +155,8 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
                         Anchor::Store => {
                             // `<path>`-style paths are resolved lazily, so they're actually `Expr`s
                             // instead of `Value`s.
-                            let path = Path::new(self.arenas.alloc_str(&path));
-                            return Ok(self.arenas.alloc(Expr::NixPath(path)));
+                            let name = self.arenas.alloc_str(&path);
+                            return Ok(self.arenas.alloc(Expr::NixPath { name, span }));
                         },
                         Anchor::Uri => unreachable!(), // handled above
                     },
@@ -120,7 +164,16 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
 
                 Ok(self.arenas.alloc(Expr::Value(self.arenas.alloc(value))))
             }
-            RawExpr::Apply(_) => unimplemented!(),
+            RawExpr::Apply(apply) => {
+                let lambda = self.translate_expr(apply.lambda())?;
+                let argument = self.translate_lazy(apply.value())?;
+
+                Ok(self.arenas.alloc(Expr::Apply {
+                    lambda,
+                    argument,
+                    span,
+                }))
+            }
             RawExpr::Assert(assert) => {
                 let cond = self.translate_expr(assert.condition())?;
                 let body = self.translate_expr(assert.body())?;
@@ -128,20 +181,789 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
                 Ok(self.arenas.alloc(Expr::Assert {
                     assertion: cond,
                     then: body,
+                    span,
                 }))
             }
-            RawExpr::Ident(ident) => {
-                let var = self.resolve_local_variable(ident.as_str()).map_err(|()| {
-                    Error::at(self.file.clone(), &ident, "cannot resolve variable")
-                })?;
-                Ok(self.arenas.alloc(Expr::Variable(var)))
+            RawExpr::Ident(ident) => self.translate_variable(ident.as_str(), span),
+            RawExpr::IfElse(if_else) => {
+                let cond = self.translate_expr(if_else.condition())?;
+                let then = self.translate_expr(if_else.body())?;
+                let els = self.translate_expr(if_else.else_body())?;
+
+                Ok(self.arenas.alloc(Expr::IfElse {
+                    cond,
+                    then,
+                    els,
+                    span,
+                }))
+            }
+            RawExpr::IndexSet(index) => {
+                let (set, attr) = self.translate_index(index)?;
+
+                Ok(self.arenas.alloc(Expr::IndexSet {
+                    set,
+                    attr,
+                    default: None,
+                    span,
+                }))
+            }
+            RawExpr::Lambda(lambda) => {
+                self.lambdas.push(LambdaScope::new());
+                self.scopes.push(Scope::empty());
+                let arg = lambda.arg();
+                let param = match rnix::types::Pattern::cast(arg.clone()) {
+                    Some(pattern) => LambdaParameter::Pattern(self.translate_pattern(pattern)?),
+                    None => {
+                        let ident = Ident::cast(arg).ok_or_else(|| {
+                            Error::at(self.file.clone(), &lambda, "invalid function parameter")
+                        })?;
+                        LambdaParameter::Ident(self.define_parameter(&ident)?)
+                    }
+                };
+                let body = self.translate_expr(lambda.body())?;
+                self.scopes.pop();
+                let captures = self.lambdas.pop().unwrap().captures;
+
+                Ok(self.arenas.alloc(Expr::Lambda(Lambda {
+                    captures: self.arenas.alloc_slice(&captures),
+                    param: Some(param),
+                    body,
+                    span,
+                })))
+            }
+            RawExpr::LetIn(let_in) => {
+                let bindings = self.collect_bindings(&let_in)?;
+                if let Some(binding) = bindings.dynamic.first() {
+                    return Err(Error::at_span(
+                        self.file.clone(),
+                        binding.span,
+                        "dynamic attributes are not allowed in `let`",
+                    ));
+                }
+
+                let (vars, _, _) = self.translate_recursive(bindings)?;
+                let body = self.translate_expr(let_in.body())?;
+                self.scopes.pop();
+
+                Ok(self.arenas.alloc(Expr::LetIn { vars, body }))
+            }
+            RawExpr::List(list) => {
+                let elements = list
+                    .items()
+                    .map(|item| self.translate_lazy(item))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(self
+                    .arenas
+                    .alloc(Expr::List(self.arenas.alloc_slice(&elements))))
+            }
+            RawExpr::Operation(operation) => {
+                let op = match operation.operator() {
+                    OpKind::IsSet => {
+                        let set = self.translate_expr(operation.value1())?;
+                        let attr = self.translate_attr_path(operation.value2())?;
+                        return Ok(self.arenas.alloc(Expr::HasAttr { set, attr, span }));
+                    }
+                    OpKind::Concat => BinOp::Concat,
+                    OpKind::Update => BinOp::Update,
+                    OpKind::Add => BinOp::Add,
+                    OpKind::Sub => BinOp::Sub,
+                    OpKind::Mul => BinOp::Mul,
+                    OpKind::Div => BinOp::Div,
+                    OpKind::And => BinOp::And,
+                    OpKind::Equal => BinOp::Equal,
+                    OpKind::Implication => BinOp::Implication,
+                    OpKind::Less => BinOp::Less,
+                    OpKind::LessOrEq => BinOp::LessOrEq,
+                    OpKind::More => BinOp::More,
+                    OpKind::MoreOrEq => BinOp::MoreOrEq,
+                    OpKind::NotEqual => BinOp::NotEqual,
+                    OpKind::Or => BinOp::Or,
+                };
+                let left = self.translate_expr(operation.value1())?;
+                let right = self.translate_expr(operation.value2())?;
+
+                Ok(self.arenas.alloc(Expr::BinOp {
+                    op,
+                    left,
+                    right,
+                    span,
+                }))
+            }
+            RawExpr::Unary(unary) => {
+                let op = match unary.operator() {
+                    UnaryOpKind::Invert => UnOp::Not,
+                    UnaryOpKind::Negate => UnOp::Negate,
+                };
+                let operand = self.translate_expr(unary.value())?;
+
+                Ok(self.arenas.alloc(Expr::UnOp { op, operand, span }))
+            }
+            RawExpr::OrDefault(or_default) => {
+                let (set, attr) = self.translate_index(or_default.index())?;
+                let default = self.translate_expr(or_default.default())?;
+
+                Ok(self.arenas.alloc(Expr::IndexSet {
+                    set,
+                    attr,
+                    default: Some(default),
+                    span,
+                }))
+            }
+            RawExpr::Paren(paren) => self.translate_expr(paren.inner()),
+            RawExpr::Set(set) => {
+                let bindings = self.collect_bindings(&set)?;
+                if !set.recursive() {
+                    return self.translate_set(bindings);
+                }
+
+                // `rec { a = 1; b = a; }` is desugared to
+                // `let a = 1; b = a; in { a = a; b = b; }`
+                let (vars, entries, dynamic) = self.translate_recursive(bindings)?;
+                self.scopes.pop();
+
+                let body = self.arenas.alloc(Expr::Set {
+                    entries: self.arenas.alloc_slice(&entries),
+                    dynamic: self.arenas.alloc_slice(&dynamic),
+                });
+                Ok(self.arenas.alloc(Expr::LetIn { vars, body }))
+            }
+            RawExpr::Interpol(interpol) => {
+                let interpolations = interpol
+                    .parts()
+                    .filter_map(|part| match part {
+                        InterpolPart::Literal(_) => None,
+                        InterpolPart::Ast(ast) => Some(ast.inner()),
+                    })
+                    .collect();
+                self.translate_string(interpol.node(), interpolations)
+            }
+            RawExpr::With(with) => {
+                let namespace = self.define_hidden("<with>", with.namespace())?;
+                self.withs.push(namespace);
+                let body = self.translate_expr(with.body())?;
+                self.withs.pop();
+
+                Ok(self.arenas.alloc(Expr::With { namespace, body }))
+            }
+        }
+    }
+
+    /// Translates a string literal, given the expressions interpolated into
+    /// it.
+    fn translate_string<R: TreeRoot<Types>>(
+        &mut self,
+        node: &Node<R>,
+        interpolations: Vec<Node<R>>,
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        let span = node_span(self.file, node);
+        let range = node.range();
+        let start = range.start().to_usize();
+        let source = &self.file.source()[start..range.end().to_usize()];
+
+        let mut ends = interpolations
+            .iter()
+            .map(|expr| expr.range().end().to_usize() - start)
+            .collect::<Vec<_>>()
+            .into_iter();
+        let decoded = string::decode(source, |_| {
+            let end = ends.next().expect("missing interpolated expression");
+            string::closing_brace(source, end)
+        });
+
+        let mut interpolations = interpolations.into_iter();
+        let mut parts = Vec::with_capacity(decoded.len());
+        for part in decoded {
+            match part {
+                string::Part::Literal(literal) => {
+                    let value = self.arenas.alloc(Value::String(literal.into()));
+                    parts.push(&*self.arenas.alloc(Expr::Value(value)));
+                }
+                string::Part::Interpolation => {
+                    let expr = interpolations.next().expect("missing interpolated expression");
+                    parts.push(self.translate_expr(expr)?);
+                }
+            }
+        }
+
+        match parts.as_slice() {
+            [] => Ok(self.arenas.alloc(Expr::Value(self.arenas.alloc(Value::String("".into()))))),
+            [part @ Expr::Value(Value::String(_))] => Ok(*part),
+            _ => Ok(self.arenas.alloc(Expr::Interpolate {
+                parts: self.arenas.alloc_slice(&parts),
+                span,
+            })),
+        }
+    }
+
+    /// Translates an expression whose evaluation is deferred until its value
+    /// is needed.
+    ///
+    /// Expressions that are cheap to instantiate (literals, variables and
+    /// lambdas) are returned as-is, everything else is wrapped in a
+    /// transparent lambda capturing the expression's free variables.
+    fn translate_lazy<R: TreeRoot<Types>>(
+        &mut self,
+        expr: Node<R>,
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        let span = node_span(self.file, &expr);
+        self.lazy(span, |this| this.translate_expr(expr))
+    }
+
+    /// Translates the value of a (possibly recursive) binding.
+    ///
+    /// Unlike `translate_lazy`, this always creates a transparent lambda
+    /// unless the value is a literal, since its captures might refer to
+    /// bindings that are created at the same time.
+    fn translate_thunk<R: TreeRoot<Types>>(
+        &mut self,
+        expr: Node<R>,
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        let span = node_span(self.file, &expr);
+        self.thunk(span, |this| this.translate_expr(expr))
+    }
+
+    /// Like `translate_lazy`, but for an expression produced by `translate`.
+    fn lazy<F>(&mut self, span: Span, translate: F) -> Result<&'arenas Expr<'arenas>, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<&'arenas Expr<'arenas>, Error>,
+    {
+        let thunk = self.thunk(span, translate)?;
+        match thunk {
+            Expr::Lambda(Lambda {
+                param: None, body, ..
+            }) => match body {
+                Expr::Value(_)
+                | Expr::Variable(_)
+                | Expr::Lambda(Lambda { param: Some(_), .. }) => Ok(*body),
+                _ => Ok(thunk),
+            },
+            _ => Ok(thunk),
+        }
+    }
+
+    /// Like `translate_thunk`, but for an expression produced by `translate`.
+    fn thunk<F>(&mut self, span: Span, translate: F) -> Result<&'arenas Expr<'arenas>, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<&'arenas Expr<'arenas>, Error>,
+    {
+        self.lambdas.push(LambdaScope::new());
+        let body = translate(self)?;
+        let captures = self.lambdas.pop().unwrap().captures;
+
+        match body {
+            Expr::Value(_) => Ok(body),
+            _ => Ok(self.arenas.alloc(Expr::Lambda(Lambda {
+                captures: self.arenas.alloc_slice(&captures),
+                param: None,
+                body,
+                span,
+            }))),
+        }
+    }
+
+    /// Collects the entries and `inherit` clauses of a set or `let`
+    /// expression, merging nested attribute paths.
+    fn collect_bindings<R, H>(&mut self, holder: &H) -> Result<Bindings<'arenas, R>, Error>
+    where
+        R: TreeRoot<Types>,
+        H: EntryHolder<R>,
+    {
+        let mut bindings = Bindings::new();
+        self.collect_into(holder, &mut bindings)?;
+        Ok(bindings)
+    }
+
+    fn collect_into<R, H>(&mut self, holder: &H, bindings: &mut Bindings<'arenas, R>) -> Result<(), Error>
+    where
+        R: TreeRoot<Types>,
+        H: EntryHolder<R>,
+    {
+        for inherit in holder.inherits() {
+            let source = inherit.from().map(|from| {
+                bindings.sources.push(from.inner());
+                bindings.sources.len() - 1
+            });
+
+            for ident in inherit.idents() {
+                let key = AttrKey::Name(
+                    self.arenas.alloc_str(ident.as_str()),
+                    node_span(self.file, ident.node()),
+                );
+                let value = match source {
+                    Some(index) => BindingValue::InheritFrom(index),
+                    None => BindingValue::Inherit,
+                };
+                self.add_binding(bindings, vec![key], value)?;
             }
-            RawExpr::IfElse(_) => unimplemented!(),
-            RawExpr::IndexSet(_) => unimplemented!(),
-            RawExpr::Lambda(_) => unimplemented!(),
-            RawExpr::LetIn(_) => unimplemented!(),
-            RawExpr::List(_) => unimplemented!(),
-            _ => unimplemented!(),
+        }
+
+        for entry in holder.entries() {
+            let path = entry
+                .key()
+                .path()
+                .map(|part| self.collect_attr_key(part))
+                .collect::<Result<Vec<_>, _>>()?;
+            self.add_binding(bindings, path, BindingValue::Expr(entry.value()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Determines whether a segment of a binding's attribute path has a static
+    /// name.
+    ///
+    /// Dynamic names are translated later, since they can refer to variables
+    /// that are not yet defined.
+    fn collect_attr_key<R: TreeRoot<Types>>(
+        &mut self,
+        node: Node<R>,
+    ) -> Result<AttrKey<'arenas, R>, Error> {
+        let span = node_span(self.file, &node);
+        if let Some(ident) = Ident::cast(node.clone()) {
+            return Ok(AttrKey::Name(self.arenas.alloc_str(ident.as_str()), span));
+        }
+        if let Some(dynamic) = Dynamic::cast(node.clone()) {
+            return Ok(AttrKey::Dynamic(dynamic.inner(), span));
+        }
+
+        match RawExpr::from(self.file, node.clone()) {
+            // String literals are translated right away, they can't refer to
+            // any variables
+            Ok(RawExpr::Value(_)) => match self.translate_expr(node)? {
+                Expr::Value(Value::String(name)) => match name.to_str() {
+                    Some(name) => Ok(AttrKey::Name(self.arenas.alloc_str(name), span)),
+                    None => Err(Error::at_span(self.file.clone(), span, "attribute name is not valid UTF-8")),
+                },
+                _ => Err(Error::at_span(self.file.clone(), span, "invalid attribute name")),
+            },
+            Ok(RawExpr::Interpol(_)) => Ok(AttrKey::Dynamic(node, span)),
+            _ => Err(Error::at_span(self.file.clone(), span, "invalid attribute name")),
+        }
+    }
+
+    /// Adds a binding of `path` to `value`, creating or extending the nested
+    /// sets along the path.
+    ///
+    /// Like Nix, this also merges bindings with attribute set literals, so
+    /// `a = { b = 1; }; a.c = 2;` is accepted.
+    fn add_binding<R: TreeRoot<Types>>(
+        &mut self,
+        bindings: &mut Bindings<'arenas, R>,
+        mut path: Vec<AttrKey<'arenas, R>>,
+        value: BindingValue<'arenas, R>,
+    ) -> Result<(), Error> {
+        let (name, span) = match path.remove(0) {
+            AttrKey::Name(name, span) => (name, span),
+            AttrKey::Dynamic(name, span) => {
+                // Dynamic attributes are never merged
+                let value = self.nest_binding(path, value)?;
+                bindings.dynamic.push(DynamicBinding { name, span, value });
+                return Ok(());
+            }
+        };
+
+        let existing = match bindings.named.iter().position(|binding| binding.name == name) {
+            Some(index) => &mut bindings.named[index],
+            None => {
+                let value = self.nest_binding(path, value)?;
+                bindings.named.push(NamedBinding { name, span, value });
+                return Ok(());
+            }
+        };
+
+        let duplicate = Error::at_span(
+            self.file.clone(),
+            span,
+            format!("attribute `{}` already defined", name),
+        ).with_note(existing.span, "first defined here");
+
+        // Turn a previously bound set literal into nested bindings we can add to
+        let literal = match existing.value {
+            BindingValue::Expr(ref node) => set_literal(node),
+            _ => None,
+        };
+        if let Some(literal) = literal {
+            let mut nested = Bindings::new();
+            self.collect_into(&literal, &mut nested)?;
+            existing.value = BindingValue::Nested(nested);
+        }
+
+        let nested = match existing.value {
+            BindingValue::Nested(ref mut nested) => nested,
+            _ => return Err(duplicate),
+        };
+        if !path.is_empty() {
+            return self.add_binding(nested, path, value);
+        }
+        match value {
+            BindingValue::Expr(ref node) => match set_literal(node) {
+                Some(literal) => self.collect_into(&literal, nested),
+                None => Err(duplicate),
+            },
+            _ => Err(duplicate),
+        }
+    }
+
+    /// Creates the nested sets for the rest of an attribute path.
+    fn nest_binding<R: TreeRoot<Types>>(
+        &mut self,
+        path: Vec<AttrKey<'arenas, R>>,
+        value: BindingValue<'arenas, R>,
+    ) -> Result<BindingValue<'arenas, R>, Error> {
+        if path.is_empty() {
+            return Ok(value);
+        }
+
+        let mut nested = Bindings::new();
+        self.add_binding(&mut nested, path, value)?;
+        Ok(BindingValue::Nested(nested))
+    }
+
+    /// Translates the bindings of a non-recursive attribute set.
+    fn translate_set<R: TreeRoot<Types>>(
+        &mut self,
+        bindings: Bindings<'arenas, R>,
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        // The sources of `inherit (source) ...;` are only evaluated once
+        let sources = bindings
+            .sources
+            .into_iter()
+            .map(|source| self.define_hidden("<inherit source>", source))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut entries = Vec::with_capacity(bindings.named.len());
+        for binding in bindings.named {
+            let value = self.translate_binding_value(binding.value, binding.name, binding.span, &sources)?;
+            entries.push(SetEntry {
+                name: binding.name,
+                value,
+                span: binding.span,
+            });
+        }
+        let dynamic = self.translate_dynamic_bindings(bindings.dynamic, &sources)?;
+
+        let set = self.arenas.alloc(Expr::Set {
+            entries: self.arenas.alloc_slice(&entries),
+            dynamic: self.arenas.alloc_slice(&dynamic),
+        });
+        if sources.is_empty() {
+            Ok(set)
+        } else {
+            Ok(self.arenas.alloc(Expr::LetIn {
+                vars: self.arenas.alloc_slice(&sources),
+                body: set,
+            }))
+        }
+    }
+
+    /// Translates the bindings of a `let` expression or recursive set.
+    ///
+    /// This pushes a new scope that defines all bindings as variables, which
+    /// the caller has to pop once it has translated everything that can refer
+    /// to them. The values of `inherit name;` are resolved in the surrounding
+    /// scope.
+    ///
+    /// Returns all bound variables along with the entries of the equivalent
+    /// attribute set.
+    fn translate_recursive<R: TreeRoot<Types>>(
+        &mut self,
+        bindings: Bindings<'arenas, R>,
+    ) -> Result<
+        (
+            &'arenas [Variable],
+            Vec<SetEntry<'arenas>>,
+            Vec<DynamicEntry<'arenas>>,
+        ),
+        Error,
+    > {
+        let mut named = Vec::with_capacity(bindings.named.len());
+        for binding in bindings.named {
+            let value = match binding.value {
+                BindingValue::Inherit => {
+                    let (name, span) = (binding.name, binding.span);
+                    BindingValue::Translated(self.thunk(span, |this| this.translate_inherit(name, span))?)
+                }
+                value => value,
+            };
+            named.push(NamedBinding {
+                name: binding.name,
+                span: binding.span,
+                value,
+            });
+        }
+
+        // Define all variables first, so they can refer to each other
+        self.scopes.push(Scope::empty());
+        let mut vars = Vec::with_capacity(named.len() + bindings.sources.len());
+        for binding in &named {
+            let var = self
+                .define_variable(VarInfo {
+                    decl_span: binding.span,
+                    name: binding.name,
+                    expr: None,
+                }).unwrap_or_else(|()| {
+                    // `add_binding` rejects duplicate names, and the scope
+                    // was just created
+                    unreachable!("duplicate binding `{}`", binding.name)
+                });
+            vars.push(var);
+        }
+        let sources = bindings
+            .sources
+            .into_iter()
+            .map(|source| self.define_hidden("<inherit source>", source))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut entries = Vec::with_capacity(named.len());
+        for (&var, binding) in vars.iter().zip(named) {
+            let value = match binding.value {
+                BindingValue::Expr(node) => self.translate_thunk(node)?,
+                BindingValue::Translated(expr) => expr,
+                value => {
+                    let (name, span) = (binding.name, binding.span);
+                    self.thunk(span, |this| {
+                        this.translate_binding_value(value, name, span, &sources)
+                    })?
+                }
+            };
+            self.variables[var].expr = Some(value);
+            entries.push(SetEntry {
+                name: binding.name,
+                value: self.arenas.alloc(Expr::Variable(var)),
+                span: binding.span,
+            });
+        }
+        let dynamic = self.translate_dynamic_bindings(bindings.dynamic, &sources)?;
+
+        vars.extend(sources);
+        Ok((self.arenas.alloc_slice(&vars), entries, dynamic))
+    }
+
+    /// Translates the value bound to an attribute of a non-recursive set.
+    ///
+    /// `sources` are the variables holding the sources of `inherit (source)`.
+    fn translate_binding_value<R: TreeRoot<Types>>(
+        &mut self,
+        value: BindingValue<'arenas, R>,
+        name: &'arenas str,
+        span: Span,
+        sources: &[Variable],
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        match value {
+            BindingValue::Expr(node) => self.translate_lazy(node),
+            BindingValue::Nested(nested) => self.lazy(span, |this| this.translate_set(nested)),
+            BindingValue::Inherit => self.lazy(span, |this| this.translate_inherit(name, span)),
+            BindingValue::InheritFrom(index) => {
+                let source = sources[index];
+                self.lazy(span, |this| {
+                    this.use_variable(source);
+                    let set = this.arenas.alloc(Expr::Variable(source));
+                    Ok(this.arenas.alloc(Expr::IndexSet {
+                        set,
+                        attr: Attr {
+                            parts: this.arenas.alloc_slice(&[AttrPart::Name(name)]),
+                        },
+                        default: None,
+                        span,
+                    }))
+                })
+            }
+            BindingValue::Translated(expr) => Ok(expr),
+        }
+    }
+
+    fn translate_dynamic_bindings<R: TreeRoot<Types>>(
+        &mut self,
+        bindings: Vec<DynamicBinding<'arenas, R>>,
+        sources: &[Variable],
+    ) -> Result<Vec<DynamicEntry<'arenas>>, Error> {
+        bindings
+            .into_iter()
+            .map(|binding| {
+                let name = self.translate_expr(binding.name)?;
+                let value = self.translate_binding_value(binding.value, "", binding.span, sources)?;
+                Ok(DynamicEntry {
+                    name,
+                    value,
+                    span: binding.span,
+                })
+            })
+            .collect()
+    }
+
+    /// Translates `inherit name;` into a reference to the variable `name`.
+    fn translate_inherit(
+        &mut self,
+        name: &'arenas str,
+        span: Span,
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        self.translate_variable(name, span)
+    }
+
+    /// Translates a reference to the variable `name`.
+    ///
+    /// Local variables always take precedence over the attributes of `with`
+    /// namespaces, even if the `with` expression is nested more deeply. Names
+    /// that are not bound locally are looked up in the namespaces at runtime.
+    fn translate_variable(
+        &mut self,
+        name: &str,
+        span: Span,
+    ) -> Result<&'arenas Expr<'arenas>, Error> {
+        if let Ok(var) = self.resolve_local_variable(name) {
+            return Ok(self.arenas.alloc(Expr::Variable(var)));
+        }
+        if self.withs.is_empty() {
+            return Err(Error::at_span(self.file.clone(), span, "cannot resolve variable"));
+        }
+
+        let namespaces = self.withs.iter().rev().cloned().collect::<Vec<_>>();
+        for &namespace in &namespaces {
+            self.use_variable(namespace);
+        }
+
+        Ok(self.arenas.alloc(Expr::WithVariable {
+            name: self.arenas.alloc_str(name),
+            namespaces: self.arenas.alloc_slice(&namespaces),
+            span,
+        }))
+    }
+
+    /// Translates an attribute set pattern, defining all variables it binds in
+    /// the current scope.
+    fn translate_pattern<R: TreeRoot<Types>>(
+        &mut self,
+        pattern: rnix::types::Pattern<R>,
+    ) -> Result<Pattern<'arenas>, Error> {
+        let bind = match pattern.at() {
+            Some(ident) => Some(self.define_parameter(&ident)?),
+            None => None,
+        };
+
+        // Declare all variables first, since defaults can refer to any of them
+        let mut entries = Vec::new();
+        for entry in pattern.entries() {
+            let var = self.define_parameter(&entry.name())?;
+            entries.push((var, entry.default()));
+        }
+
+        let entries = entries
+            .into_iter()
+            .map(|(var, default)| {
+                let default = match default {
+                    Some(default) => Some(self.translate_thunk(default)?),
+                    None => None,
+                };
+                Ok(PatternEntry { var, default })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        Ok(Pattern {
+            entries: self.arenas.alloc_slice(&entries),
+            ellipsis: pattern.ellipsis(),
+            bind,
+        })
+    }
+
+    /// Defines a variable bound by a lambda parameter.
+    fn define_parameter<R: TreeRoot<Types>>(&mut self, ident: &Ident<R>) -> Result<Variable, Error> {
+        self.define_variable(VarInfo {
+            decl_span: node_span(self.file, ident.node()),
+            name: self.arenas.alloc_str(ident.as_str()),
+            expr: None,
+        }).map_err(|()| {
+            Error::at(
+                self.file.clone(),
+                ident,
+                format!("duplicate formal function argument `{}`", ident.as_str()),
+            )
+        })
+    }
+
+    /// Translates `set.a.b.c` into the indexed expression and its path.
+    ///
+    /// `rnix` parses this as `((set.a).b).c`, but we flatten it to allow
+    /// `or` defaults to apply to the whole path.
+    fn translate_index<R: TreeRoot<Types>>(
+        &mut self,
+        index: IndexSet<R>,
+    ) -> Result<(&'arenas Expr<'arenas>, Attr<'arenas>), Error> {
+        let mut path = vec![index.index()];
+        let mut set = index.set();
+        while let Some(inner) = IndexSet::cast(set.clone()) {
+            path.push(inner.index());
+            set = inner.set();
+        }
+        path.reverse();
+
+        let set = self.translate_expr(set)?;
+        let attr = self.translate_attr(path)?;
+        Ok((set, attr))
+    }
+
+    /// Translates the attribute path on the right-hand side of `set ? a.b.c`.
+    fn translate_attr_path<R: TreeRoot<Types>>(
+        &mut self,
+        node: Node<R>,
+    ) -> Result<Attr<'arenas>, Error> {
+        if let Some(attribute) = rnix::types::Attribute::cast(node.clone()) {
+            let path = attribute.path().collect::<Vec<_>>();
+            return self.translate_attr(path);
+        }
+
+        let mut path = Vec::new();
+        let mut node = node;
+        while let Some(index) = IndexSet::cast(node.clone()) {
+            path.push(index.index());
+            node = index.set();
+        }
+        path.push(node);
+        path.reverse();
+
+        self.translate_attr(path)
+    }
+
+    fn translate_attr<R: TreeRoot<Types>>(
+        &mut self,
+        path: Vec<Node<R>>,
+    ) -> Result<Attr<'arenas>, Error> {
+        let parts = path
+            .into_iter()
+            .map(|part| self.translate_attr_part(part))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Attr {
+            parts: self.arenas.alloc_slice(&parts),
+        })
+    }
+
+    /// Translates a single segment of an attribute path.
+    fn translate_attr_part<R: TreeRoot<Types>>(
+        &mut self,
+        node: Node<R>,
+    ) -> Result<AttrPart<'arenas>, Error> {
+        // Plain identifiers are attribute names, not variable references
+        if let Some(ident) = Ident::cast(node.clone()) {
+            return Ok(AttrPart::Name(self.arenas.alloc_str(ident.as_str())));
+        }
+        if let Some(dynamic) = Dynamic::cast(node.clone()) {
+            return Ok(AttrPart::Dynamic(self.translate_expr(dynamic.inner())?));
+        }
+
+        let span = node_span(self.file, &node);
+        let expr = self.translate_expr(node)?;
+        match expr {
+            Expr::Value(Value::String(name)) => match name.to_str() {
+                Some(name) => Ok(AttrPart::Name(self.arenas.alloc_str(name))),
+                None => Err(Error::at_span(self.file.clone(), span, "attribute name is not valid UTF-8")),
+            },
+            Expr::Interpolate { .. } => Ok(AttrPart::Dynamic(expr)),
+            _ => Err(Error::at_span(
+                self.file.clone(),
+                span,
+                "invalid attribute name",
+            )),
         }
     }
 
@@ -149,26 +971,70 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
     ///
     /// This is a very hashmap-heavy operation, since it interns the identifier
     /// and walks up the scope stack.
+    ///
+    /// The variable is recorded as a capture of all lambdas between its
+    /// declaration and the current position (see `use_variable`).
     fn resolve_local_variable(&mut self, name: &str) -> Result<Variable, ()> {
-        let (innermost, rest) = self.scopes.split_last_mut().expect("no scope");
-        let tendril = StrTendril::from(name);
-        match innermost.entries.entry(tendril) {
-            Entry::Occupied(entry) => Ok(*entry.get()),
-            Entry::Vacant(entry) => {
-                // Slow path: Walk scope stack upwards
-                let tendril = StrTendril::from(name);
-                for scope in rest.iter_mut().rev() {
-                    if let Some(&variable) = scope.entries.get(&tendril) {
-                        entry.insert(variable);
-                        return Ok(variable);
-                    }
+        let variable = {
+            let (innermost, rest) = self.scopes.split_last_mut().expect("no scope");
+            let tendril = StrTendril::from(name);
+            match innermost.entries.entry(tendril) {
+                Entry::Occupied(entry) => *entry.get(),
+                Entry::Vacant(entry) => {
+                    // Slow path: Walk scope stack upwards
+                    let tendril = StrTendril::from(name);
+                    let variable = rest
+                        .iter()
+                        .rev()
+                        .filter_map(|scope| scope.entries.get(&tendril))
+                        .next()
+                        .cloned()
+                        .ok_or(())?;
+                    entry.insert(variable);
+                    variable
                 }
+            }
+        };
 
-                Err(())
+        self.use_variable(variable);
+        Ok(variable)
+    }
+
+    /// Records `variable` as a capture of all lambdas between its declaration
+    /// and the current position.
+    fn use_variable(&mut self, variable: Variable) {
+        let level = self.levels[&variable];
+        if level > 0 {
+            for lambda in &mut self.lambdas[level..] {
+                if !lambda.captures.contains(&variable) {
+                    lambda.captures.push(variable);
+                }
             }
         }
     }
 
+    /// Defines an unnamed variable bound to `expr` in the current scope.
+    ///
+    /// The variable can't be referred to by name, so references to it have to
+    /// be recorded with `use_variable`.
+    fn define_hidden<R: TreeRoot<Types>>(
+        &mut self,
+        name: &'static str,
+        expr: Node<R>,
+    ) -> Result<Variable, Error> {
+        let variable = Variable(self.variables.len() as u32);
+        self.variables.push(VarInfo {
+            decl_span: node_span(self.file, &expr),
+            name,
+            expr: None,
+        });
+        self.levels.insert(variable, self.lambdas.len());
+
+        let expr = self.translate_thunk(expr)?;
+        self.variables[variable].expr = Some(expr);
+        Ok(variable)
+    }
+
     /// Defines a new local variable in the currently active scope.
     ///
     /// If the scope already defines a variables named `name`, this returns an
@@ -190,6 +1056,7 @@ impl<'arenas, 'a> Builder<'arenas, 'a> {
             Entry::Vacant(vacant) => {
                 vacant.insert(variable);
                 self.variables.push(var);
+                self.levels.insert(variable, self.lambdas.len());
                 Ok(variable)
             }
         }
@@ -214,3 +1081,76 @@ impl Scope {
         }
     }
 }
+
+/// A lambda whose body is being translated.
+struct LambdaScope {
+    /// Variables declared outside of the lambda that are used inside of it.
+    captures: Vec<Variable>,
+}
+
+impl LambdaScope {
+    fn new() -> Self {
+        Self {
+            captures: Vec::new(),
+        }
+    }
+}
+
+/// The bindings of a set or `let` expression.
+///
+/// These are collected from the parse tree before being translated, so that
+/// `a.b = 1; a.c = 2;` can be merged into `a = { b = 1; c = 2; };`.
+struct Bindings<'arenas, R: TreeRoot<Types>> {
+    /// Bindings with a static name, in order of definition.
+    named: Vec<NamedBinding<'arenas, R>>,
+    /// Bindings with a `${dynamic}` name.
+    dynamic: Vec<DynamicBinding<'arenas, R>>,
+    /// The `source` expressions of `inherit (source) ...;`.
+    sources: Vec<Node<R>>,
+}
+
+impl<'arenas, R: TreeRoot<Types>> Bindings<'arenas, R> {
+    fn new() -> Self {
+        Self {
+            named: Vec::new(),
+            dynamic: Vec::new(),
+            sources: Vec::new(),
+        }
+    }
+}
+
+struct NamedBinding<'arenas, R: TreeRoot<Types>> {
+    name: &'arenas str,
+    span: Span,
+    value: BindingValue<'arenas, R>,
+}
+
+struct DynamicBinding<'arenas, R: TreeRoot<Types>> {
+    name: Node<R>,
+    span: Span,
+    value: BindingValue<'arenas, R>,
+}
+
+enum BindingValue<'arenas, R: TreeRoot<Types>> {
+    /// `name = expr;`
+    Expr(Node<R>),
+    /// A set created by `name.a = ...;` paths.
+    Nested(Bindings<'arenas, R>),
+    /// `inherit name;`
+    Inherit,
+    /// `inherit (source) name;`, with the index of `source`.
+    InheritFrom(usize),
+    /// An already translated value.
+    Translated(&'arenas Expr<'arenas>),
+}
+
+/// A segment of the attribute path a value is bound to.
+enum AttrKey<'arenas, R: TreeRoot<Types>> {
+    Name(&'arenas str, Span),
+    Dynamic(Node<R>, Span),
+}
+
+/// Returns the set if `node` is a non-recursive attribute set literal.
+fn set_literal<R: TreeRoot<Types>>(node: &Node<R>) -> Option<rnix::types::Set<R>> {
+    rnix::types::Set::cast(node.clone()).filter(|set| !set.recursive())
+}
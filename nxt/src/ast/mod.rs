@@ -10,9 +10,11 @@
 //! to make as simple and efficient as possible.
 
 mod build;
+mod string;
 
 use self::build::Builder;
 use parser::Error;
+use utils::IndexVec;
 use value::Value;
 
 use codemap::{File, Span};
@@ -33,6 +35,7 @@ pub enum Expr<'a> {
     Apply {
         lambda: &'a Expr<'a>,
         argument: &'a Expr<'a>,
+        span: Span,
     },
 
     /// `assert <assertion>; <then>`
@@ -44,6 +47,28 @@ pub enum Expr<'a> {
     Assert {
         assertion: &'a Expr<'a>,
         then: &'a Expr<'a>,
+        span: Span,
+    },
+
+    /// `<left> <op> <right>`
+    ///
+    /// A binary operator. The right-hand side of the logical operators `&&`,
+    /// `||` and `->` is only evaluated when needed.
+    BinOp {
+        op: BinOp,
+        left: &'a Expr<'a>,
+        right: &'a Expr<'a>,
+        span: Span,
+    },
+
+    /// `set ? attr.path`
+    ///
+    /// Evaluates to `true` if `set` is a set containing the (possibly nested)
+    /// attribute `attr`, and to `false` otherwise.
+    HasAttr {
+        set: &'a Expr<'a>,
+        attr: Attr<'a>,
+        span: Span,
     },
 
     /// `if <cond> then <then> else <els>`
@@ -55,14 +80,27 @@ pub enum Expr<'a> {
         cond: &'a Expr<'a>,
         then: &'a Expr<'a>,
         els: &'a Expr<'a>,
+        span: Span,
     },
 
-    /// `set.index`
+    /// `set.index` or `set.index or default`
     ///
-    /// Evaluates `index`, then evaluates to the corresponding member of `set`.
+    /// Evaluates `set` and selects the attribute path `attr` from it. If the
+    /// attribute does not exist, evaluates to `default` if it is given, or
+    /// aborts with an error otherwise.
     IndexSet {
         set: &'a Expr<'a>,
-        index: &'a Expr<'a>,
+        attr: Attr<'a>,
+        default: Option<&'a Expr<'a>>,
+        span: Span,
+    },
+
+    /// A string literal containing `${interpolated}` expressions.
+    ///
+    /// All parts are evaluated, coerced to strings and concatenated.
+    Interpolate {
+        parts: &'a [&'a Expr<'a>],
+        span: Span,
     },
 
     /// Instantiate a lambda, supplying all free variables and building a
@@ -72,17 +110,132 @@ pub enum Expr<'a> {
     /// `Apply`.
     Lambda(Lambda<'a>),
 
+    /// `let <bindings> in <body>`
+    ///
+    /// Binds all `vars` (which can refer to each other) to their expressions
+    /// stored in the corresponding `VarInfo`, then evaluates to `body`.
+    ///
+    /// Recursive sets (`rec { ... }`) are desugared to this as well.
+    LetIn {
+        vars: &'a [Variable],
+        body: &'a Expr<'a>,
+    },
+
+    /// `[ <elements> ]`
+    List(&'a [&'a Expr<'a>]),
+
     /// A `<path>` expression.
     ///
     /// Note that this is only for angle-bracketed paths that are searched for
     /// in `NIX_PATH`, not for other kinds of paths, which are just `Value`s.
-    NixPath(&'a Path),
+    /// The path is resolved when the expression is evaluated.
+    NixPath { name: &'a str, span: Span },
+
+    /// A (non-recursive) set expression `{ <entries> }`.
+    Set {
+        /// Entries with a statically known name.
+        entries: &'a [SetEntry<'a>],
+        /// Entries whose name is computed by a `${dynamic}` expression.
+        dynamic: &'a [DynamicEntry<'a>],
+    },
+
+    /// `<op> <operand>`
+    UnOp {
+        op: UnOp,
+        operand: &'a Expr<'a>,
+        span: Span,
+    },
 
     /// A literal value.
     Value(&'a Value<'a>),
 
     /// A local variable.
     Variable(Variable),
+
+    /// `with <namespace>; <body>`
+    ///
+    /// The namespace is bound to an unnamed variable.
+    With {
+        namespace: Variable,
+        body: &'a Expr<'a>,
+    },
+
+    /// An identifier that does not refer to a local variable.
+    ///
+    /// It is looked up at runtime in the namespaces of the enclosing `with`
+    /// expressions.
+    WithVariable {
+        name: &'a str,
+        /// The variables bound to the namespaces, innermost first.
+        namespaces: &'a [Variable],
+        span: Span,
+    },
+}
+
+/// A binary operator.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BinOp {
+    /// `+`
+    Add,
+    /// `&&`
+    And,
+    /// `++`
+    Concat,
+    /// `/`
+    Div,
+    /// `==`
+    Equal,
+    /// `->`
+    Implication,
+    /// `<`
+    Less,
+    /// `<=`
+    LessOrEq,
+    /// `>`
+    More,
+    /// `>=`
+    MoreOrEq,
+    /// `*`
+    Mul,
+    /// `!=`
+    NotEqual,
+    /// `||`
+    Or,
+    /// `-`
+    Sub,
+    /// `//`
+    Update,
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            BinOp::Add => "+",
+            BinOp::And => "&&",
+            BinOp::Concat => "++",
+            BinOp::Div => "/",
+            BinOp::Equal => "==",
+            BinOp::Implication => "->",
+            BinOp::Less => "<",
+            BinOp::LessOrEq => "<=",
+            BinOp::More => ">",
+            BinOp::MoreOrEq => ">=",
+            BinOp::Mul => "*",
+            BinOp::NotEqual => "!=",
+            BinOp::Or => "||",
+            BinOp::Sub => "-",
+            BinOp::Update => "//",
+        })
+    }
+}
+
+/// A unary operator.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UnOp {
+    /// `-`
+    Negate,
+    /// `!`
+    Not,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -96,34 +249,104 @@ pub struct Lambda<'a> {
     /// declared in the direct parent. It also ensures that all captured
     /// variables are kept alive for as long as they might be needed in any
     /// contained lambda, which can be tricky due to laziness.
-    captures: &'a [Variable],
+    pub captures: &'a [Variable],
 
     /// Describes the parameters the lambda expects.
     ///
     /// Can be `None` for compiler-generated "transparent" lambdas that
     /// facilitate lazy evaluation.
-    param: Option<LambdaParameter>,
+    pub param: Option<LambdaParameter<'a>>,
 
     /// The expression this lambda evaluates to when called.
-    body: &'a Expr<'a>,
+    pub body: &'a Expr<'a>,
+
+    /// The span of the lambda expression, or of the lazily evaluated
+    /// expression for transparent lambdas.
+    pub span: Span,
+}
+
+impl<'a> Lambda<'a> {
+    /// Creates a transparent lambda that calls the function bound to
+    /// `function` with the argument bound to `argument`.
+    ///
+    /// Both variables are captured by the lambda, in this order.
+    pub fn call(arenas: &'a Arenas<'a>, function: Variable, argument: Variable, span: Span) -> &'a Self {
+        let body = Expr::Apply {
+            lambda: arenas.alloc(Expr::Variable(function)),
+            argument: arenas.alloc(Expr::Variable(argument)),
+            span,
+        };
+        arenas.alloc(Lambda {
+            captures: arenas.alloc_slice(&[function, argument]),
+            param: None,
+            body: arenas.alloc(body),
+            span,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum LambdaParameter<'a> {
+    /// `ident: <body>`
+    ///
+    /// Binds the argument to a variable.
+    Ident(Variable),
+
+    /// `{ a, b ? default, ... }: <body>`
+    ///
+    /// Destructures the argument, which must be a set.
+    Pattern(Pattern<'a>),
+}
+
+/// An attribute set pattern `{ a, b ? default, ... }`, optionally bound to a
+/// variable via `args@{ ... }` or `{ ... }@args`.
+#[derive(Copy, Clone, Debug)]
+pub struct Pattern<'a> {
+    /// The expected attributes.
+    pub entries: &'a [PatternEntry<'a>],
+
+    /// Whether the pattern ends in `...`, which allows the argument to
+    /// contain attributes that aren't listed in `entries`.
+    pub ellipsis: bool,
+
+    /// The variable the whole argument is bound to using `@`.
+    ///
+    /// Note that the argument is bound as passed, so it does not contain
+    /// attributes that were filled in by default values.
+    pub bind: Option<Variable>,
 }
 
+/// An attribute in a `Pattern`.
 #[derive(Copy, Clone, Debug)]
-pub enum LambdaParameter {
-    // TODO
+pub struct PatternEntry<'a> {
+    /// The variable the attribute is bound to. It has the same name as the
+    /// attribute.
+    pub var: Variable,
+
+    /// The value to use when the argument lacks the attribute.
+    ///
+    /// This is evaluated lazily and may refer to the other variables bound by
+    /// the pattern.
+    pub default: Option<&'a Expr<'a>>,
 }
 
 /// A resolved local variable.
 ///
 /// At runtime, every local variable is associated to an `&'a Expr<'a>`.
 /// It's also possible to cache the `Value` this expression resolves to.
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub struct Variable(u32);
 
 impl Into<usize> for Variable {
     fn into(self) -> usize { self.0 as usize }
 }
 
+impl From<usize> for Variable {
+    fn from(index: usize) -> Self {
+        Variable(index as u32)
+    }
+}
+
 /// Information about a local variable.
 #[derive(Debug, Copy, Clone)]
 pub struct VarInfo<'a> {
@@ -132,9 +355,58 @@ pub struct VarInfo<'a> {
     /// The variable name (can collide with other variables).
     pub name: &'a str,
     /// The expression assigned to the variable.
-    pub expr: &'a Expr<'a>,
+    ///
+    /// This is `None` for variables bound by lambda parameters, which get
+    /// their value when the lambda is called.
+    pub expr: Option<&'a Expr<'a>>,
+}
+
+impl<'a> VarInfo<'a> {
+    /// Creates the `VarInfo` of a global variable bound to `value`.
+    ///
+    /// `decl_span` should point to an empty span, since globals aren't
+    /// declared in the source code.
+    pub fn global(arenas: &'a Arenas<'a>, name: &str, value: Value<'a>, decl_span: Span) -> Self {
+        Self {
+            decl_span,
+            name: arenas.alloc_str(name),
+            expr: Some(arenas.alloc(Expr::Value(arenas.alloc(value)))),
+        }
+    }
+
+    /// Creates the `VarInfo` of a global variable bound to a set whose
+    /// attributes refer to other global variables.
+    pub fn global_set(
+        arenas: &'a Arenas<'a>,
+        name: &str,
+        attrs: &[(String, Variable)],
+        decl_span: Span,
+    ) -> Self {
+        let entries = attrs
+            .iter()
+            .map(|(name, var)| SetEntry {
+                name: arenas.alloc_str(name),
+                value: arenas.alloc(Expr::Variable(*var)),
+                span: decl_span,
+            })
+            .collect::<Vec<_>>();
+        Self {
+            decl_span,
+            name: arenas.alloc_str(name),
+            expr: Some(arenas.alloc(Expr::Set {
+                entries: arenas.alloc_slice(&entries),
+                dynamic: &[],
+            })),
+        }
+    }
 }
 
+/// Maps `Variable` IDs to their `VarInfo`.
+///
+/// A single table is shared by all files evaluated by an `EvalContext`, so a
+/// `Variable` uniquely identifies a variable across all `Ast`s.
+pub type Variables<'a> = IndexVec<VarInfo<'a>, Variable>;
+
 /// An attribute or variable path.
 ///
 /// `a`, `"a"."a"`, `x.y`, `x."${interpolated} string"`.
@@ -154,45 +426,88 @@ pub struct VarInfo<'a> {
 #[derive(Debug, Copy, Clone)]
 pub struct Attr<'a> {
     /// Always contains at least one element.
-    parts: &'a [AttrPart<'a>],
+    pub parts: &'a [AttrPart<'a>],
 }
 
 /// A segment of an attribute path.
 ///
 /// Segments are separated by `.`.
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone)]
 pub enum AttrPart<'a> {
-    /// `unquoted_variable`
-    Variable(Variable),
-    /// `"quoted string"`
-    String(&'a str),
+    /// `unquoted_name` or `"quoted string"`
+    Name(&'a str),
+    /// `${expr}` or `"interpolated ${string}"`
+    ///
+    /// The expression must evaluate to a string.
+    Dynamic(&'a Expr<'a>),
+}
+
+/// A `name = value;` entry in a set expression.
+#[derive(Debug, Copy, Clone)]
+pub struct SetEntry<'a> {
+    pub name: &'a str,
+    /// The (lazily evaluated) value.
+    pub value: &'a Expr<'a>,
+    /// The span of the attribute name.
+    pub span: Span,
+}
+
+/// A `${name} = value;` entry in a set expression.
+#[derive(Debug, Copy, Clone)]
+pub struct DynamicEntry<'a> {
+    /// Expression evaluating to the attribute name (or `null` to omit the
+    /// entry).
+    pub name: &'a Expr<'a>,
+    /// The (lazily evaluated) value.
+    pub value: &'a Expr<'a>,
+    /// The span of the attribute name.
+    pub span: Span,
 }
 
 pub struct Ast<'a> {
     root: &'a Expr<'a>,
+    scope: &'a [Variable],
     file: Arc<File>,
 }
 
 impl<'a> Ast<'a> {
     /// Builds a high-level AST from a raw expression parse tree.
+    ///
+    /// All variables declared in the file are registered in `variables`.
+    /// `prelude` lists the global variables, which are visible everywhere
+    /// unless shadowed.
+    ///
+    /// The names in `scope` are bound to variables that are visible in the
+    /// whole file and take precedence over the globals. They don't have an
+    /// expression and must be put in the frame the root is evaluated in (see
+    /// `Ast::scope`). This is used by `scopedImport`.
     pub fn build<R: TreeRoot<Types>>(
         arenas: &'a Arenas<'a>,
         file: Arc<File>,
         search_path: &Path,
         root: rnix::parser::Node<R>,
+        variables: &mut Variables<'a>,
+        prelude: &[(&'a str, Variable)],
+        scope: &[&str],
     ) -> Result<Self, Error> {
-        let root = {
-            let mut builder = Builder::new(&file, search_path, arenas);
-            builder.build(root)?
+        let (root, scope) = {
+            let mut builder = Builder::new(&file, search_path, arenas, variables, prelude);
+            builder.build(root, scope)?
         };
 
-        Ok(Self { root, file })
+        Ok(Self { root, scope, file })
     }
 
     /// Returns the root expression represented by this AST.
     pub fn root(&self) -> &'a Expr<'a> {
         self.root
     }
+
+    /// Returns the variables bound to the `scope` names passed to
+    /// `Ast::build`, in the same order.
+    pub fn scope(&self) -> &'a [Variable] {
+        self.scope
+    }
 }
 
 impl<'a> fmt::Debug for Ast<'a> {
@@ -213,6 +528,12 @@ pub struct Arenas<'a> {
     values: TypedArena<Value<'a>>,
 }
 
+impl<'a> fmt::Debug for Arenas<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Arenas")
+    }
+}
+
 impl<'a> Arenas<'a> {
     pub fn new() -> Self {
         Self {
@@ -228,6 +549,10 @@ impl<'a> Arenas<'a> {
     fn alloc_str(&self, s: &str) -> &str {
         self.copy.alloc_str(s)
     }
+
+    fn alloc_slice<T: Copy>(&self, s: &[T]) -> &[T] {
+        self.copy.alloc_slice(s)
+    }
 }
 
 /// Trait implemented by all types that can be allocated in `Arenas`.
@@ -0,0 +1,271 @@
+//! Decoding of string literals.
+//!
+//! `rnix` only unescapes simple strings, so we decode the source code of
+//! string literals ourselves. This handles escape sequences and the
+//! indentation stripping of `''indented strings''` the same way Nix does.
+
+use std::cmp;
+
+/// A part of a decoded string literal.
+#[derive(Debug, PartialEq)]
+pub enum Part {
+    /// Literal text.
+    Literal(String),
+    /// An interpolated `${expression}`.
+    Interpolation,
+}
+
+/// Decodes the source code of a string literal, including its quotes.
+///
+/// `interpolation` is called with the byte offset following each `${` and has
+/// to return the offset following the matching `}`.
+pub fn decode<F>(source: &str, interpolation: F) -> Vec<Part>
+where
+    F: FnMut(usize) -> usize,
+{
+    if source.starts_with("''") {
+        strip_indentation(decode_indented(source, interpolation))
+    } else {
+        decode_plain(source, interpolation)
+    }
+}
+
+/// Returns the offset following the `}` that closes an interpolation, given
+/// the offset where the interpolated expression ends.
+///
+/// Only whitespace and comments may appear in between.
+pub fn closing_brace(source: &str, mut pos: usize) -> usize {
+    loop {
+        let rest = &source[pos..];
+        if rest.starts_with('}') {
+            return pos + 1;
+        } else if rest.starts_with('#') {
+            pos += rest.find('\n').unwrap_or(rest.len());
+        } else if rest.starts_with("/*") {
+            pos += rest.find("*/").map(|end| end + 2).expect("unterminated comment");
+        } else {
+            let c = rest.chars().next().expect("unterminated interpolation");
+            assert!(c.is_whitespace(), "unexpected {:?} after interpolation", c);
+            pos += c.len_utf8();
+        }
+    }
+}
+
+/// Decodes a `"string"`.
+fn decode_plain<F>(source: &str, mut interpolation: F) -> Vec<Part>
+where
+    F: FnMut(usize) -> usize,
+{
+    let end = source.len() - 1;
+    let mut parts = Vec::new();
+    let mut literal = String::new();
+    let mut pos = 1;
+    while pos < end {
+        let rest = &source[pos..end];
+        if rest.starts_with('\\') {
+            let c = rest[1..].chars().next().expect("unterminated escape sequence");
+            literal.push(unescape(c));
+            pos += 1 + c.len_utf8();
+        } else if rest.starts_with("$$") {
+            // `$${` is not an interpolation
+            literal.push_str("$$");
+            pos += 2;
+        } else if rest.starts_with("${") {
+            push_literal(&mut parts, &mut literal);
+            parts.push(Part::Interpolation);
+            pos = interpolation(pos + 2);
+        } else {
+            let c = rest.chars().next().unwrap();
+            literal.push(c);
+            pos += c.len_utf8();
+        }
+    }
+
+    push_literal(&mut parts, &mut literal);
+    parts
+}
+
+/// A part of an indented string whose indentation hasn't been stripped yet.
+#[derive(Debug)]
+enum IndentedPart {
+    /// Source text, which is subject to indentation stripping.
+    Text(String),
+    /// The character produced by an `''\` escape. Like interpolations, it is
+    /// never stripped and ends the indentation of its line.
+    Escaped(char),
+    /// An interpolated `${expression}`.
+    Interpolation,
+}
+
+/// Decodes an `''indented string''`, without stripping indentation.
+fn decode_indented<F>(source: &str, mut interpolation: F) -> Vec<IndentedPart>
+where
+    F: FnMut(usize) -> usize,
+{
+    let end = source.len() - 2;
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut pos = 2;
+
+    // A first line consisting only of spaces is dropped
+    let first_line = source[pos..end].trim_left_matches(' ');
+    if first_line.starts_with('\n') {
+        pos = end - first_line.len() + 1;
+    }
+
+    while pos < end {
+        let rest = &source[pos..end];
+        if rest.starts_with("'''") {
+            text.push_str("''");
+            pos += 3;
+        } else if rest.starts_with("''$") {
+            text.push('$');
+            pos += 3;
+        } else if rest.starts_with("''\\") {
+            let c = rest[3..].chars().next().expect("unterminated escape sequence");
+            push_text(&mut parts, &mut text);
+            parts.push(IndentedPart::Escaped(unescape(c)));
+            pos += 3 + c.len_utf8();
+        } else if rest.starts_with("$$") {
+            text.push_str("$$");
+            pos += 2;
+        } else if rest.starts_with("${") {
+            push_text(&mut parts, &mut text);
+            parts.push(IndentedPart::Interpolation);
+            pos = interpolation(pos + 2);
+        } else {
+            let c = rest.chars().next().unwrap();
+            text.push(c);
+            pos += c.len_utf8();
+        }
+    }
+
+    push_text(&mut parts, &mut text);
+    parts
+}
+
+/// Removes the common indentation from all lines of an indented string.
+///
+/// Lines consisting only of spaces don't count towards the indentation, but
+/// interpolations and escaped characters do. Only spaces are considered
+/// indentation, not tabs. If the last line consists only of spaces, it is
+/// removed.
+fn strip_indentation(parts: Vec<IndentedPart>) -> Vec<Part> {
+    let mut min_indent = usize::max_value();
+    let mut at_start_of_line = true;
+    let mut indent = 0;
+    for part in &parts {
+        match part {
+            IndentedPart::Interpolation | IndentedPart::Escaped(_) => {
+                if at_start_of_line {
+                    at_start_of_line = false;
+                    min_indent = cmp::min(min_indent, indent);
+                }
+            }
+            IndentedPart::Text(text) => {
+                for c in text.chars() {
+                    if at_start_of_line {
+                        match c {
+                            ' ' => indent += 1,
+                            '\n' => indent = 0,
+                            _ => {
+                                at_start_of_line = false;
+                                min_indent = cmp::min(min_indent, indent);
+                            }
+                        }
+                    } else if c == '\n' {
+                        at_start_of_line = true;
+                        indent = 0;
+                    }
+                }
+            }
+        }
+    }
+
+    let count = parts.len();
+    let mut stripped_parts = Vec::with_capacity(count);
+    let mut literal = String::new();
+    let mut at_start_of_line = true;
+    let mut dropped = 0;
+    for (index, part) in parts.into_iter().enumerate() {
+        let text = match part {
+            IndentedPart::Interpolation => {
+                at_start_of_line = false;
+                dropped = 0;
+                push_literal(&mut stripped_parts, &mut literal);
+                stripped_parts.push(Part::Interpolation);
+                continue;
+            }
+            IndentedPart::Escaped(c) => {
+                at_start_of_line = false;
+                dropped = 0;
+                literal.push(c);
+                continue;
+            }
+            IndentedPart::Text(text) => text,
+        };
+
+        let mut stripped = String::with_capacity(text.len());
+        for c in text.chars() {
+            if at_start_of_line {
+                match c {
+                    ' ' => {
+                        if dropped >= min_indent {
+                            stripped.push(c);
+                        }
+                        dropped += 1;
+                    }
+                    '\n' => {
+                        dropped = 0;
+                        stripped.push(c);
+                    }
+                    _ => {
+                        at_start_of_line = false;
+                        dropped = 0;
+                        stripped.push(c);
+                    }
+                }
+            } else {
+                stripped.push(c);
+                if c == '\n' {
+                    at_start_of_line = true;
+                }
+            }
+        }
+
+        if index == count - 1 {
+            if let Some(newline) = stripped.rfind('\n') {
+                if stripped[newline + 1..].chars().all(|c| c == ' ') {
+                    stripped.truncate(newline + 1);
+                }
+            }
+        }
+
+        literal.push_str(&stripped);
+    }
+
+    push_literal(&mut stripped_parts, &mut literal);
+    stripped_parts
+}
+
+/// Returns the character produced by the escape sequence `\c`.
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        c => c,
+    }
+}
+
+fn push_literal(parts: &mut Vec<Part>, literal: &mut String) {
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal.split_off(0)));
+    }
+}
+
+fn push_text(parts: &mut Vec<IndentedPart>, text: &mut String) {
+    if !text.is_empty() {
+        parts.push(IndentedPart::Text(text.split_off(0)));
+    }
+}
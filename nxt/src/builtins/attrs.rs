@@ -0,0 +1,197 @@
+//! Primops operating on attribute sets.
+
+use ast::LambdaParameter;
+use builtins::{force_attr_name, force_list, force_set, type_mismatch};
+use eval::{Error, EvalContext};
+use utils;
+use value::{Closure, Thunk, Type, Value};
+
+use codemap::Span;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// `attrNames set`
+///
+/// Returns the names of the attributes, sorted alphabetically.
+pub fn attr_names<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let set = force_set(ctx, &args[0], span)?;
+    let names = set.keys().map(|name| evaluated(string(name))).collect();
+    Ok(Value::List(Rc::new(names)))
+}
+
+/// `attrValues set`
+///
+/// Returns the values of the attributes, sorted by their names.
+pub fn attr_values<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let set = force_set(ctx, &args[0], span)?;
+    Ok(Value::List(Rc::new(set.values().cloned().collect())))
+}
+
+/// `getAttr name set`
+pub fn get_attr<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let name = force_attr_name(ctx, &args[0], span)?;
+    let set = force_set(ctx, &args[1], span)?;
+    match set.get(&*name) {
+        Some(value) => ctx.force(value),
+        None => {
+            let suggestions = utils::similar_names(&name, set.keys().map(|key| &**key))
+                .into_iter()
+                .map(String::from)
+                .collect();
+            Err(Error::MissingAttribute {
+                name: name.to_string(),
+                suggestions,
+                span,
+            })
+        }
+    }
+}
+
+/// `hasAttr name set`
+pub fn has_attr<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let name = force_attr_name(ctx, &args[0], span)?;
+    let set = force_set(ctx, &args[1], span)?;
+    Ok(Value::Bool(set.contains_key(&*name)))
+}
+
+/// `removeAttrs set names`
+///
+/// Returns `set` without the attributes listed in `names`. Names that are
+/// not in the set are ignored.
+pub fn remove_attrs<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let set = force_set(ctx, &args[0], span)?;
+    let names = force_list(ctx, &args[1], span)?;
+    let mut set = (**set).clone();
+    for name in names.iter() {
+        let name = force_attr_name(ctx, name, span)?;
+        set.remove(&*name);
+    }
+    Ok(Value::Set(Rc::new(set.into())))
+}
+
+/// `intersectAttrs e1 e2`
+///
+/// Returns the attributes of `e2` whose names also occur in `e1`.
+pub fn intersect_attrs<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let names = force_set(ctx, &args[0], span)?;
+    let set = force_set(ctx, &args[1], span)?;
+    let intersection = set
+        .iter()
+        .filter(|(name, _)| names.contains_key(*name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    Ok(Value::Set(Rc::new(intersection)))
+}
+
+/// `catAttrs name sets`
+///
+/// Collects the values of the attribute `name` of all sets in the list that
+/// have it.
+pub fn cat_attrs<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let name = force_attr_name(ctx, &args[0], span)?;
+    let sets = force_list(ctx, &args[1], span)?;
+    let mut values = Vec::new();
+    for set in sets.iter() {
+        if let Some(value) = force_set(ctx, set, span)?.get(&*name) {
+            values.push(value.clone());
+        }
+    }
+    Ok(Value::List(Rc::new(values)))
+}
+
+/// `mapAttrs function set`
+///
+/// Replaces every attribute value with `function name value`, which is only
+/// evaluated when the attribute is forced.
+pub fn map_attrs<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let set = force_set(ctx, &args[1], span)?;
+    let mapped = set
+        .iter()
+        .map(|(name, value)| {
+            let function = ctx.lazy_call(args[0].clone(), evaluated(string(name)), span);
+            (name.clone(), ctx.lazy_call(function, value.clone(), span))
+        })
+        .collect();
+    Ok(Value::Set(Rc::new(mapped)))
+}
+
+/// `zipAttrsWith function sets`
+///
+/// For every attribute name occurring in the list of sets, calls
+/// `function name values` with the values of that attribute in all sets
+/// (lazily).
+pub fn zip_attrs_with<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let sets = force_list(ctx, &args[1], span)?;
+    let mut zipped = BTreeMap::<String, Vec<Thunk<'a>>>::new();
+    for set in sets.iter() {
+        for (name, value) in force_set(ctx, set, span)?.iter() {
+            zipped.entry(name.clone()).or_default().push(value.clone());
+        }
+    }
+
+    let set = zipped
+        .into_iter()
+        .map(|(name, values)| {
+            let function = ctx.lazy_call(args[0].clone(), evaluated(string(&name)), span);
+            let values = evaluated(Value::List(Rc::new(values)));
+            (name, ctx.lazy_call(function, values, span))
+        })
+        .collect();
+    Ok(Value::Set(Rc::new(set)))
+}
+
+/// `functionArgs function`
+///
+/// Returns the attributes expected by a function with a set pattern, mapped
+/// to whether they have a default value. Other functions return `{}`.
+pub fn function_args<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let mut set = BTreeMap::new();
+    match ctx.force(&args[0])? {
+        Value::Lambda(closure) => {
+            if let Some((lambda, _)) = closure.code() {
+                if let Some(LambdaParameter::Pattern(pattern)) = lambda.param {
+                    for entry in pattern.entries {
+                        let name = ctx.variable_name(entry.var).to_string();
+                        let has_default = Value::Bool(entry.default.is_some());
+                        set.insert(name, evaluated(has_default));
+                    }
+                }
+            }
+        }
+        Value::Builtin { .. } => {}
+        other => return Err(type_mismatch(Type::Lambda, &other, span)),
+    }
+    Ok(Value::Set(Rc::new(set.into())))
+}
+
+/// `unsafeGetAttrPos name set`
+///
+/// Returns the location (`{ file, line, column }`) where the attribute is
+/// defined, or `null` if it is unknown (eg. because the set wasn't created
+/// by a set expression).
+pub fn unsafe_get_attr_pos<'a>(
+    ctx: &mut EvalContext<'a>,
+    args: &[Thunk<'a>],
+    span: Span,
+) -> Result<Value<'a>, Error> {
+    let name = force_attr_name(ctx, &args[0], span)?;
+    let set = force_set(ctx, &args[1], span)?;
+    let pos = match set.attr_pos(&name) {
+        Some(pos) => ctx.codemap().look_up_pos(pos.low()),
+        None => return Ok(Value::Null),
+    };
+
+    let mut location = BTreeMap::new();
+    location.insert("file".to_string(), evaluated(string(pos.file.name())));
+    location.insert("line".to_string(), evaluated(Value::Int(pos.position.line as i64 + 1)));
+    location.insert("column".to_string(), evaluated(Value::Int(pos.position.column as i64 + 1)));
+    Ok(Value::Set(Rc::new(location.into())))
+}
+
+fn string<'a>(s: &str) -> Value<'a> {
+    Value::String(s.into())
+}
+
+fn evaluated<'a>(value: Value<'a>) -> Thunk<'a> {
+    Rc::new(Closure::evaluated(value))
+}
@@ -0,0 +1,731 @@
+//! `match` and `split`, which use POSIX extended regular expressions (EREs).
+//!
+//! Like in Nix (which uses libstdc++'s `std::regex`), regexes are matched
+//! against the bytes of strings and the longest match is preferred. Among
+//! matches of the same length, the first one found is used, trying
+//! alternatives from left to right and repeating greedily.
+//!
+//! Regexes are compiled to a program for a backtracking matcher. Unlike
+//! libstdc++, the matcher remembers which states (instruction and position)
+//! it has visited and never visits them again, so matching takes at most
+//! time proportional to the length of the string times the program size.
+//! Memory is only used for the states that are actually visited, apart from
+//! a small bitset for short strings.
+
+use builtins::force_string;
+use eval::{Error, EvalContext};
+use value::{Closure, Thunk, Value};
+
+use codemap::Span;
+use std::collections::HashSet;
+use std::rc::Rc;
+
+/// The largest count allowed in intervals like `{2,5}` (`RE_DUP_MAX`).
+const MAX_REPEAT: u32 = 255;
+
+/// The largest number of instructions a compiled regex may have.
+const MAX_INSTRUCTIONS: usize = 1 << 16;
+
+/// A compiled ERE.
+#[derive(Debug)]
+pub struct Regex {
+    program: Vec<Inst>,
+    /// The number of capture groups, including the whole match (group 0).
+    groups: usize,
+}
+
+/// The byte ranges matched by the capture groups, with `None` for groups
+/// that didn't participate in the match.
+type Captures = Vec<Option<(usize, usize)>>;
+
+impl Regex {
+    /// Compiles an ERE, returning an error message if it is invalid.
+    pub fn new(ere: &[u8]) -> Result<Self, String> {
+        let mut parser = Parser {
+            ere,
+            pos: 0,
+            groups: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.pos < ere.len() {
+            // Parsing only stops early at a `)`
+            return Err("unmatched `)`".to_string());
+        }
+
+        let mut compiler = Compiler { program: Vec::new() };
+        compiler.push(Inst::Save(0))?;
+        compiler.compile(&node)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+        Ok(Regex {
+            program: compiler.program,
+            groups: parser.groups + 1,
+        })
+    }
+
+    /// Matches the regex against the whole `string`.
+    fn full_match(&self, string: &[u8]) -> Option<Captures> {
+        let mut visited = Visited::new(self.program.len(), string.len());
+        self.run(string, 0, Mode::Whole, &mut visited)
+    }
+
+    /// Returns the matches `split` splits `string` at.
+    ///
+    /// Like `std::sregex_iterator`, after an empty match, a non-empty match
+    /// starting at the same position is preferred over searching from the
+    /// next byte.
+    fn split_matches(&self, string: &[u8]) -> Vec<Captures> {
+        let mut visited = Visited::new(self.program.len(), string.len());
+        let mut matches = Vec::new();
+        let mut next = self.search(string, 0, &mut visited);
+        while let Some(captures) = next {
+            let (start, end) = captures[0].expect("match without group 0");
+            next = if start < end {
+                self.search(string, end, &mut visited)
+            } else if end == string.len() {
+                None
+            } else {
+                let non_empty = self.run(string, end, Mode::NonEmpty, &mut visited);
+                visited.clear();
+                non_empty.or_else(|| self.search(string, end + 1, &mut visited))
+            };
+            matches.push(captures);
+        }
+        matches
+    }
+
+    /// Returns the leftmost-longest match starting at or after `start`.
+    fn search(&self, string: &[u8], start: usize, visited: &mut Visited) -> Option<Captures> {
+        // The states visited when trying earlier positions can't lead to a
+        // match, so they don't have to be visited again
+        let found = (start..=string.len()).find_map(|pos| self.run(string, pos, Mode::Prefix, visited));
+        visited.clear();
+        found
+    }
+
+    /// Returns the longest match starting at `start` that is accepted by
+    /// `mode`, or the first one found if there are several.
+    fn run(&self, string: &[u8], start: usize, mode: Mode, visited: &mut Visited) -> Option<Captures> {
+        let mut slots = vec![None; 2 * self.groups];
+        let mut best: Option<(usize, Vec<Option<usize>>)> = None;
+        let mut stack = vec![Job::Explore(0, start)];
+        while let Some(job) = stack.pop() {
+            let (mut pc, mut pos) = match job {
+                Job::Explore(pc, pos) => (pc, pos),
+                Job::Restore(slot, value) => {
+                    slots[slot] = value;
+                    continue;
+                }
+            };
+
+            // A state that has been visited before can only lead to matches
+            // that have been found already (or that are shorter)
+            while visited.insert(pc, pos) {
+                match self.program[pc] {
+                    Inst::Byte(b) if string.get(pos) == Some(&b) => pos += 1,
+                    Inst::Class(ref set) if string.get(pos).map_or(false, |&b| set.contains(b)) => pos += 1,
+                    Inst::Start if pos == 0 => {}
+                    Inst::End if pos == string.len() => {}
+                    Inst::Save(slot) => {
+                        stack.push(Job::Restore(slot, slots[slot]));
+                        slots[slot] = Some(pos);
+                    }
+                    Inst::Split(first, second) => {
+                        stack.push(Job::Explore(second, pos));
+                        pc = first;
+                        continue;
+                    }
+                    Inst::Jump(target) => {
+                        pc = target;
+                        continue;
+                    }
+                    Inst::Match => {
+                        let accepted = match mode {
+                            Mode::Whole => pos == string.len(),
+                            Mode::Prefix => true,
+                            Mode::NonEmpty => pos > start,
+                        };
+                        if accepted && best.as_ref().map_or(true, |&(end, _)| pos > end) {
+                            // No match can be longer than one reaching the end
+                            if pos == string.len() {
+                                return Some(captures(&slots));
+                            }
+                            best = Some((pos, slots.clone()));
+                        }
+                        break;
+                    }
+                    _ => break,
+                }
+                pc += 1;
+            }
+        }
+        best.map(|(_, slots)| captures(&slots))
+    }
+}
+
+/// Converts the positions saved by the matcher to capture groups.
+fn captures(slots: &[Option<usize>]) -> Captures {
+    slots
+        .chunks(2)
+        .map(|group| match (group[0], group[1]) {
+            (Some(start), Some(end)) => Some((start, end)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// What kind of match the matcher accepts.
+#[derive(Debug, Copy, Clone)]
+enum Mode {
+    /// Only a match of the whole string.
+    Whole,
+    /// Any match starting at the starting position.
+    Prefix,
+    /// Any non-empty match starting at the starting position.
+    NonEmpty,
+}
+
+/// Work left to do for the matcher.
+#[derive(Debug)]
+enum Job {
+    /// Continue matching at an instruction and position.
+    Explore(usize, usize),
+    /// Reset a slot to a previous value when backtracking.
+    Restore(usize, Option<usize>),
+}
+
+/// The largest number of states tracked with a bitset, which takes up one bit
+/// per state even if it's never visited (128 KiB).
+const MAX_DENSE_STATES: usize = 1 << 20;
+
+/// The states (instruction and position) the matcher has visited.
+///
+/// For short strings and small programs, the states are tracked with a bitset.
+/// Otherwise, it would take up memory proportional to the length of the string
+/// times the program size, so only the visited states are stored.
+enum Visited {
+    Dense {
+        bits: Vec<u64>,
+        /// The number of positions in the string (its length plus one).
+        positions: usize,
+        /// The indices of the non-zero words in `bits`, to quickly clear them.
+        dirty: Vec<usize>,
+    },
+    Sparse(HashSet<(usize, usize)>),
+}
+
+impl Visited {
+    fn new(instructions: usize, len: usize) -> Self {
+        let positions = len + 1;
+        match instructions.checked_mul(positions) {
+            Some(states) if states <= MAX_DENSE_STATES => Visited::Dense {
+                bits: vec![0; (states + 63) / 64],
+                positions,
+                dirty: Vec::new(),
+            },
+            _ => Visited::Sparse(HashSet::new()),
+        }
+    }
+
+    /// Marks a state as visited, returning whether it hadn't been before.
+    fn insert(&mut self, pc: usize, pos: usize) -> bool {
+        match self {
+            Visited::Dense { bits, positions, dirty } => {
+                let index = pc * *positions + pos;
+                let (word, bit) = (index / 64, 1u64 << (index % 64));
+                if bits[word] & bit != 0 {
+                    return false;
+                }
+                if bits[word] == 0 {
+                    dirty.push(word);
+                }
+                bits[word] |= bit;
+                true
+            }
+            Visited::Sparse(states) => states.insert((pc, pos)),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Visited::Dense { bits, dirty, .. } => {
+                for word in dirty.drain(..) {
+                    bits[word] = 0;
+                }
+            }
+            Visited::Sparse(states) => states.clear(),
+        }
+    }
+}
+
+/// An instruction of a compiled regex.
+#[derive(Debug)]
+enum Inst {
+    /// Matches a byte.
+    Byte(u8),
+    /// Matches any byte in a set.
+    Class(ByteSet),
+    /// Matches at the start of the string (`^`).
+    Start,
+    /// Matches at the end of the string (`$`).
+    End,
+    /// Saves the current position in a slot. Slots `2 * n` and `2 * n + 1`
+    /// hold the start and end of group `n`.
+    Save(usize),
+    /// Continues at both instructions, trying the first one first.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+/// A parsed ERE.
+#[derive(Debug)]
+enum Node {
+    Byte(u8),
+    Class(ByteSet),
+    Start,
+    End,
+    Group(usize, Box<Node>),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    /// `node{min,max}`, with `max` being unbounded if it's `None`.
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+    },
+}
+
+struct Parser<'r> {
+    ere: &'r [u8],
+    pos: usize,
+    /// The number of groups opened so far.
+    groups: usize,
+}
+
+impl<'r> Parser<'r> {
+    fn peek(&self) -> Option<u8> {
+        self.ere.get(self.pos).cloned()
+    }
+
+    fn bump(&mut self) -> Option<u8> {
+        let b = self.peek()?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    /// Parses alternatives separated by `|`, up to a `)` or the end.
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut alternatives = vec![self.concatenation()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            alternatives.push(self.concatenation()?);
+        }
+        if alternatives.len() == 1 {
+            Ok(alternatives.pop().unwrap())
+        } else {
+            Ok(Node::Alternate(alternatives))
+        }
+    }
+
+    fn concatenation(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        loop {
+            let atom = match self.peek() {
+                None | Some(b'|') | Some(b')') => break,
+                Some(b'*') | Some(b'+') | Some(b'?') | Some(b'{') => {
+                    return Err("repetition without an expression to repeat".to_string());
+                }
+                Some(b'^') => {
+                    self.pos += 1;
+                    nodes.push(Node::Start);
+                    continue;
+                }
+                Some(b'$') => {
+                    self.pos += 1;
+                    nodes.push(Node::End);
+                    continue;
+                }
+                Some(_) => self.atom()?,
+            };
+            nodes.push(self.repetitions(atom)?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.bump().expect("no atom to parse") {
+            b'(' => {
+                self.groups += 1;
+                let index = self.groups;
+                let node = self.alternation()?;
+                if self.bump() != Some(b')') {
+                    return Err("unclosed group".to_string());
+                }
+                Node::Group(index, Box::new(node))
+            }
+            b'.' => {
+                let mut any = ByteSet::default();
+                any.insert_range(1, 255);
+                Node::Class(any)
+            }
+            b'[' => Node::Class(self.bracket()?),
+            // Like in libstdc++, a backslash makes any character literal
+            b'\\' => match self.bump() {
+                Some(b) => Node::Byte(b),
+                None => return Err("trailing backslash".to_string()),
+            },
+            b => Node::Byte(b),
+        })
+    }
+
+    /// Parses the repetition operators following an atom.
+    fn repetitions(&mut self, mut node: Node) -> Result<Node, String> {
+        loop {
+            let (min, max) = match self.bump() {
+                Some(b'*') => (0, None),
+                Some(b'+') => (1, None),
+                Some(b'?') => (0, Some(1)),
+                Some(b'{') => self.interval()?,
+                Some(_) => {
+                    self.pos -= 1;
+                    return Ok(node);
+                }
+                None => return Ok(node),
+            };
+            node = Node::Repeat {
+                node: Box::new(node),
+                min,
+                max,
+            };
+        }
+    }
+
+    /// Parses the bounds of an interval like `{2,5}`, after the `{`.
+    ///
+    /// `{,n}` is accepted as `{0,n}`.
+    fn interval(&mut self) -> Result<(u32, Option<u32>), String> {
+        let min = self.number()?;
+        let max = if self.peek() == Some(b',') {
+            self.pos += 1;
+            self.number()?
+        } else {
+            Some(min.ok_or_else(|| "invalid interval".to_string())?)
+        };
+        let min = min.unwrap_or(0);
+        if self.bump() != Some(b'}') || max.map_or(false, |max| max < min) {
+            return Err("invalid interval".to_string());
+        }
+        Ok((min, max))
+    }
+
+    fn number(&mut self) -> Result<Option<u32>, String> {
+        let start = self.pos;
+        let mut number = 0;
+        while let Some(digit @ b'0'..=b'9') = self.peek() {
+            number = number * 10 + u32::from(digit - b'0');
+            if number > MAX_REPEAT {
+                return Err(format!("repetition count larger than {}", MAX_REPEAT));
+            }
+            self.pos += 1;
+        }
+        Ok(if self.pos > start { Some(number) } else { None })
+    }
+
+    /// Parses a bracket expression like `[^a-z_]`, after the `[`.
+    fn bracket(&mut self) -> Result<ByteSet, String> {
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.pos += 1;
+        }
+
+        let mut set = ByteSet::default();
+        // A `]` at the start is literal
+        let mut first = true;
+        loop {
+            let start = match self.bump() {
+                Some(b']') if !first => break,
+                Some(b'[') if self.peek() == Some(b':') => {
+                    self.pos += 1;
+                    set.extend(&class(self.bracket_name(b':')?)?);
+                    first = false;
+                    continue;
+                }
+                Some(b'[') if self.peek() == Some(b'.') || self.peek() == Some(b'=') => {
+                    let delimiter = self.bump().unwrap();
+                    self.collating_element(delimiter)?
+                }
+                Some(b) => b,
+                None => return Err("unterminated bracket expression".to_string()),
+            };
+            first = false;
+
+            // A `-` at the start or end is literal, otherwise it denotes a
+            // range
+            let is_range = self.peek() == Some(b'-') && self.ere.get(self.pos + 1).map_or(false, |&b| b != b']');
+            if !is_range {
+                set.insert(start);
+                continue;
+            }
+            self.pos += 1;
+            let end = match self.bump() {
+                Some(b'[') if self.peek() == Some(b'.') => {
+                    self.pos += 1;
+                    self.collating_element(b'.')?
+                }
+                Some(b) => b,
+                None => return Err("unterminated bracket expression".to_string()),
+            };
+            if end < start {
+                return Err("invalid range in bracket expression".to_string());
+            }
+            set.insert_range(start, end);
+        }
+
+        if negated {
+            set.negate();
+        }
+        Ok(set)
+    }
+
+    /// Parses a `[.c.]` collating element or a `[=c=]` equivalence class,
+    /// after the `[.` or `[=`. Only single bytes are supported.
+    fn collating_element(&mut self, delimiter: u8) -> Result<u8, String> {
+        match self.bracket_name(delimiter)? {
+            &[b] => Ok(b),
+            name => Err(format!(
+                "unsupported collating element `{}`",
+                String::from_utf8_lossy(name)
+            )),
+        }
+    }
+
+    /// Parses the name in a `[:name:]`, `[.name.]` or `[=name=]` bracket
+    /// item, after its opening delimiter.
+    fn bracket_name(&mut self, delimiter: u8) -> Result<&'r [u8], String> {
+        let start = self.pos;
+        loop {
+            match self.bump() {
+                Some(b) if b == delimiter && self.peek() == Some(b']') => {
+                    self.pos += 1;
+                    return Ok(&self.ere[start..self.pos - 2]);
+                }
+                Some(_) => {}
+                None => return Err("unterminated bracket expression".to_string()),
+            }
+        }
+    }
+}
+
+/// Returns the bytes in the character class `name` (in the C locale).
+fn class(name: &[u8]) -> Result<ByteSet, String> {
+    let predicate: fn(&u8) -> bool = match name {
+        b"alnum" => u8::is_ascii_alphanumeric,
+        b"alpha" => u8::is_ascii_alphabetic,
+        b"blank" => |&b| b == b' ' || b == b'\t',
+        b"cntrl" => u8::is_ascii_control,
+        b"digit" => u8::is_ascii_digit,
+        b"graph" => u8::is_ascii_graphic,
+        b"lower" => u8::is_ascii_lowercase,
+        b"print" => |&b| b == b' ' || b.is_ascii_graphic(),
+        b"punct" => u8::is_ascii_punctuation,
+        // Unlike `is_ascii_whitespace`, this includes the vertical tab
+        b"space" => |&b| b == b'\x0b' || b.is_ascii_whitespace(),
+        b"upper" => u8::is_ascii_uppercase,
+        b"xdigit" => u8::is_ascii_hexdigit,
+        _ => {
+            return Err(format!(
+                "unknown character class `{}`",
+                String::from_utf8_lossy(name)
+            ));
+        }
+    };
+
+    let mut set = ByteSet::default();
+    for b in (0..=255).filter(predicate) {
+        set.insert(b);
+    }
+    Ok(set)
+}
+
+/// A set of bytes.
+#[derive(Debug, Copy, Clone, Default)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn contains(&self, b: u8) -> bool {
+        self.0[usize::from(b / 64)] & (1 << (b % 64)) != 0
+    }
+
+    fn insert(&mut self, b: u8) {
+        self.0[usize::from(b / 64)] |= 1 << (b % 64);
+    }
+
+    fn insert_range(&mut self, start: u8, end: u8) {
+        for b in start..=end {
+            self.insert(b);
+        }
+    }
+
+    fn extend(&mut self, other: &ByteSet) {
+        for (word, other) in self.0.iter_mut().zip(other.0.iter()) {
+            *word |= other;
+        }
+    }
+
+    fn negate(&mut self) {
+        for word in &mut self.0 {
+            *word = !*word;
+        }
+    }
+}
+
+/// Compiles a parsed ERE to a program for the matcher.
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    /// Appends an instruction, returning its index.
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() == MAX_INSTRUCTIONS {
+            return Err("regex is too large".to_string());
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Byte(b) => {
+                self.push(Inst::Byte(*b))?;
+            }
+            Node::Class(set) => {
+                self.push(Inst::Class(*set))?;
+            }
+            Node::Start => {
+                self.push(Inst::Start)?;
+            }
+            Node::End => {
+                self.push(Inst::End)?;
+            }
+            Node::Group(index, node) => {
+                self.push(Inst::Save(2 * index))?;
+                self.compile(node)?;
+                self.push(Inst::Save(2 * index + 1))?;
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alternate(alternatives) => {
+                let (last, rest) = alternatives.split_last().expect("no alternatives");
+                let mut jumps = Vec::new();
+                for alternative in rest {
+                    let split = self.push(Inst::Split(0, 0))?;
+                    self.compile(alternative)?;
+                    jumps.push(self.push(Inst::Jump(0))?);
+                    self.program[split] = Inst::Split(split + 1, self.program.len());
+                }
+                self.compile(last)?;
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat { node, min, max: None } => {
+                // `node{min,}` is compiled like `node{min - 1}node+`, or like
+                // `(node+)?` if `min` is 0
+                for _ in 1..*min {
+                    self.compile(node)?;
+                }
+                let optional = if *min == 0 { Some(self.push(Inst::Split(0, 0))?) } else { None };
+                let start = self.program.len();
+                self.compile(node)?;
+                let exit = self.program.len() + 1;
+                self.push(Inst::Split(start, exit))?;
+                if let Some(split) = optional {
+                    self.program[split] = Inst::Split(split + 1, exit);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max: Some(max),
+            } => {
+                // `node{min,max}` is compiled like `node{min}(node(node...)?)?`
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                let mut splits = Vec::new();
+                for _ in *min..*max {
+                    splits.push(self.push(Inst::Split(0, 0))?);
+                    self.compile(node)?;
+                }
+                let end = self.program.len();
+                for split in splits {
+                    self.program[split] = Inst::Split(split + 1, end);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `match regex string`
+///
+/// Returns `null` if the regex doesn't match the whole string. Otherwise,
+/// returns the list of capture groups, with `null` for groups that didn't
+/// participate in the match.
+pub fn match_<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let ere = force_string(ctx, &args[0], span)?;
+    let regex = ctx.regex(&ere, span)?;
+    let string = force_string(ctx, &args[1], span)?;
+    Ok(match regex.full_match(&string) {
+        Some(captures) => Value::List(Rc::new(groups(&string, &captures))),
+        None => Value::Null,
+    })
+}
+
+/// `split regex string`
+///
+/// Splits the string at all matches of the regex. The result contains the
+/// strings between the matches, interleaved with the lists of capture groups
+/// of the matches (like the result of `match`):
+///
+/// ```nix
+/// split "(a)|b" "xaybz" == [ "x" [ "a" ] "y" [ null ] "z" ]
+/// ```
+pub fn split<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let ere = force_string(ctx, &args[0], span)?;
+    let regex = ctx.regex(&ere, span)?;
+    let string = force_string(ctx, &args[1], span)?;
+
+    let mut parts = Vec::new();
+    let mut end = 0;
+    for captures in regex.split_matches(&string) {
+        let (start, match_end) = captures[0].expect("match without group 0");
+        parts.push(evaluated(Value::String(string[end..start].into())));
+        parts.push(evaluated(Value::List(Rc::new(groups(&string, &captures)))));
+        end = match_end;
+    }
+    parts.push(evaluated(Value::String(string[end..].into())));
+    Ok(Value::List(Rc::new(parts)))
+}
+
+/// Returns the capture groups (without the whole match) as strings, using
+/// `null` for groups that didn't participate.
+fn groups<'a>(string: &[u8], captures: &[Option<(usize, usize)>]) -> Vec<Thunk<'a>> {
+    captures[1..]
+        .iter()
+        .map(|group| match *group {
+            Some((start, end)) => evaluated(Value::String(string[start..end].into())),
+            None => evaluated(Value::Null),
+        })
+        .collect()
+}
+
+fn evaluated<'a>(value: Value<'a>) -> Thunk<'a> {
+    Rc::new(Closure::evaluated(value))
+}
@@ -0,0 +1,44 @@
+//! `import` and `scopedImport`.
+
+use builtins::force_set;
+use eval::{Error, EvalContext};
+use value::{Thunk, Value};
+
+use codemap::Span;
+use std::path::PathBuf;
+
+/// `import path`
+pub fn import<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let path = import_path(ctx, &args[0], span)?;
+    ctx.import(&path, None, span)
+}
+
+/// `scopedImport scope path`
+///
+/// Like `import`, but the attributes of `scope` are visible as variables in
+/// the imported file (taking precedence over the globals).
+pub fn scoped_import<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let scope = force_set(ctx, &args[0], span)?;
+    let path = import_path(ctx, &args[1], span)?;
+    ctx.import(&path, Some(&**scope), span)
+}
+
+/// Evaluates the argument of `import`, which must be a path or a string
+/// containing an absolute path.
+fn import_path<'a>(ctx: &mut EvalContext<'a>, path: &Thunk<'a>, span: Span) -> Result<PathBuf, Error> {
+    match ctx.force(path)? {
+        Value::Path(path) => Ok(path),
+        value => {
+            let string = ctx.coerce_to_string(value, span)?;
+            let path = PathBuf::from(string.to_string_lossy().into_owned());
+            if path.is_absolute() {
+                Ok(path)
+            } else {
+                Err(Error::NotAnAbsolutePath {
+                    path: string.to_string(),
+                    span,
+                })
+            }
+        }
+    }
+}
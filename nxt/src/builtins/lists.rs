@@ -0,0 +1,305 @@
+//! Primops operating on lists.
+//!
+//! The elements of lists are passed around as thunks, so elements that are
+//! never used are never evaluated (eg. by `length`).
+
+use builtins::{call_predicate, force_attr_name, force_int, force_list, force_set, type_mismatch};
+use eval::{Error, EvalContext};
+use value::{Closure, Thunk, Type, Value};
+
+use codemap::Span;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// `length list`
+pub fn length<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[0], span)?;
+    Ok(Value::Int(list.len() as i64))
+}
+
+/// `head list`
+pub fn head<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[0], span)?;
+    match list.first() {
+        Some(head) => ctx.force(head),
+        None => Err(Error::EmptyList {
+            function: "head",
+            span,
+        }),
+    }
+}
+
+/// `tail list`
+///
+/// Returns all elements except the first.
+pub fn tail<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[0], span)?;
+    if list.is_empty() {
+        return Err(Error::EmptyList {
+            function: "tail",
+            span,
+        });
+    }
+    Ok(Value::List(Rc::new(list[1..].to_vec())))
+}
+
+/// `elemAt list index`
+pub fn elem_at<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[0], span)?;
+    let index = force_int(ctx, &args[1], span)?;
+    if index < 0 || index as usize >= list.len() {
+        return Err(Error::IndexOutOfBounds {
+            index,
+            len: list.len(),
+            span,
+        });
+    }
+    ctx.force(&list[index as usize])
+}
+
+/// `elem value list`
+///
+/// Returns whether `list` contains an element equal to `value`.
+pub fn elem<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let value = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    for element in list.iter() {
+        let element = ctx.force(element)?;
+        if ctx.equal(value.clone(), element)? {
+            return Ok(Value::Bool(true));
+        }
+    }
+    Ok(Value::Bool(false))
+}
+
+/// `map function list`
+///
+/// The function is only called when an element of the result is forced.
+pub fn map<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[1], span)?;
+    let mapped = list
+        .iter()
+        .map(|element| ctx.lazy_call(args[0].clone(), element.clone(), span))
+        .collect();
+    Ok(Value::List(Rc::new(mapped)))
+}
+
+/// `filter predicate list`
+pub fn filter<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let predicate = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    let mut filtered = Vec::new();
+    for element in list.iter() {
+        if call_predicate(ctx, &predicate, element, span)? {
+            filtered.push(element.clone());
+        }
+    }
+    Ok(Value::List(Rc::new(filtered)))
+}
+
+/// `foldl' op initial list`
+///
+/// Folds the list from the left, forcing the accumulator after every step.
+pub fn foldl<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let op = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[2], span)?;
+    let mut acc = ctx.force(&args[1])?;
+    for element in list.iter() {
+        let partial = ctx.call(op.clone(), Rc::new(Closure::evaluated(acc)), span)?;
+        acc = ctx.call(partial, element.clone(), span)?;
+    }
+    Ok(acc)
+}
+
+/// `concatLists lists`
+pub fn concat_lists<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let lists = force_list(ctx, &args[0], span)?;
+    let mut concatenated = Vec::new();
+    for list in lists.iter() {
+        concatenated.extend(force_list(ctx, list, span)?.iter().cloned());
+    }
+    Ok(Value::List(Rc::new(concatenated)))
+}
+
+/// `concatMap function list`
+///
+/// Like `concatLists (map function list)`.
+pub fn concat_map<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let function = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    let mut concatenated = Vec::new();
+    for element in list.iter() {
+        match ctx.call(function.clone(), element.clone(), span)? {
+            Value::List(list) => concatenated.extend(list.iter().cloned()),
+            other => return Err(type_mismatch(Type::List, &other, span)),
+        }
+    }
+    Ok(Value::List(Rc::new(concatenated)))
+}
+
+/// `genList generator length`
+///
+/// Creates the list `[ (generator 0) ... (generator (length - 1)) ]`. The
+/// generator is only called when an element is forced.
+pub fn gen_list<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let length = force_int(ctx, &args[1], span)?;
+    if length < 0 {
+        return Err(Error::NegativeLength { length, span });
+    }
+    let list = (0..length)
+        .map(|index| {
+            let index = Rc::new(Closure::evaluated(Value::Int(index)));
+            ctx.lazy_call(args[0].clone(), index, span)
+        })
+        .collect();
+    Ok(Value::List(Rc::new(list)))
+}
+
+/// `sort lessThan list`
+///
+/// Sorts the list using the comparator `lessThan`, which returns whether its
+/// first argument is less than the second one. The sort is stable.
+pub fn sort<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let less_than = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    let mut less = |ctx: &mut EvalContext<'a>, left: &Thunk<'a>, right: &Thunk<'a>| {
+        let partial = ctx.call(less_than.clone(), left.clone(), span)?;
+        call_predicate(ctx, &partial, right, span)
+    };
+    let sorted = merge_sort(ctx, &list, &mut less)?;
+    Ok(Value::List(Rc::new(sorted)))
+}
+
+/// Sorts `list` stably using the fallible comparison `less`.
+///
+/// `slice::sort_by` can't be used, since the comparator can fail and since
+/// it requires a total order, which user-defined comparators might not
+/// implement.
+fn merge_sort<'a, F>(
+    ctx: &mut EvalContext<'a>,
+    list: &[Thunk<'a>],
+    less: &mut F,
+) -> Result<Vec<Thunk<'a>>, Error>
+where
+    F: FnMut(&mut EvalContext<'a>, &Thunk<'a>, &Thunk<'a>) -> Result<bool, Error>,
+{
+    if list.len() <= 1 {
+        return Ok(list.to_vec());
+    }
+
+    let (left, right) = list.split_at(list.len() / 2);
+    let left = merge_sort(ctx, left, less)?;
+    let right = merge_sort(ctx, right, less)?;
+
+    let mut merged = Vec::with_capacity(list.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    loop {
+        let take_right = match (left.peek(), right.peek()) {
+            // Only take the right element if it is strictly less, to keep
+            // the sort stable
+            (Some(l), Some(r)) => less(ctx, r, l)?,
+            (Some(_), None) => false,
+            (None, Some(_)) => true,
+            (None, None) => break,
+        };
+        let next = if take_right { right.next() } else { left.next() };
+        merged.extend(next);
+    }
+    Ok(merged)
+}
+
+/// `partition predicate list`
+///
+/// Returns a set with the elements for which the predicate is true in
+/// `right`, and the other ones in `wrong`.
+pub fn partition<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let predicate = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    let (mut right, mut wrong) = (Vec::new(), Vec::new());
+    for element in list.iter() {
+        if call_predicate(ctx, &predicate, element, span)? {
+            right.push(element.clone());
+        } else {
+            wrong.push(element.clone());
+        }
+    }
+
+    let mut set = BTreeMap::new();
+    set.insert("right".to_string(), list_thunk(right));
+    set.insert("wrong".to_string(), list_thunk(wrong));
+    Ok(Value::Set(Rc::new(set.into())))
+}
+
+/// `groupBy function list`
+///
+/// Groups the elements by the string `function` returns for them.
+pub fn group_by<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let function = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    let mut groups = BTreeMap::<String, Vec<Thunk<'a>>>::new();
+    for element in list.iter() {
+        let name = match ctx.call(function.clone(), element.clone(), span)? {
+            Value::String(name) => name,
+            other => return Err(type_mismatch(Type::String, &other, span)),
+        };
+        groups.entry(name.to_string()).or_default().push(element.clone());
+    }
+
+    let set = groups
+        .into_iter()
+        .map(|(name, group)| (name, list_thunk(group)))
+        .collect();
+    Ok(Value::Set(Rc::new(set)))
+}
+
+/// `all predicate list`
+pub fn all<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let predicate = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    for element in list.iter() {
+        if !call_predicate(ctx, &predicate, element, span)? {
+            return Ok(Value::Bool(false));
+        }
+    }
+    Ok(Value::Bool(true))
+}
+
+/// `any predicate list`
+pub fn any<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let predicate = ctx.force(&args[0])?;
+    let list = force_list(ctx, &args[1], span)?;
+    for element in list.iter() {
+        if call_predicate(ctx, &predicate, element, span)? {
+            return Ok(Value::Bool(true));
+        }
+    }
+    Ok(Value::Bool(false))
+}
+
+/// `listToAttrs list`
+///
+/// Builds a set from a list of `{ name = ...; value = ...; }` sets. If a name
+/// occurs more than once, the first value is used.
+pub fn list_to_attrs<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[0], span)?;
+    let mut set = BTreeMap::new();
+    for element in list.iter() {
+        let entry = force_set(ctx, element, span)?;
+        let attr = |name: &str| {
+            entry.get(name).cloned().ok_or_else(|| Error::MissingAttribute {
+                name: name.to_string(),
+                suggestions: Vec::new(),
+                span,
+            })
+        };
+        let (name, value) = (attr("name")?, attr("value")?);
+        let name = force_attr_name(ctx, &name, span)?;
+        set.entry(name).or_insert(value);
+    }
+    Ok(Value::Set(Rc::new(set.into())))
+}
+
+/// Creates an evaluated thunk containing a list.
+fn list_thunk<'a>(list: Vec<Thunk<'a>>) -> Thunk<'a> {
+    Rc::new(Closure::evaluated(Value::List(Rc::new(list))))
+}
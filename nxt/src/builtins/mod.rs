@@ -0,0 +1,315 @@
+//! Built-in functions and values.
+//!
+//! Functions implemented in Rust are called "primops" (like in Nix). They are
+//! stored in `Value::Builtin`, which also collects the arguments passed to
+//! them until they have received all of them.
+//!
+//! All builtins are attributes of the global `builtins` set. Some of them are
+//! also available as global variables.
+
+mod attrs;
+mod ere;
+mod import;
+mod lists;
+mod search_path;
+mod strings;
+mod types;
+
+pub(crate) use self::ere::Regex;
+
+use config::Config;
+use eval::{attr_name_string, Error, EvalContext};
+use value::{NixString, Set, Thunk, Type, Value};
+
+use codemap::Span;
+use std::fmt;
+use std::rc::Rc;
+
+/// The signature of primop implementations.
+///
+/// Primops are called with exactly as many (unevaluated) arguments as their
+/// arity specifies. `span` is the span of the call.
+type PrimOpFn = dyn for<'a> Fn(&mut EvalContext<'a>, &[Thunk<'a>], Span) -> Result<Value<'a>, Error>;
+
+/// A function implemented in Rust.
+///
+/// Applications can make their own primops available to Nix code using
+/// `EvalContext::register_builtin`.
+pub struct PrimOp {
+    name: String,
+    arity: usize,
+    strict: bool,
+    doc: Option<String>,
+    function: Box<PrimOpFn>,
+}
+
+impl PrimOp {
+    /// Creates a primop that takes `arity` arguments.
+    ///
+    /// `function` receives the arguments as thunks, which it can force with
+    /// `EvalContext::force` if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `arity` is zero. Builtins that aren't functions can be
+    /// registered with `EvalContext::register_value` instead.
+    pub fn new<F>(name: &str, arity: usize, function: F) -> Self
+    where
+        F: for<'a> Fn(&mut EvalContext<'a>, &[Thunk<'a>], Span) -> Result<Value<'a>, Error> + 'static,
+    {
+        assert!(arity > 0, "primop `{}` must take at least one argument", name);
+        Self {
+            name: name.to_string(),
+            arity,
+            strict: false,
+            doc: None,
+            function: Box::new(function),
+        }
+    }
+
+    /// Sets whether all arguments are forced before the primop is called.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the documentation of the primop.
+    pub fn doc<D: Into<String>>(mut self, doc: D) -> Self {
+        self.doc = Some(doc.into());
+        self
+    }
+
+    /// Returns the name of this primop.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of arguments this primop takes.
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// Returns whether all arguments are forced before calling the primop.
+    pub fn is_strict(&self) -> bool {
+        self.strict
+    }
+
+    /// Returns the documentation of this primop, if it has any.
+    pub fn documentation(&self) -> Option<&str> {
+        self.doc.as_ref().map(|doc| &**doc)
+    }
+
+    /// Calls the primop with all of its arguments.
+    pub fn call<'a>(
+        &self,
+        ctx: &mut EvalContext<'a>,
+        args: &[Thunk<'a>],
+        span: Span,
+    ) -> Result<Value<'a>, Error> {
+        debug_assert_eq!(args.len(), self.arity);
+        if self.strict {
+            for arg in args {
+                ctx.force(arg)?;
+            }
+        }
+        (self.function)(ctx, args, span)
+    }
+}
+
+impl fmt::Debug for PrimOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<PRIMOP {}/{}>", self.name, self.arity)
+    }
+}
+
+/// Creates a `Value` for a primop that hasn't been applied to any arguments.
+pub fn primop<'a>(primop: PrimOp) -> Value<'a> {
+    Value::Builtin {
+        primop: Rc::new(primop),
+        args: Vec::new(),
+    }
+}
+
+/// Creates the error for a primop argument of the wrong type.
+fn type_mismatch(expected: Type, found: &Value, span: Span) -> Error {
+    Error::TypeMismatch {
+        expected,
+        found: found.type_(),
+        span,
+    }
+}
+
+/// Forces a primop argument that must be a list.
+fn force_list<'a>(
+    ctx: &mut EvalContext<'a>,
+    thunk: &Thunk<'a>,
+    span: Span,
+) -> Result<Rc<Vec<Thunk<'a>>>, Error> {
+    match ctx.force(thunk)? {
+        Value::List(list) => Ok(list),
+        other => Err(type_mismatch(Type::List, &other, span)),
+    }
+}
+
+/// Forces a primop argument that must be a set.
+fn force_set<'a>(
+    ctx: &mut EvalContext<'a>,
+    thunk: &Thunk<'a>,
+    span: Span,
+) -> Result<Rc<Set<'a>>, Error> {
+    match ctx.force(thunk)? {
+        Value::Set(set) => Ok(set),
+        other => Err(type_mismatch(Type::Set, &other, span)),
+    }
+}
+
+/// Forces a primop argument that must be an integer.
+fn force_int<'a>(ctx: &mut EvalContext<'a>, thunk: &Thunk<'a>, span: Span) -> Result<i64, Error> {
+    match ctx.force(thunk)? {
+        Value::Int(i) => Ok(i),
+        other => Err(type_mismatch(Type::Int, &other, span)),
+    }
+}
+
+/// Forces a primop argument that must be a string (without coercing it).
+fn force_string<'a>(
+    ctx: &mut EvalContext<'a>,
+    thunk: &Thunk<'a>,
+    span: Span,
+) -> Result<NixString, Error> {
+    match ctx.force(thunk)? {
+        Value::String(s) => Ok(s),
+        other => Err(type_mismatch(Type::String, &other, span)),
+    }
+}
+
+/// Forces a primop argument that must be a string used as an attribute name.
+fn force_attr_name<'a>(ctx: &mut EvalContext<'a>, thunk: &Thunk<'a>, span: Span) -> Result<String, Error> {
+    let name = force_string(ctx, thunk, span)?;
+    attr_name_string(name, span)
+}
+
+/// Forces a primop argument and coerces it to a string, like string
+/// interpolation does.
+fn coerce_string<'a>(
+    ctx: &mut EvalContext<'a>,
+    thunk: &Thunk<'a>,
+    span: Span,
+) -> Result<NixString, Error> {
+    let value = ctx.force(thunk)?;
+    ctx.coerce_to_string(value, span)
+}
+
+/// Calls `function`, which must return a boolean, with `argument`.
+fn call_predicate<'a>(
+    ctx: &mut EvalContext<'a>,
+    function: &Value<'a>,
+    argument: &Thunk<'a>,
+    span: Span,
+) -> Result<bool, Error> {
+    match ctx.call(function.clone(), argument.clone(), span)? {
+        Value::Bool(b) => Ok(b),
+        other => Err(type_mismatch(Type::Bool, &other, span)),
+    }
+}
+
+/// Builtins that are also visible as global variables under the same name
+/// (the same ones as in Nix, except for `derivation`, `fetchTarball` and
+/// `placeholder`, which aren't implemented).
+///
+/// All others are only visible as `__name`.
+const GLOBALS: &[&str] = &[
+    "abort",
+    "baseNameOf",
+    "dirOf",
+    "false",
+    "import",
+    "isNull",
+    "map",
+    "null",
+    "removeAttrs",
+    "scopedImport",
+    "throw",
+    "toString",
+    "true",
+];
+
+/// Returns the name of the global variable that refers to `builtins.<name>`.
+pub fn global_name(name: &str) -> String {
+    if GLOBALS.contains(&name) {
+        name.to_string()
+    } else {
+        format!("__{}", name)
+    }
+}
+
+/// Returns the contents of the `builtins` set.
+pub fn builtins<'a>(config: &Config) -> Vec<(&'static str, Value<'a>)> {
+    vec![
+        ("true", Value::Bool(true)),
+        ("false", Value::Bool(false)),
+        ("null", Value::Null),
+        ("import", primop(PrimOp::new("import", 1, import::import))),
+        ("scopedImport", primop(PrimOp::new("scopedImport", 2, import::scoped_import))),
+        ("nixPath", search_path::nix_path(&config.nix_path)),
+        ("findFile", primop(PrimOp::new("findFile", 2, search_path::find_file))),
+        ("throw", primop(PrimOp::new("throw", 1, types::throw))),
+        ("abort", primop(PrimOp::new("abort", 1, types::abort))),
+        ("typeOf", primop(PrimOp::new("typeOf", 1, types::type_of))),
+        ("isNull", primop(types::is_type("isNull", Type::Null))),
+        ("isInt", primop(types::is_type("isInt", Type::Int))),
+        ("isFloat", primop(types::is_type("isFloat", Type::Float))),
+        ("isBool", primop(types::is_type("isBool", Type::Bool))),
+        ("isString", primop(types::is_type("isString", Type::String))),
+        ("isPath", primop(types::is_type("isPath", Type::Path))),
+        ("isList", primop(types::is_type("isList", Type::List))),
+        ("isAttrs", primop(types::is_type("isAttrs", Type::Set))),
+        ("isFunction", primop(types::is_type("isFunction", Type::Lambda))),
+        ("length", primop(PrimOp::new("length", 1, lists::length))),
+        ("head", primop(PrimOp::new("head", 1, lists::head))),
+        ("tail", primop(PrimOp::new("tail", 1, lists::tail))),
+        ("elemAt", primop(PrimOp::new("elemAt", 2, lists::elem_at))),
+        ("elem", primop(PrimOp::new("elem", 2, lists::elem))),
+        ("map", primop(PrimOp::new("map", 2, lists::map))),
+        ("filter", primop(PrimOp::new("filter", 2, lists::filter))),
+        ("foldl'", primop(PrimOp::new("foldl'", 3, lists::foldl))),
+        ("concatLists", primop(PrimOp::new("concatLists", 1, lists::concat_lists))),
+        ("concatMap", primop(PrimOp::new("concatMap", 2, lists::concat_map))),
+        ("genList", primop(PrimOp::new("genList", 2, lists::gen_list))),
+        ("sort", primop(PrimOp::new("sort", 2, lists::sort))),
+        ("partition", primop(PrimOp::new("partition", 2, lists::partition))),
+        ("groupBy", primop(PrimOp::new("groupBy", 2, lists::group_by))),
+        ("all", primop(PrimOp::new("all", 2, lists::all))),
+        ("any", primop(PrimOp::new("any", 2, lists::any))),
+        ("listToAttrs", primop(PrimOp::new("listToAttrs", 1, lists::list_to_attrs))),
+        ("attrNames", primop(PrimOp::new("attrNames", 1, attrs::attr_names))),
+        ("attrValues", primop(PrimOp::new("attrValues", 1, attrs::attr_values))),
+        ("getAttr", primop(PrimOp::new("getAttr", 2, attrs::get_attr))),
+        ("hasAttr", primop(PrimOp::new("hasAttr", 2, attrs::has_attr))),
+        ("removeAttrs", primop(PrimOp::new("removeAttrs", 2, attrs::remove_attrs))),
+        ("intersectAttrs", primop(PrimOp::new("intersectAttrs", 2, attrs::intersect_attrs))),
+        ("catAttrs", primop(PrimOp::new("catAttrs", 2, attrs::cat_attrs))),
+        ("mapAttrs", primop(PrimOp::new("mapAttrs", 2, attrs::map_attrs))),
+        ("zipAttrsWith", primop(PrimOp::new("zipAttrsWith", 2, attrs::zip_attrs_with))),
+        ("functionArgs", primop(PrimOp::new("functionArgs", 1, attrs::function_args))),
+        (
+            "unsafeGetAttrPos",
+            primop(PrimOp::new("unsafeGetAttrPos", 2, attrs::unsafe_get_attr_pos)),
+        ),
+        ("stringLength", primop(PrimOp::new("stringLength", 1, strings::string_length))),
+        ("substring", primop(PrimOp::new("substring", 3, strings::substring))),
+        ("replaceStrings", primop(PrimOp::new("replaceStrings", 3, strings::replace_strings))),
+        (
+            "concatStringsSep",
+            primop(PrimOp::new("concatStringsSep", 2, strings::concat_strings_sep)),
+        ),
+        ("toString", primop(PrimOp::new("toString", 1, strings::to_string))),
+        ("baseNameOf", primop(PrimOp::new("baseNameOf", 1, strings::base_name_of))),
+        ("dirOf", primop(PrimOp::new("dirOf", 1, strings::dir_of))),
+        ("splitVersion", primop(PrimOp::new("splitVersion", 1, strings::split_version))),
+        ("compareVersions", primop(PrimOp::new("compareVersions", 2, strings::compare_versions))),
+        ("parseDrvName", primop(PrimOp::new("parseDrvName", 1, strings::parse_drv_name))),
+        ("match", primop(PrimOp::new("match", 2, ere::match_))),
+        ("split", primop(PrimOp::new("split", 2, ere::split))),
+    ]
+}
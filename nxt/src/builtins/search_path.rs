@@ -0,0 +1,57 @@
+//! `builtins.nixPath` and `builtins.findFile`.
+
+use builtins::{coerce_string, force_list, force_set};
+use config::SearchPathEntry;
+use eval::{Error, EvalContext};
+use value::{Closure, Thunk, Value};
+
+use codemap::Span;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// Creates the value of `builtins.nixPath`, a list of `{ prefix, path }`
+/// sets.
+pub fn nix_path<'a>(entries: &[SearchPathEntry]) -> Value<'a> {
+    let string = |s: &str| Rc::new(Closure::evaluated(Value::String(s.into())));
+    let list = entries
+        .iter()
+        .map(|entry| {
+            let mut set = BTreeMap::new();
+            set.insert("prefix".to_string(), string(&entry.prefix));
+            set.insert("path".to_string(), string(&entry.path));
+            Rc::new(Closure::evaluated(Value::Set(Rc::new(set.into()))))
+        })
+        .collect();
+    Value::List(Rc::new(list))
+}
+
+/// `findFile searchPath name`
+///
+/// Resolves `name` like a `<name>` path, using a search path in the format of
+/// `builtins.nixPath`.
+pub fn find_file<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let list = force_list(ctx, &args[0], span)?;
+
+    let mut entries = Vec::with_capacity(list.len());
+    for entry in list.iter() {
+        let entry = force_set(ctx, entry, span)?;
+        let prefix = match entry.get("prefix") {
+            Some(prefix) => coerce_string(ctx, prefix, span)?.to_string(),
+            None => String::new(),
+        };
+        let path = match entry.get("path") {
+            Some(path) => coerce_string(ctx, path, span)?.to_string(),
+            None => {
+                return Err(Error::MissingAttribute {
+                    name: "path".to_string(),
+                    suggestions: Vec::new(),
+                    span,
+                });
+            }
+        };
+        entries.push(SearchPathEntry { prefix, path });
+    }
+
+    let name = coerce_string(ctx, &args[1], span)?.to_string();
+    Ok(Value::Path(ctx.find_file(&name, &entries, span)?))
+}
@@ -0,0 +1,331 @@
+//! Primops operating on strings.
+//!
+//! Nix strings are byte strings, so lengths and offsets are in bytes, like
+//! in Nix.
+
+use builtins::{force_int, force_list, force_string};
+use eval::{Error, EvalContext};
+use value::{Closure, NixString, Thunk, Value};
+
+use codemap::Span;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str;
+
+/// `stringLength string`
+///
+/// Returns the length of the string in bytes.
+pub fn string_length<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let value = ctx.force(&args[0])?;
+    let string = ctx.coerce_to_string(value, span)?;
+    Ok(Value::Int(string.len() as i64))
+}
+
+/// `substring start length string`
+///
+/// Returns `length` bytes of `string`, starting at the byte offset `start`.
+/// The range is clamped to the end of the string, and a negative length
+/// selects the rest of the string.
+pub fn substring<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let start = force_int(ctx, &args[0], span)?;
+    let length = force_int(ctx, &args[1], span)?;
+    let value = ctx.force(&args[2])?;
+    let string = ctx.coerce_to_string(value, span)?;
+    if start < 0 {
+        return Err(Error::NegativeStart { start, span });
+    }
+
+    let bytes = string.as_bytes();
+    let start = (start as u64).min(bytes.len() as u64) as usize;
+    let end = if length < 0 {
+        bytes.len()
+    } else {
+        (start as u64 + length as u64).min(bytes.len() as u64) as usize
+    };
+    Ok(Value::String(bytes[start..end].into()))
+}
+
+/// `replaceStrings from to string`
+///
+/// Replaces all occurrences of the strings in `from` with the corresponding
+/// strings in `to`. At every position, the first matching string in `from`
+/// is replaced, and the replacement isn't searched again. An empty string
+/// matches between all bytes.
+pub fn replace_strings<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let from = force_list(ctx, &args[0], span)?;
+    let to = force_list(ctx, &args[1], span)?;
+    if from.len() != to.len() {
+        return Err(Error::ReplacementCount {
+            from: from.len(),
+            to: to.len(),
+            span,
+        });
+    }
+    let patterns = from
+        .iter()
+        .map(|pattern| force_string(ctx, pattern, span))
+        .collect::<Result<Vec<_>, _>>()?;
+    let string = force_string(ctx, &args[2], span)?;
+    let bytes = string.as_bytes();
+
+    // Replacements are only forced when they're used
+    let mut replacements = vec![None; to.len()];
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut pos = 0;
+    while pos <= bytes.len() {
+        let found = patterns
+            .iter()
+            .position(|pattern| bytes[pos..].starts_with(pattern.as_bytes()));
+        if let Some(index) = found {
+            if replacements[index].is_none() {
+                replacements[index] = Some(force_string(ctx, &to[index], span)?);
+            }
+            result.extend_from_slice(replacements[index].as_ref().unwrap().as_bytes());
+            if !patterns[index].is_empty() {
+                pos += patterns[index].len();
+                continue;
+            }
+        }
+        if pos < bytes.len() {
+            result.push(bytes[pos]);
+        }
+        pos += 1;
+    }
+    Ok(Value::String(result.into()))
+}
+
+/// `concatStringsSep separator list`
+///
+/// Coerces the elements of the list to strings and joins them.
+pub fn concat_strings_sep<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let separator = force_string(ctx, &args[0], span)?;
+    let list = force_list(ctx, &args[1], span)?;
+    let mut result = NixString::new();
+    for (index, element) in list.iter().enumerate() {
+        if index > 0 {
+            result.push_bytes(&separator);
+        }
+        let element = ctx.force(element)?;
+        result.push_bytes(&ctx.coerce_to_string(element, span)?);
+    }
+    Ok(Value::String(result))
+}
+
+/// `toString value`
+///
+/// Converts a value to a string. Besides the values that can be interpolated
+/// into strings, this also accepts integers, floats, booleans (`"1"` or
+/// `""`), `null` (`""`) and lists (whose elements are separated by spaces).
+pub fn to_string<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let value = ctx.force(&args[0])?;
+    Ok(Value::String(coerce_more(ctx, value, span)?))
+}
+
+/// Coerces a value to a string, following the rules of `toString`.
+fn coerce_more<'a>(ctx: &mut EvalContext<'a>, value: Value<'a>, span: Span) -> Result<NixString, Error> {
+    match value {
+        Value::Int(i) => Ok(i.to_string().into()),
+        // Like C++'s `std::to_string`
+        Value::Float(f) => Ok(format!("{:.6}", f).into()),
+        Value::Bool(true) => Ok("1".into()),
+        Value::Bool(false) | Value::Null => Ok(NixString::new()),
+        Value::List(list) => {
+            let mut result = NixString::new();
+            for (index, element) in list.iter().enumerate() {
+                let element = ctx.force(element)?;
+                // Nix doesn't add a space after empty lists
+                let is_empty_list = match element {
+                    Value::List(ref list) => list.is_empty(),
+                    _ => false,
+                };
+                result.push_bytes(&coerce_more(ctx, element, span)?);
+                if index + 1 < list.len() && !is_empty_list {
+                    result.push_str(" ");
+                }
+            }
+            Ok(result)
+        }
+        Value::Set(ref set) if set.contains_key("__toString") => {
+            let function = ctx.force(&set["__toString"])?;
+            let string = ctx.call(function, Rc::new(Closure::evaluated(value.clone())), span)?;
+            coerce_more(ctx, string, span)
+        }
+        Value::Set(ref set) if set.contains_key("outPath") => {
+            let path = ctx.force(&set["outPath"])?;
+            coerce_more(ctx, path, span)
+        }
+        other => ctx.coerce_to_string(other, span),
+    }
+}
+
+/// `baseNameOf path`
+///
+/// Returns everything after the last `/` (ignoring a trailing `/`).
+pub fn base_name_of<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let value = ctx.force(&args[0])?;
+    let path = ctx.coerce_to_string(value, span)?;
+    let path = path.as_bytes();
+    if path.is_empty() {
+        return Ok(Value::String(NixString::new()));
+    }
+
+    let mut last = path.len() - 1;
+    if path[last] == b'/' && last > 0 {
+        last -= 1;
+    }
+    let start = path[..=last]
+        .iter()
+        .rposition(|&b| b == b'/')
+        .map_or(0, |pos| pos + 1);
+    Ok(Value::String(path[start..=last].into()))
+}
+
+/// `dirOf path`
+///
+/// Returns everything before the last `/`. The result is a path if the
+/// argument is a path, and a string otherwise.
+pub fn dir_of<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let value = ctx.force(&args[0])?;
+    let is_path = match value {
+        Value::Path(_) => true,
+        _ => false,
+    };
+    let path = ctx.coerce_to_string(value, span)?;
+    let path = path.as_bytes();
+    let dir = match path.iter().rposition(|&b| b == b'/') {
+        None => &b"."[..],
+        Some(0) => &b"/"[..],
+        Some(pos) => &path[..pos],
+    };
+    Ok(if is_path {
+        Value::Path(PathBuf::from(String::from_utf8_lossy(dir).into_owned()))
+    } else {
+        Value::String(dir.into())
+    })
+}
+
+/// `splitVersion version`
+///
+/// Splits a version string into its components (see `compareVersions`).
+pub fn split_version<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let version = force_string(ctx, &args[0], span)?;
+    let components = VersionComponents::new(&version)
+        .take_while(|component| !component.is_empty())
+        .map(|component| Rc::new(Closure::evaluated(Value::String(component.into()))))
+        .collect();
+    Ok(Value::List(Rc::new(components)))
+}
+
+/// `compareVersions v1 v2`
+///
+/// Returns -1, 0 or 1 if `v1` is older than, equal to or newer than `v2`.
+pub fn compare_versions<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let left = force_string(ctx, &args[0], span)?;
+    let right = force_string(ctx, &args[1], span)?;
+    Ok(Value::Int(match version_cmp(&left, &right) {
+        Ordering::Less => -1,
+        Ordering::Equal => 0,
+        Ordering::Greater => 1,
+    }))
+}
+
+/// `parseDrvName name`
+///
+/// Splits a derivation name like `nix-0.12pre12876` into its `name` (`nix`)
+/// and `version` (`0.12pre12876`), which starts after the first `-` that is
+/// not followed by a letter.
+pub fn parse_drv_name<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let full_name = force_string(ctx, &args[0], span)?;
+    let bytes = full_name.as_bytes();
+    let split = (0..bytes.len()).find(|&i| {
+        bytes[i] == b'-' && bytes.get(i + 1).map_or(false, |b| !b.is_ascii_alphabetic())
+    });
+    let (name, version) = match split {
+        Some(i) => (&bytes[..i], &bytes[i + 1..]),
+        None => (bytes, &b""[..]),
+    };
+
+    let mut set = BTreeMap::new();
+    for &(key, value) in &[("name", name), ("version", version)] {
+        let value = Rc::new(Closure::evaluated(Value::String(value.into())));
+        set.insert(key.to_string(), value);
+    }
+    Ok(Value::Set(Rc::new(set.into())))
+}
+
+/// Compares two versions component by component, like Nix does.
+fn version_cmp(left: &[u8], right: &[u8]) -> Ordering {
+    let (mut left, mut right) = (VersionComponents::new(left), VersionComponents::new(right));
+    while !left.rest.is_empty() || !right.rest.is_empty() {
+        let (l, r) = (left.next().unwrap(), right.next().unwrap());
+        if component_less(l, r) {
+            return Ordering::Less;
+        } else if component_less(r, l) {
+            return Ordering::Greater;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Returns whether the version component `left` is older than `right`.
+///
+/// Numbers are compared numerically and are newer than everything else,
+/// except that a missing component is older than a number. `pre` is older
+/// than everything else. Other strings are compared lexicographically.
+fn component_less(left: &[u8], right: &[u8]) -> bool {
+    let number = |component: &[u8]| str::from_utf8(component).ok()?.parse::<u64>().ok();
+    match (number(left), number(right)) {
+        (Some(l), Some(r)) => l < r,
+        (_, Some(_)) if left.is_empty() => true,
+        _ if left == b"pre" && right != b"pre" => true,
+        _ if right == b"pre" => false,
+        (_, Some(_)) => true,
+        (Some(_), _) => false,
+        _ => left < right,
+    }
+}
+
+/// Iterates over the components of a version string.
+///
+/// A component is either a sequence of digits or a sequence of other
+/// characters, and components are separated by `.` or `-` (or by switching
+/// between digits and other characters). At the end of the string, empty
+/// components are returned indefinitely.
+struct VersionComponents<'s> {
+    rest: &'s [u8],
+}
+
+impl<'s> VersionComponents<'s> {
+    fn new(version: &'s [u8]) -> Self {
+        Self { rest: version }
+    }
+}
+
+impl<'s> Iterator for VersionComponents<'s> {
+    type Item = &'s [u8];
+
+    fn next(&mut self) -> Option<&'s [u8]> {
+        let start = self
+            .rest
+            .iter()
+            .position(|&b| b != b'.' && b != b'-')
+            .unwrap_or(self.rest.len());
+        let rest = &self.rest[start..];
+        let is_digit = rest.first().map_or(false, u8::is_ascii_digit);
+        let end = rest
+            .iter()
+            .position(|&b| {
+                if is_digit {
+                    !b.is_ascii_digit()
+                } else {
+                    b.is_ascii_digit() || b == b'.' || b == b'-'
+                }
+            })
+            .unwrap_or(rest.len());
+        self.rest = &rest[end..];
+        Some(&rest[..end])
+    }
+}
+
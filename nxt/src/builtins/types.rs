@@ -0,0 +1,46 @@
+//! Type inspection (`typeOf` and the `is*` predicates), `throw` and `abort`.
+
+use builtins::{coerce_string, PrimOp};
+use eval::{Error, EvalContext};
+use value::{Thunk, Type, Value};
+
+use codemap::Span;
+
+/// `typeOf value`
+///
+/// Returns the name of the type of `value`, like `"int"` or `"set"`.
+pub fn type_of<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], _: Span) -> Result<Value<'a>, Error> {
+    let value = ctx.force(&args[0])?;
+    Ok(Value::String(value.type_().name().into()))
+}
+
+/// Creates the primop `name`, which returns whether its argument has type
+/// `ty` (like `isInt`).
+pub fn is_type(name: &str, ty: Type) -> PrimOp {
+    PrimOp::new(name, 1, move |ctx, args, _| {
+        let value = ctx.force(&args[0])?;
+        Ok(Value::Bool(value.type_() == ty))
+    }).strict(true)
+}
+
+/// `throw message`
+///
+/// Fails with `message` as the error message.
+pub fn throw<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let message = coerce_string(ctx, &args[0], span)?;
+    Err(Error::Thrown {
+        message: message.to_string(),
+        span,
+    })
+}
+
+/// `abort message`
+///
+/// Aborts evaluation, reporting `message`.
+pub fn abort<'a>(ctx: &mut EvalContext<'a>, args: &[Thunk<'a>], span: Span) -> Result<Value<'a>, Error> {
+    let message = coerce_string(ctx, &args[0], span)?;
+    Err(Error::Aborted {
+        message: message.to_string(),
+        span,
+    })
+}
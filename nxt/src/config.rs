@@ -1,3 +1,75 @@
+use std::fmt;
+
+/// Evaluator configuration.
+#[derive(Debug, Default)]
 pub struct Config {
     pub color: ::utils::ColorConfig,
+    /// The search path used to resolve `<name>` paths.
+    ///
+    /// Entries given with `-I` come first, followed by the ones from the
+    /// `NIX_PATH` environment variable.
+    pub nix_path: Vec<SearchPathEntry>,
+    /// Whether to report all independent syntax errors in a file, instead of
+    /// only the one that's most likely to be relevant.
+    pub all_syntax_errors: bool,
+}
+
+/// An entry of the Nix search path.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchPathEntry {
+    /// Names starting with this prefix are resolved using this entry.
+    ///
+    /// An empty prefix matches all names.
+    pub prefix: String,
+    /// The path the prefix maps to.
+    pub path: String,
+}
+
+impl<'a> From<&'a str> for SearchPathEntry {
+    /// Parses a `prefix=path` or `path` entry.
+    fn from(entry: &'a str) -> Self {
+        match entry.find('=') {
+            Some(index) => SearchPathEntry {
+                prefix: entry[..index].to_string(),
+                path: entry[index + 1..].to_string(),
+            },
+            None => SearchPathEntry {
+                prefix: String::new(),
+                path: entry.to_string(),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SearchPathEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix.is_empty() {
+            f.write_str(&self.path)
+        } else {
+            write!(f, "{}={}", self.prefix, self.path)
+        }
+    }
+}
+
+/// Parses a colon-separated search path like `NIX_PATH`.
+///
+/// Colons that are followed by `//` are part of a URL and don't separate
+/// entries.
+pub fn parse_nix_path(nix_path: &str) -> Vec<SearchPathEntry> {
+    let mut entries = Vec::new();
+    let mut start = 0;
+    for (index, _) in nix_path.match_indices(':') {
+        if nix_path[index + 1..].starts_with("//") {
+            continue;
+        }
+        entries.push(&nix_path[start..index]);
+        start = index + 1;
+    }
+    entries.push(&nix_path[start..]);
+
+    entries
+        .into_iter()
+        .filter(|entry| !entry.is_empty())
+        .map(SearchPathEntry::from)
+        .collect()
 }
@@ -0,0 +1,271 @@
+//! A serde `Deserializer` for Nix values.
+//!
+//! Sets are deserialized as maps (or structs), lists as sequences and `null`
+//! as unit or `None`. Enum variants are either strings (for unit variants) or
+//! sets with a single attribute named after the variant.
+
+use super::Error;
+use eval::EvalContext;
+use value::{Thunk, Value};
+
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::rc::Rc;
+use std::vec;
+
+/// Deserializes attribute names and enum variants.
+type StringDeserializer = de::value::StringDeserializer<Error>;
+
+/// Deserializes a `T` from a Nix value, forcing thunks as needed.
+pub fn from_value<'a, T>(ctx: &mut EvalContext<'a>, value: Value<'a>) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    T::deserialize(Deserializer::new(ctx, value))
+}
+
+/// Deserializes Rust data structures from a Nix value.
+///
+/// Errors report the attribute path to the value that could not be
+/// deserialized.
+#[derive(Debug)]
+pub struct Deserializer<'c, 'a: 'c> {
+    ctx: &'c mut EvalContext<'a>,
+    value: Value<'a>,
+}
+
+impl<'c, 'a> Deserializer<'c, 'a> {
+    /// Creates a deserializer reading from `value`.
+    pub fn new(ctx: &'c mut EvalContext<'a>, value: Value<'a>) -> Self {
+        Self { ctx, value }
+    }
+
+    /// Creates a deserializer reading from the value of `thunk`.
+    fn from_thunk(ctx: &'c mut EvalContext<'a>, thunk: &Thunk<'a>) -> Result<Self, Error> {
+        let value = ctx.force(thunk)?;
+        Ok(Self::new(ctx, value))
+    }
+}
+
+impl<'de, 'c, 'a> de::Deserializer<'de> for Deserializer<'c, 'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(s) => match s.to_str() {
+                Some(s) => visitor.visit_str(s),
+                None => visitor.visit_bytes(&s),
+            },
+            Value::Int(i) => visitor.visit_i64(i),
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::Path(path) => visitor.visit_str(&path.to_string_lossy()),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Null => visitor.visit_unit(),
+            Value::List(list) => visitor.visit_seq(SeqAccess {
+                ctx: self.ctx,
+                list,
+                index: 0,
+            }),
+            Value::Set(set) => {
+                let entries = set
+                    .iter()
+                    .map(|(name, thunk)| (name.clone(), thunk.clone()))
+                    .collect::<Vec<_>>();
+                visitor.visit_map(MapAccess {
+                    ctx: self.ctx,
+                    entries: entries.into_iter(),
+                    value: None,
+                })
+            }
+            Value::Lambda(_) | Value::Builtin { .. } => {
+                Err(Error::message("cannot deserialize a function".to_string()))
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.value {
+            Value::Null => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.value {
+            Value::String(s) => visitor.visit_enum(s.to_string().into_deserializer()),
+            Value::Set(ref set) if set.len() == 1 => {
+                let (variant, value) = set.iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    ctx: self.ctx,
+                    variant: variant.clone(),
+                    value: value.clone(),
+                })
+            }
+            other => Err(Error::message(format!(
+                "expected a string or a set with a single attribute, found {}",
+                other.type_()
+            ))),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// Deserializes the elements of a list.
+struct SeqAccess<'c, 'a: 'c> {
+    ctx: &'c mut EvalContext<'a>,
+    list: Rc<Vec<Thunk<'a>>>,
+    index: usize,
+}
+
+impl<'de, 'c, 'a> de::SeqAccess<'de> for SeqAccess<'c, 'a> {
+    type Error = Error;
+
+    fn next_element_seed<T>(&mut self, seed: T) -> Result<Option<T::Value>, Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        let index = self.index;
+        let thunk = match self.list.get(index) {
+            Some(thunk) => thunk.clone(),
+            None => return Ok(None),
+        };
+        self.index += 1;
+
+        Deserializer::from_thunk(&mut *self.ctx, &thunk)
+            .and_then(|deserializer| seed.deserialize(deserializer))
+            .map(Some)
+            .map_err(|e| e.in_element(index))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.list.len() - self.index)
+    }
+}
+
+/// Deserializes the attributes of a set.
+struct MapAccess<'c, 'a: 'c> {
+    ctx: &'c mut EvalContext<'a>,
+    entries: vec::IntoIter<(String, Thunk<'a>)>,
+    /// The attribute whose name was just deserialized.
+    value: Option<(String, Thunk<'a>)>,
+}
+
+impl<'de, 'c, 'a> de::MapAccess<'de> for MapAccess<'c, 'a> {
+    type Error = Error;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Error>
+    where
+        K: de::DeserializeSeed<'de>,
+    {
+        match self.entries.next() {
+            Some((name, thunk)) => {
+                let deserializer: StringDeserializer = name.clone().into_deserializer();
+                let key = seed.deserialize(deserializer)?;
+                self.value = Some((name, thunk));
+                Ok(Some(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let (name, thunk) = self
+            .value
+            .take()
+            .expect("`next_value_seed` called before `next_key_seed`");
+        Deserializer::from_thunk(&mut *self.ctx, &thunk)
+            .and_then(|deserializer| seed.deserialize(deserializer))
+            .map_err(|e| e.in_attr(&name))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.entries.len())
+    }
+}
+
+/// Deserializes an enum variant from a set `{ Variant = value; }`.
+struct EnumAccess<'c, 'a: 'c> {
+    ctx: &'c mut EvalContext<'a>,
+    variant: String,
+    value: Thunk<'a>,
+}
+
+impl<'c, 'a> EnumAccess<'c, 'a> {
+    /// Deserializes the value of the variant.
+    fn deserialize_value<T, F>(self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(Deserializer<'c, 'a>) -> Result<T, Error>,
+    {
+        let variant = self.variant;
+        Deserializer::from_thunk(self.ctx, &self.value)
+            .and_then(f)
+            .map_err(|e| e.in_attr(&variant))
+    }
+}
+
+impl<'de, 'c, 'a> de::EnumAccess<'de> for EnumAccess<'c, 'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V>(self, seed: V) -> Result<(V::Value, Self), Error>
+    where
+        V: de::DeserializeSeed<'de>,
+    {
+        let deserializer: StringDeserializer = self.variant.clone().into_deserializer();
+        let variant = seed.deserialize(deserializer)?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, 'c, 'a> de::VariantAccess<'de> for EnumAccess<'c, 'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        self.deserialize_value(|deserializer| de::Deserialize::deserialize(deserializer))
+    }
+
+    fn newtype_variant_seed<T>(self, seed: T) -> Result<T::Value, Error>
+    where
+        T: de::DeserializeSeed<'de>,
+    {
+        self.deserialize_value(|deserializer| seed.deserialize(deserializer))
+    }
+
+    fn tuple_variant<V>(self, _len: usize, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_value(|deserializer| de::Deserializer::deserialize_seq(deserializer, visitor))
+    }
+
+    fn struct_variant<V>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_value(|deserializer| de::Deserializer::deserialize_map(deserializer, visitor))
+    }
+}
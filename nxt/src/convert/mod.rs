@@ -0,0 +1,345 @@
+//! Conversions between Rust types and Nix values.
+//!
+//! `FromValue` extracts Rust values from (possibly partially evaluated) Nix
+//! values, forcing thunks as needed. `IntoValue` goes the other way, which is
+//! useful for returning values from primops.
+//!
+//! For more complex types, `from_value` can deserialize any type implementing
+//! `serde::Deserialize`.
+
+mod de;
+
+pub use self::de::{from_value, Deserializer};
+
+use eval::{self, EvalContext};
+use value::{Closure, Thunk, Type, Value};
+
+use serde::de as serde_de;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::{error, fmt};
+
+/// An error produced when converting a Nix value to a Rust type.
+///
+/// Errors remember the attribute path leading to the value that failed to
+/// convert.
+#[derive(Debug)]
+pub struct Error {
+    /// The path to the value, innermost segment first.
+    path: Vec<Segment>,
+    kind: ErrorKind,
+}
+
+#[derive(Debug)]
+enum ErrorKind {
+    Message(String),
+    Eval(eval::Error),
+}
+
+/// A component of the path to a converted value.
+#[derive(Debug)]
+enum Segment {
+    Attr(String),
+    Index(usize),
+}
+
+impl Error {
+    /// Creates an error reporting that a value has an unexpected type.
+    pub fn type_mismatch(expected: Type, found: Type) -> Self {
+        Self::message(format!("value is {} while {} was expected", found, expected))
+    }
+
+    fn message(message: String) -> Self {
+        Self {
+            path: Vec::new(),
+            kind: ErrorKind::Message(message),
+        }
+    }
+
+    /// Marks this error as occurring in the attribute `name` of a set.
+    pub fn in_attr(mut self, name: &str) -> Self {
+        self.path.push(Segment::Attr(name.to_string()));
+        self
+    }
+
+    /// Marks this error as occurring in the element `index` of a list.
+    pub fn in_element(mut self, index: usize) -> Self {
+        self.path.push(Segment::Index(index));
+        self
+    }
+
+    /// Returns the path to the value that failed to convert (eg.
+    /// `services.web.ports[1]`).
+    ///
+    /// The path is empty if the converted value itself is at fault.
+    pub fn path(&self) -> String {
+        let mut path = String::new();
+        for segment in self.path.iter().rev() {
+            match segment {
+                Segment::Attr(name) => {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(name);
+                }
+                Segment::Index(index) => path.push_str(&format!("[{}]", index)),
+            }
+        }
+        path
+    }
+
+    /// Returns the evaluation error that caused this error, if any.
+    pub fn eval_error(&self) -> Option<&eval::Error> {
+        match &self.kind {
+            ErrorKind::Eval(error) => Some(error),
+            ErrorKind::Message(_) => None,
+        }
+    }
+}
+
+impl From<eval::Error> for Error {
+    fn from(error: eval::Error) -> Self {
+        Self {
+            path: Vec::new(),
+            kind: ErrorKind::Eval(error),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.path.is_empty() {
+            write!(f, "at `{}`: ", self.path())?;
+        }
+        match &self.kind {
+            ErrorKind::Message(message) => f.write_str(message),
+            ErrorKind::Eval(error) => error.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl serde_de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self::message(msg.to_string())
+    }
+}
+
+/// Types that can be extracted from a Nix value.
+pub trait FromValue<'a>: Sized {
+    /// Converts `value`, forcing any thunks inside of it as needed.
+    fn from_value(ctx: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error>;
+
+    /// Forces `thunk` and converts its value.
+    fn from_thunk(ctx: &mut EvalContext<'a>, thunk: &Thunk<'a>) -> Result<Self, Error> {
+        let value = ctx.force(thunk)?;
+        Self::from_value(ctx, value)
+    }
+}
+
+/// Types that can be converted to a Nix value.
+pub trait IntoValue<'a> {
+    /// Converts `self` to a (fully evaluated) Nix value.
+    fn into_value(self) -> Value<'a>;
+
+    /// Converts `self` to an already evaluated thunk.
+    fn into_thunk(self) -> Thunk<'a>
+    where
+        Self: Sized,
+    {
+        Rc::new(Closure::evaluated(self.into_value()))
+    }
+}
+
+impl<'a> FromValue<'a> for Value<'a> {
+    fn from_value(_: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        Ok(value)
+    }
+}
+
+impl<'a> IntoValue<'a> for Value<'a> {
+    fn into_value(self) -> Value<'a> {
+        self
+    }
+}
+
+impl<'a> FromValue<'a> for bool {
+    fn from_value(_: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            other => Err(Error::type_mismatch(Type::Bool, other.type_())),
+        }
+    }
+}
+
+impl<'a> IntoValue<'a> for bool {
+    fn into_value(self) -> Value<'a> {
+        Value::Bool(self)
+    }
+}
+
+impl<'a> FromValue<'a> for i64 {
+    fn from_value(_: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        match value {
+            Value::Int(i) => Ok(i),
+            other => Err(Error::type_mismatch(Type::Int, other.type_())),
+        }
+    }
+}
+
+impl<'a> IntoValue<'a> for i64 {
+    fn into_value(self) -> Value<'a> {
+        Value::Int(self)
+    }
+}
+
+/// Implements the conversion traits for integer types other than `i64`.
+///
+/// Extracting an integer that is out of range is an error. Converting `u64`
+/// and `usize` values that don't fit in an `i64` wraps around.
+macro_rules! impl_int {
+    ( $($int:ty),* ) => {
+        $(
+            impl<'a> FromValue<'a> for $int {
+                fn from_value(ctx: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+                    let i = i64::from_value(ctx, value)?;
+                    let (min, max) = (<$int>::min_value() as i128, <$int>::max_value() as i128);
+                    if (i as i128) < min || (i as i128) > max {
+                        return Err(Error::message(format!(
+                            "integer {} does not fit in `{}`",
+                            i,
+                            stringify!($int),
+                        )));
+                    }
+                    Ok(i as $int)
+                }
+            }
+
+            impl<'a> IntoValue<'a> for $int {
+                fn into_value(self) -> Value<'a> {
+                    Value::Int(self as i64)
+                }
+            }
+        )*
+    };
+}
+
+impl_int!(i8, i16, i32, isize, u8, u16, u32, u64, usize);
+
+impl<'a> FromValue<'a> for f64 {
+    /// Integers are converted to floats, like in Nix arithmetic.
+    fn from_value(_: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        value
+            .as_number()
+            .ok_or_else(|| Error::type_mismatch(Type::Float, value.type_()))
+    }
+}
+
+impl<'a> IntoValue<'a> for f64 {
+    fn into_value(self) -> Value<'a> {
+        Value::Float(self)
+    }
+}
+
+impl<'a> FromValue<'a> for String {
+    fn from_value(_: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        match value {
+            Value::String(s) => Ok(s.to_string()),
+            other => Err(Error::type_mismatch(Type::String, other.type_())),
+        }
+    }
+}
+
+impl<'a> IntoValue<'a> for String {
+    fn into_value(self) -> Value<'a> {
+        Value::String(self.into())
+    }
+}
+
+impl<'a, 'b> IntoValue<'a> for &'b str {
+    fn into_value(self) -> Value<'a> {
+        Value::String(self.into())
+    }
+}
+
+impl<'a> FromValue<'a> for PathBuf {
+    /// Paths are extracted from path values and strings.
+    fn from_value(_: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        match value {
+            Value::Path(path) => Ok(path),
+            Value::String(s) => Ok(PathBuf::from(s.to_string_lossy().into_owned())),
+            other => Err(Error::type_mismatch(Type::Path, other.type_())),
+        }
+    }
+}
+
+impl<'a> IntoValue<'a> for PathBuf {
+    fn into_value(self) -> Value<'a> {
+        Value::Path(self)
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for Option<T> {
+    /// `null` is converted to `None`.
+    fn from_value(ctx: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        match value {
+            Value::Null => Ok(None),
+            other => T::from_value(ctx, other).map(Some),
+        }
+    }
+}
+
+impl<'a, T: IntoValue<'a>> IntoValue<'a> for Option<T> {
+    fn into_value(self) -> Value<'a> {
+        match self {
+            Some(value) => value.into_value(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for Vec<T> {
+    fn from_value(ctx: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        let list = match value {
+            Value::List(list) => list,
+            other => return Err(Error::type_mismatch(Type::List, other.type_())),
+        };
+        list.iter()
+            .enumerate()
+            .map(|(index, thunk)| T::from_thunk(ctx, thunk).map_err(|e| e.in_element(index)))
+            .collect()
+    }
+}
+
+impl<'a, T: IntoValue<'a>> IntoValue<'a> for Vec<T> {
+    fn into_value(self) -> Value<'a> {
+        Value::List(Rc::new(self.into_iter().map(T::into_thunk).collect()))
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for BTreeMap<String, T> {
+    fn from_value(ctx: &mut EvalContext<'a>, value: Value<'a>) -> Result<Self, Error> {
+        let set = match value {
+            Value::Set(set) => set,
+            other => return Err(Error::type_mismatch(Type::Set, other.type_())),
+        };
+        set.iter()
+            .map(|(name, thunk)| {
+                let value = T::from_thunk(ctx, thunk).map_err(|e| e.in_attr(name))?;
+                Ok((name.clone(), value))
+            })
+            .collect()
+    }
+}
+
+impl<'a, T: IntoValue<'a>> IntoValue<'a> for BTreeMap<String, T> {
+    fn into_value(self) -> Value<'a> {
+        let set = self
+            .into_iter()
+            .map(|(name, value)| (name, value.into_thunk()))
+            .collect();
+        Value::Set(Rc::new(set))
+    }
+}
@@ -0,0 +1,456 @@
+//! Dumps of the syntax trees of Nix expressions.
+//!
+//! Both the raw `rnix` parse tree and the lowered `ast::Expr` tree can be
+//! converted to a `SyntaxTree`, which can be printed as indented text or as
+//! JSON. This is meant for debugging the desugaring done by the AST builder
+//! and for external tools.
+
+use ast::{AttrPart, Expr, LambdaParameter, UnOp, Variable, Variables};
+use parser::node_span;
+use print::write_string;
+use value::Value;
+
+use codemap::{CodeMap, File, LineCol, Span};
+use rnix::parser::{Node, Types};
+use rowan::TreeRoot;
+use std::fmt::{self, Write};
+
+/// The kind of syntax tree to dump.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TreeKind {
+    /// The parse tree produced by `rnix`, including whitespace and comments.
+    Raw,
+    /// The desugared AST that is evaluated, with resolved variables and the
+    /// variables captured by each lambda.
+    Lowered,
+}
+
+/// A node of a dumped syntax tree.
+#[derive(Debug, Clone)]
+pub struct SyntaxTree {
+    kind: String,
+    location: Option<Location>,
+    fields: Vec<(&'static str, Field)>,
+    /// Unnamed child nodes (used by the raw parse tree).
+    children: Vec<SyntaxTree>,
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+    Node(Box<SyntaxTree>),
+    List(Vec<SyntaxTree>),
+}
+
+impl Field {
+    /// Returns whether the field is printed on the same line as its node.
+    fn is_inline(&self) -> bool {
+        match self {
+            Field::Node(_) => false,
+            Field::List(list) => list.is_empty(),
+            _ => true,
+        }
+    }
+}
+
+/// The source location of a node (with 0-based lines and columns).
+#[derive(Debug, Copy, Clone)]
+struct Location {
+    begin: LineCol,
+    end: LineCol,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{}",
+            self.begin.line + 1,
+            self.begin.column + 1,
+            self.end.line + 1,
+            self.end.column + 1
+        )
+    }
+}
+
+impl SyntaxTree {
+    fn new<K: Into<String>>(kind: K, location: Option<Location>) -> Self {
+        Self {
+            kind: kind.into(),
+            location,
+            fields: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn field(mut self, name: &'static str, value: Field) -> Self {
+        self.fields.push((name, value));
+        self
+    }
+
+    fn node(self, name: &'static str, node: SyntaxTree) -> Self {
+        self.field(name, Field::Node(Box::new(node)))
+    }
+
+    /// Returns the kind of this node (eg. `Apply` or `Token(Ident)`).
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Converts the tree to a JSON document.
+    ///
+    /// Every node is an object with a `kind`, a `location` (if the node has
+    /// one) containing 1-based `line`, `column`, `end_line` and `end_column`,
+    /// and its fields. Nodes of the raw parse tree also have `children`.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        out.push_str("{\"kind\":");
+        write_string(&self.kind, out);
+        if let Some(loc) = self.location {
+            write!(
+                out,
+                ",\"location\":{{\"line\":{},\"column\":{},\"end_line\":{},\"end_column\":{}}}",
+                loc.begin.line + 1,
+                loc.begin.column + 1,
+                loc.end.line + 1,
+                loc.end.column + 1
+            ).unwrap();
+        }
+        for (name, field) in &self.fields {
+            out.push(',');
+            write_string(name, out);
+            out.push(':');
+            match field {
+                Field::Str(s) => write_string(s, out),
+                Field::Int(i) => write!(out, "{}", i).unwrap(),
+                Field::Float(f) => write!(out, "{}", f).unwrap(),
+                Field::Bool(b) => write!(out, "{}", b).unwrap(),
+                Field::Null => out.push_str("null"),
+                Field::Node(node) => node.write_json(out),
+                Field::List(list) => write_json_list(list, out),
+            }
+        }
+        if !self.children.is_empty() {
+            out.push_str(",\"children\":");
+            write_json_list(&self.children, out);
+        }
+        out.push('}');
+    }
+
+    fn write_text(&self, indent: usize, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.kind)?;
+        if let Some(loc) = self.location {
+            write!(f, " @ {}", loc)?;
+        }
+        for (name, field) in self.fields.iter().filter(|(_, field)| field.is_inline()) {
+            match field {
+                Field::Str(s) => write!(f, " {}={:?}", name, s)?,
+                Field::Int(i) => write!(f, " {}={}", name, i)?,
+                Field::Float(x) => write!(f, " {}={}", name, x)?,
+                Field::Bool(b) => write!(f, " {}={}", name, b)?,
+                Field::Null => write!(f, " {}=null", name)?,
+                Field::List(_) => write!(f, " {}=[]", name)?,
+                Field::Node(_) => unreachable!(),
+            }
+        }
+        writeln!(f)?;
+
+        for (name, field) in self.fields.iter().filter(|(_, field)| !field.is_inline()) {
+            match field {
+                Field::Node(node) => {
+                    write!(f, "{:indent$}{}: ", "", name, indent = indent + 2)?;
+                    node.write_text(indent + 2, f)?;
+                }
+                Field::List(list) => {
+                    writeln!(f, "{:indent$}{}:", "", name, indent = indent + 2)?;
+                    for node in list {
+                        write!(f, "{:indent$}", "", indent = indent + 4)?;
+                        node.write_text(indent + 4, f)?;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+        for child in &self.children {
+            write!(f, "{:indent$}", "", indent = indent + 2)?;
+            child.write_text(indent + 2, f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints the tree as indented text, one node per line.
+impl fmt::Display for SyntaxTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_text(0, f)
+    }
+}
+
+fn write_json_list(list: &[SyntaxTree], out: &mut String) {
+    out.push('[');
+    for (index, node) in list.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        node.write_json(out);
+    }
+    out.push(']');
+}
+
+/// Dumps an `rnix` parse tree of `file`.
+pub fn raw<R: TreeRoot<Types>>(file: &File, node: &Node<R>) -> SyntaxTree {
+    let span = node_span(file, node);
+    let location = Location {
+        begin: file.find_line_col(span.low()),
+        end: file.find_line_col(span.high()),
+    };
+    let mut tree = SyntaxTree::new(format!("{:?}", node.kind()), Some(location));
+    if node.first_child().is_none() {
+        tree = tree.field("text", Field::Str(file.source_slice(span).to_string()));
+    }
+    tree.children = node.children().map(|child| raw(file, &child)).collect();
+    tree
+}
+
+/// Dumps a lowered expression.
+///
+/// The expressions of the variables bound by `let` and `with` expressions are
+/// looked up in `variables` and dumped along with them. References to
+/// variables only include their ID and name.
+pub fn lowered<'a>(codemap: &CodeMap, variables: &Variables<'a>, expr: &Expr<'a>) -> SyntaxTree {
+    Lowerer { codemap, variables }.expr(expr)
+}
+
+struct Lowerer<'c, 'a: 'c> {
+    codemap: &'c CodeMap,
+    variables: &'c Variables<'a>,
+}
+
+impl<'c, 'a> Lowerer<'c, 'a> {
+    fn expr(&self, expr: &Expr<'a>) -> SyntaxTree {
+        match *expr {
+            Expr::Apply {
+                lambda,
+                argument,
+                span,
+            } => self
+                .at("Apply", span)
+                .node("lambda", self.expr(lambda))
+                .node("argument", self.expr(argument)),
+            Expr::Assert {
+                assertion,
+                then,
+                span,
+            } => self
+                .at("Assert", span)
+                .node("assertion", self.expr(assertion))
+                .node("then", self.expr(then)),
+            Expr::BinOp {
+                op,
+                left,
+                right,
+                span,
+            } => self
+                .at("BinOp", span)
+                .field("op", Field::Str(op.to_string()))
+                .node("left", self.expr(left))
+                .node("right", self.expr(right)),
+            Expr::HasAttr { set, attr, span } => self
+                .at("HasAttr", span)
+                .node("set", self.expr(set))
+                .field("attr", self.attr(attr.parts)),
+            Expr::IfElse {
+                cond,
+                then,
+                els,
+                span,
+            } => self
+                .at("IfElse", span)
+                .node("cond", self.expr(cond))
+                .node("then", self.expr(then))
+                .node("else", self.expr(els)),
+            Expr::IndexSet {
+                set,
+                attr,
+                default,
+                span,
+            } => {
+                let default = match default {
+                    Some(default) => Field::Node(Box::new(self.expr(default))),
+                    None => Field::Null,
+                };
+                self.at("IndexSet", span)
+                    .node("set", self.expr(set))
+                    .field("attr", self.attr(attr.parts))
+                    .field("default", default)
+            }
+            Expr::Interpolate { parts, span } => self
+                .at("Interpolate", span)
+                .field("parts", self.exprs(parts)),
+            Expr::Lambda(lambda) => {
+                let param = match lambda.param {
+                    Some(LambdaParameter::Ident(var)) => Field::Node(Box::new(self.binding(var))),
+                    Some(LambdaParameter::Pattern(pattern)) => {
+                        let entries = pattern
+                            .entries
+                            .iter()
+                            .map(|entry| {
+                                let default = match entry.default {
+                                    Some(default) => Field::Node(Box::new(self.expr(default))),
+                                    None => Field::Null,
+                                };
+                                self.binding(entry.var).field("default", default)
+                            })
+                            .collect();
+                        let bind = match pattern.bind {
+                            Some(var) => Field::Node(Box::new(self.binding(var))),
+                            None => Field::Null,
+                        };
+                        let pattern = SyntaxTree::new("Pattern", None)
+                            .field("ellipsis", Field::Bool(pattern.ellipsis))
+                            .field("entries", Field::List(entries))
+                            .field("bind", bind);
+                        Field::Node(Box::new(pattern))
+                    }
+                    None => Field::Null,
+                };
+                self.at("Lambda", lambda.span)
+                    .field("captures", self.variables(lambda.captures))
+                    .field("param", param)
+                    .node("body", self.expr(lambda.body))
+            }
+            Expr::LetIn { vars, body } => {
+                let vars = vars.iter().map(|&var| self.binding(var)).collect();
+                SyntaxTree::new("LetIn", None)
+                    .field("vars", Field::List(vars))
+                    .node("body", self.expr(body))
+            }
+            Expr::List(elements) => {
+                SyntaxTree::new("List", None).field("elements", self.exprs(elements))
+            }
+            Expr::NixPath { name, span } => self
+                .at("NixPath", span)
+                .field("name", Field::Str(name.to_string())),
+            Expr::Set { entries, dynamic } => {
+                let entries = entries
+                    .iter()
+                    .map(|entry| {
+                        self.at("Entry", entry.span)
+                            .field("name", Field::Str(entry.name.to_string()))
+                            .node("value", self.expr(entry.value))
+                    })
+                    .collect();
+                let dynamic = dynamic
+                    .iter()
+                    .map(|entry| {
+                        self.at("DynamicEntry", entry.span)
+                            .node("name", self.expr(entry.name))
+                            .node("value", self.expr(entry.value))
+                    })
+                    .collect();
+                SyntaxTree::new("Set", None)
+                    .field("entries", Field::List(entries))
+                    .field("dynamic", Field::List(dynamic))
+            }
+            Expr::UnOp { op, operand, span } => {
+                let op = match op {
+                    UnOp::Negate => "-",
+                    UnOp::Not => "!",
+                };
+                self.at("UnOp", span)
+                    .field("op", Field::Str(op.to_string()))
+                    .node("operand", self.expr(operand))
+            }
+            Expr::Value(value) => {
+                let field = match value {
+                    Value::String(s) => Field::Str(s.to_string()),
+                    Value::Path(path) => Field::Str(path.display().to_string()),
+                    Value::Int(i) => Field::Int(*i),
+                    Value::Float(f) => Field::Float(*f),
+                    Value::Bool(b) => Field::Bool(*b),
+                    Value::Null => Field::Null,
+                    other => Field::Str(other.to_string()),
+                };
+                SyntaxTree::new("Value", None)
+                    .field("type", Field::Str(value.type_().name().to_string()))
+                    .field("value", field)
+            }
+            Expr::Variable(var) => self.variable(var),
+            Expr::With { namespace, body } => SyntaxTree::new("With", None)
+                .node("namespace", self.binding(namespace))
+                .node("body", self.expr(body)),
+            Expr::WithVariable {
+                name,
+                namespaces,
+                span,
+            } => self
+                .at("WithVariable", span)
+                .field("name", Field::Str(name.to_string()))
+                .field("namespaces", self.variables(namespaces)),
+        }
+    }
+
+    fn at(&self, kind: &str, span: Span) -> SyntaxTree {
+        SyntaxTree::new(kind, Some(self.location(span)))
+    }
+
+    fn location(&self, span: Span) -> Location {
+        let loc = self.codemap.look_up_span(span);
+        Location {
+            begin: loc.begin,
+            end: loc.end,
+        }
+    }
+
+    fn exprs(&self, exprs: &[&Expr<'a>]) -> Field {
+        Field::List(exprs.iter().map(|expr| self.expr(expr)).collect())
+    }
+
+    fn attr(&self, parts: &[AttrPart<'a>]) -> Field {
+        let parts = parts
+            .iter()
+            .map(|part| match part {
+                AttrPart::Name(name) => {
+                    SyntaxTree::new("Name", None).field("name", Field::Str(name.to_string()))
+                }
+                AttrPart::Dynamic(expr) => self.expr(expr),
+            })
+            .collect();
+        Field::List(parts)
+    }
+
+    /// A reference to a variable.
+    fn variable(&self, var: Variable) -> SyntaxTree {
+        let index: usize = var.into();
+        SyntaxTree::new("Variable", None)
+            .field("id", Field::Int(index as i64))
+            .field("name", Field::Str(self.variables[var].name.to_string()))
+    }
+
+    fn variables(&self, vars: &[Variable]) -> Field {
+        Field::List(vars.iter().map(|&var| self.variable(var)).collect())
+    }
+
+    /// The declaration of a variable, along with its expression if it has
+    /// one.
+    fn binding(&self, var: Variable) -> SyntaxTree {
+        let info = self.variables[var];
+        let index: usize = var.into();
+        let tree = SyntaxTree::new("Binding", Some(self.location(info.decl_span)))
+            .field("id", Field::Int(index as i64))
+            .field("name", Field::Str(info.name.to_string()));
+        match info.expr {
+            Some(expr) => tree.node("value", self.expr(expr)),
+            None => tree,
+        }
+    }
+}
@@ -1,16 +1,24 @@
 use ast::*;
-use config::Config;
-use utils::ResultExt;
-use value::Value;
-use {parser, profile};
-
-use codemap::{CodeMap, File};
-use codemap_diagnostic::{Diagnostic, Emitter};
-use std::path::{Path, PathBuf};
+use builtins::{PrimOp, Regex};
+use config::{Config, SearchPathEntry};
+use dump::{SyntaxTree, TreeKind};
+use utils::{self, ResultExt};
+use value::{Closure, NixString, Set, Thunk, Type, Value};
+use {builtins, dump, parser, profile, rnix};
+
+use codemap::{CodeMap, File, Span};
+use codemap_diagnostic::{Diagnostic, Emitter, Level, SpanLabel, SpanStyle};
+use hashbrown::HashMap;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 use std::sync::Arc;
-use std::{fs, io};
+use std::{fmt, fs, io};
 
 /// Nix expression source (file, command line, ...).
+#[derive(Debug)]
 pub enum Source<'a> {
     /// Read a Nix expression from a file.
     File {
@@ -33,19 +41,153 @@ pub enum Source<'a> {
     },
 }
 
+/// Evaluates Nix expressions.
+///
+/// A context holds all state shared between the files it evaluates, like the
+/// source code, caches of imported files and the global variables.
 pub struct EvalContext<'a> {
     arenas: &'a Arenas<'a>,
     codemap: CodeMap,
     config: Config,
+    /// Information about all variables declared in the evaluated files.
+    variables: Variables<'a>,
+    /// The global variables visible in every file.
+    prelude: Vec<(&'a str, Variable)>,
+    /// The attributes of the `builtins` set.
+    builtins: Vec<(String, Variable)>,
+    /// The variable holding the `builtins` set.
+    builtins_var: Variable,
+    /// The span all globals are "declared" at.
+    globals_span: Span,
+    /// The variables bound to the function and the argument in the lambdas
+    /// created by `lazy_call`.
+    call_vars: (Variable, Variable),
+    /// The lambdas created by `lazy_call`, by the span of the call.
+    calls: HashMap<Span, &'a Lambda<'a>>,
+    /// Thunks of global variables (like `true`) that have been used already.
+    globals: HashMap<Variable, Thunk<'a>>,
+    /// ASTs of imported files, by canonical path and names passed to
+    /// `scopedImport`.
+    asts: HashMap<(PathBuf, Vec<String>), (&'a Expr<'a>, &'a [Variable])>,
+    /// Values of files loaded with `import`, by canonical path.
+    ///
+    /// Files that are currently being evaluated are mapped to `None`, so
+    /// importing them again is reported as infinite recursion.
+    imports: HashMap<PathBuf, Option<Value<'a>>>,
+    /// Compiled regular expressions used by `match` and `split`, by pattern.
+    regexes: HashMap<Vec<u8>, Rc<Regex>>,
+}
+
+impl<'a> fmt::Debug for EvalContext<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EvalContext")
+            .field("config", &self.config)
+            .field("variables", &self.variables.len())
+            .field("imports", &self.imports.keys().collect::<Vec<_>>())
+            .finish()
+    }
 }
 
 impl<'a> EvalContext<'a> {
+    /// Creates a new evaluation context.
+    ///
+    /// `arenas` will hold the ASTs of all evaluated files.
     pub fn new(config: Config, arenas: &'a Arenas<'a>) -> Self {
-        Self {
+        let mut codemap = CodeMap::new();
+        // Globals are "declared" in this empty file
+        let globals_span = codemap.add_file("<builtins>".to_string(), String::new()).span;
+        let mut variables = Variables::new();
+        // Filled in once all builtins are defined
+        let builtins_var = Variable::from(variables.len());
+        variables.push(VarInfo {
+            decl_span: globals_span,
+            name: "builtins",
+            expr: None,
+        });
+        let mut hidden = |name| {
+            let var = Variable::from(variables.len());
+            variables.push(VarInfo {
+                decl_span: globals_span,
+                name,
+                expr: None,
+            });
+            var
+        };
+        let call_vars = (hidden("<function>"), hidden("<argument>"));
+        let builtins = builtins::builtins(&config);
+
+        let mut ctx = Self {
             arenas,
-            codemap: CodeMap::new(),
+            codemap,
             config,
+            variables,
+            prelude: vec![("builtins", builtins_var)],
+            builtins: Vec::new(),
+            builtins_var,
+            globals_span,
+            call_vars,
+            calls: HashMap::new(),
+            globals: HashMap::new(),
+            asts: HashMap::new(),
+            imports: HashMap::new(),
+            regexes: HashMap::new(),
+        };
+        for (name, value) in builtins {
+            ctx.define_builtin(name, &builtins::global_name(name), value);
         }
+        ctx
+    }
+
+    /// Registers a primop implemented by the application.
+    ///
+    /// The primop is available as `builtins.<name>` and as a global variable
+    /// with the same name, replacing any existing builtin of that name. Only
+    /// files evaluated after registering the primop can refer to it.
+    pub fn register_builtin(&mut self, primop: PrimOp) {
+        let name = primop.name().to_string();
+        self.define_builtin(&name, &name, builtins::primop(primop));
+    }
+
+    /// Registers a value provided by the application, like a constant or a
+    /// function evaluated with this context.
+    ///
+    /// Like primops registered with `register_builtin`, the value is available
+    /// as `builtins.<name>` and as a global variable with the same name.
+    pub fn register_value(&mut self, name: &str, value: Value<'a>) {
+        self.define_builtin(name, name, value);
+    }
+
+    /// Defines `builtins.<name>`, which is also visible as the global variable
+    /// `global`.
+    fn define_builtin(&mut self, name: &str, global: &str, value: Value<'a>) {
+        let info = VarInfo::global(self.arenas, global, value, self.globals_span);
+        let var = Variable::from(self.variables.len());
+        self.variables.push(info);
+        replace_or_push(&mut self.prelude, info.name, var);
+        replace_or_push(&mut self.builtins, name.to_string(), var);
+
+        // Rebuild the `builtins` set, which might have been evaluated already
+        self.variables[self.builtins_var] =
+            VarInfo::global_set(self.arenas, "builtins", &self.builtins, self.globals_span);
+        self.globals.remove(&self.builtins_var);
+    }
+
+    /// Returns the code map containing all evaluated files.
+    pub(crate) fn codemap(&self) -> &CodeMap {
+        &self.codemap
+    }
+
+    /// Returns the (empty) span that global variables are declared at.
+    ///
+    /// This is used for errors that can't be attributed to a location in the
+    /// source code.
+    pub(crate) fn globals_span(&self) -> Span {
+        self.globals_span
+    }
+
+    /// Returns the name of a variable.
+    pub(crate) fn variable_name(&self, var: Variable) -> &'a str {
+        self.variables[var].name
     }
 
     fn assimilate_source(&mut self, source: Source) -> Result<(Arc<File>, PathBuf), Error> {
@@ -78,20 +220,1179 @@ impl<'a> EvalContext<'a> {
     /// This process might read and parse more `.nix` files from the file
     /// system.
     pub fn eval(&mut self, source: Source) -> Result<Value<'a>, Error> {
+        self.eval_in_scope(source, &BTreeMap::new())
+    }
+
+    /// Evaluates a Nix expression in which the entries of `scope` are visible
+    /// as variables.
+    ///
+    /// Variables in `scope` shadow global variables (like `builtins`).
+    pub fn eval_in_scope(
+        &mut self,
+        source: Source,
+        scope: &BTreeMap<String, Thunk<'a>>,
+    ) -> Result<Value<'a>, Error> {
         let (file, search_path) = self.assimilate_source(source)?;
-        let raw_ast = parser::parse(&file).print_diagnostic(self)?;
-        let ast = Ast::build(&self.arenas, file, &search_path, raw_ast).print_diagnostic(self)?;
+        let raw_ast = parser::parse(&file, self.config.all_syntax_errors).print_diagnostic(self)?;
+        let names = scope.keys().map(|name| &**name).collect::<Vec<_>>();
+        let ast = Ast::build(
+            &self.arenas,
+            file,
+            &search_path,
+            raw_ast,
+            &mut self.variables,
+            &self.prelude,
+            &names,
+        ).print_diagnostic(self)?;
         debug!("AST={:#?}", ast);
 
-        self.eval_expr(ast.root())
+        let mut frame = Frame::new();
+        // The variables are in the same order as the (sorted) names
+        for (&var, value) in ast.scope().iter().zip(scope.values()) {
+            frame.vars.insert(var, value.clone());
+        }
+        self.eval_expr(ast.root(), &mut frame)
+            .map_err(|e| self.report(e))
+    }
+
+    /// Parses `source` without evaluating it and returns its syntax tree.
+    ///
+    /// The raw parse tree is returned even if the source contains syntax
+    /// errors (they show up as `Error` nodes). Building the lowered tree
+    /// reports syntax and name resolution errors like `eval` does.
+    pub fn syntax_tree(&mut self, source: Source, kind: TreeKind) -> Result<SyntaxTree, Error> {
+        let (file, search_path) = self.assimilate_source(source)?;
+        if kind == TreeKind::Raw {
+            let root = rnix::parse(file.source()).into_node();
+            return Ok(dump::raw(&file, &root));
+        }
+
+        let raw_ast = parser::parse(&file, self.config.all_syntax_errors).print_diagnostic(self)?;
+        let ast = Ast::build(
+            &self.arenas,
+            file,
+            &search_path,
+            raw_ast,
+            &mut self.variables,
+            &self.prelude,
+            &[],
+        ).print_diagnostic(self)?;
+        Ok(dump::lowered(&self.codemap, &self.variables, ast.root()))
+    }
+
+    /// Returns the names of all global variables.
+    pub fn global_names(&self) -> impl Iterator<Item = &str> {
+        self.prelude.iter().map(|(name, _)| *name)
+    }
+
+    /// Selects an attribute path (like `foo.bar.0.baz`) in `value`, the way
+    /// `nix-instantiate -A` does.
+    ///
+    /// Path components that are integers select list elements. Functions
+    /// encountered along the path, and the selected value itself, are called
+    /// with `args` (see `auto_call`).
+    pub fn select_attr_path(
+        &mut self,
+        value: Value<'a>,
+        path: &str,
+        args: &BTreeMap<String, Thunk<'a>>,
+    ) -> Result<Value<'a>, Error> {
+        // The path gets its own file, so that errors can point into it
+        let file = self.codemap.add_file("<attribute path>".to_string(), path.to_string());
+        let mut value = self.auto_call(value, args)?;
+        for (name, start, end) in split_attr_path(path) {
+            let span = file.span.subspan(start as u64, end as u64);
+            let thunk = match value {
+                Value::List(ref list) if name.parse::<usize>().is_ok() => {
+                    let index = name.parse::<usize>().unwrap();
+                    list.get(index).cloned().ok_or(Error::IndexOutOfBounds {
+                        index: index as i64,
+                        len: list.len(),
+                        span,
+                    })
+                }
+                Value::Set(ref set) => set.get(&name).cloned().ok_or_else(|| {
+                    let suggestions = utils::similar_names(&name, set.keys().map(|key| &**key))
+                        .into_iter()
+                        .map(String::from)
+                        .collect();
+                    Error::MissingAttribute {
+                        name: name.clone(),
+                        suggestions,
+                        span,
+                    }
+                }),
+                ref other => Err(Error::TypeMismatch {
+                    expected: Type::Set,
+                    found: other.type_(),
+                    span,
+                }),
+            };
+            let thunk = thunk.map_err(|e| self.report(e))?;
+            let selected = self.force(&thunk).map_err(|e| self.report(e))?;
+            value = self.auto_call(selected, args)?;
+        }
+        Ok(value)
+    }
+
+    /// Calls `value` with the arguments it expects from `args`, if it is a
+    /// function taking a set pattern (or a set with a `__functor`).
+    ///
+    /// This is how `nix-instantiate` passes `--arg` and `--argstr` to
+    /// functions. Other values are returned unchanged.
+    pub fn auto_call(
+        &mut self,
+        value: Value<'a>,
+        args: &BTreeMap<String, Thunk<'a>>,
+    ) -> Result<Value<'a>, Error> {
+        self.auto_call_impl(value, args).map_err(|e| self.report(e))
+    }
+
+    fn auto_call_impl(
+        &mut self,
+        value: Value<'a>,
+        args: &BTreeMap<String, Thunk<'a>>,
+    ) -> Result<Value<'a>, Error> {
+        match value {
+            Value::Lambda(ref closure) => {
+                let (lambda, _) = closure.code().expect("function closure has been evaluated");
+                let pattern = match lambda.param {
+                    Some(LambdaParameter::Pattern(pattern)) => pattern,
+                    _ => return Ok(value.clone()),
+                };
+
+                let mut passed = BTreeMap::new();
+                for entry in pattern.entries {
+                    let name = self.variables[entry.var].name;
+                    match args.get(name) {
+                        Some(arg) => {
+                            passed.insert(name.to_string(), arg.clone());
+                        }
+                        None if entry.default.is_some() => {}
+                        None => {
+                            return Err(Error::CannotAutoCall {
+                                name: name.to_string(),
+                                span: lambda.span,
+                            });
+                        }
+                    }
+                }
+                if pattern.ellipsis {
+                    passed = args.clone();
+                }
+
+                let argument = Rc::new(Closure::evaluated(Value::Set(Rc::new(passed.into()))));
+                self.call(value.clone(), argument, lambda.span)
+            }
+            Value::Set(ref set) if set.contains_key("__functor") => {
+                let functor = self.force(&set["__functor"])?;
+                let span = match functor {
+                    Value::Lambda(ref closure) => closure.code().map(|(lambda, _)| lambda.span),
+                    _ => None,
+                };
+                let set = Rc::new(Closure::evaluated(value.clone()));
+                let function = self.call(functor, set, span.unwrap_or(self.globals_span))?;
+                self.auto_call_impl(function, args)
+            }
+            other => Ok(other),
+        }
+    }
+
+    /// Imports the Nix file at `path`, or `default.nix` if it is a directory.
+    ///
+    /// With `scope` (used by `scopedImport`), the attributes of the set are
+    /// visible as variables in the file. Otherwise, the resulting value is
+    /// cached, so every file is only evaluated once. Importing a file while
+    /// it is being evaluated is reported as infinite recursion.
+    pub(crate) fn import(
+        &mut self,
+        path: &Path,
+        scope: Option<&BTreeMap<String, Thunk<'a>>>,
+        span: Span,
+    ) -> Result<Value<'a>, Error> {
+        let mut path = path.to_path_buf();
+        if path.is_dir() {
+            path.push("default.nix");
+        }
+        let path = fs::canonicalize(&path).map_err(|error| Error::ImportFailed {
+            path: path.display().to_string(),
+            error,
+            span,
+        })?;
+        if scope.is_none() {
+            match self.imports.get(&path) {
+                Some(Some(value)) => return Ok(value.clone()),
+                Some(None) => return Err(Error::InfiniteRecursion { span }),
+                None => {}
+            }
+        }
+
+        let names = scope
+            .map(|scope| scope.keys().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let key = (path, names);
+        let (root, vars) = match self.asts.get(&key) {
+            Some(&ast) => ast,
+            None => {
+                let ast = self.load(&key.0, &key.1, span)?;
+                self.asts.insert(key.clone(), ast);
+                ast
+            }
+        };
+
+        let mut frame = Frame::new();
+        if let Some(scope) = scope {
+            // The variables are in the same order as the (sorted) names
+            for (&var, value) in vars.iter().zip(scope.values()) {
+                frame.vars.insert(var, value.clone());
+            }
+        }
+        if scope.is_none() {
+            self.imports.insert(key.0.clone(), None);
+        }
+        let value = self.eval_expr(root, &mut frame);
+
+        if scope.is_none() {
+            match value {
+                Ok(ref value) => {
+                    self.imports.insert(key.0, Some(value.clone()));
+                }
+                Err(_) => {
+                    self.imports.remove(&key.0);
+                }
+            }
+        }
+        value
+    }
+
+    /// Resolves a `<name>` path using the search path `entries`.
+    ///
+    /// The first entry whose prefix matches `name` and that contains the rest
+    /// of `name` is used. Entries with an empty prefix match all names.
+    pub(crate) fn find_file(
+        &self,
+        name: &str,
+        entries: &[SearchPathEntry],
+        span: Span,
+    ) -> Result<PathBuf, Error> {
+        for entry in entries {
+            let rest = if entry.prefix.is_empty() {
+                name
+            } else if name == entry.prefix {
+                ""
+            } else if name.starts_with(&entry.prefix) && name[entry.prefix.len()..].starts_with('/') {
+                &name[entry.prefix.len() + 1..]
+            } else {
+                continue;
+            };
+
+            let path = Path::new(&entry.path).join(rest);
+            if path.exists() {
+                return Ok(path);
+            }
+        }
+
+        Err(Error::NotInSearchPath {
+            name: name.to_string(),
+            searched: entries.iter().map(|entry| entry.to_string()).collect(),
+            span,
+        })
     }
 
-    fn eval_expr<'e>(&mut self, expr: &'e Expr<'e>) -> Result<Value<'e>, Error> {
+    /// Reads, parses and translates the Nix file at `path`.
+    ///
+    /// `scope` lists the names of the variables defined by `scopedImport`.
+    fn load(
+        &mut self,
+        path: &Path,
+        scope: &[String],
+        span: Span,
+    ) -> Result<(&'a Expr<'a>, &'a [Variable]), Error> {
+        let (file, search_path) = self
+            .assimilate_source(Source::File { path })
+            .map_err(|error| match error {
+                Error::Io(error) => Error::ImportFailed {
+                    path: path.display().to_string(),
+                    error,
+                    span,
+                },
+                error => error,
+            })?;
+        let raw_ast = parser::parse(&file, self.config.all_syntax_errors).print_diagnostic(self)?;
+        let scope = scope.iter().map(|name| &**name).collect::<Vec<_>>();
+        let ast = Ast::build(
+            &self.arenas,
+            file,
+            &search_path,
+            raw_ast,
+            &mut self.variables,
+            &self.prelude,
+            &scope,
+        ).print_diagnostic(self)?;
+        debug!("AST of {}={:#?}", path.display(), ast);
+
+        Ok((ast.root(), ast.scope()))
+    }
+
+    /// Evaluates an expression to weak head normal form.
+    ///
+    /// The resulting value might still contain unevaluated thunks (eg. as list
+    /// elements).
+    fn eval_expr(&mut self, expr: &'a Expr<'a>, frame: &mut Frame<'a>) -> Result<Value<'a>, Error> {
         match expr {
             Expr::Value(val) => Ok((*val).clone()),
-            _ => unimplemented!(),
+            Expr::Variable(var) => {
+                let thunk = self.variable(*var, frame);
+                if thunk.is_evaluating() {
+                    // Point at the binding instead of the lambda it evaluates
+                    return Err(Error::InfiniteRecursion {
+                        span: self.variables[*var].decl_span,
+                    });
+                }
+
+                self.force(&thunk)
+            }
+            Expr::Apply {
+                lambda,
+                argument,
+                span,
+            } => {
+                let function = self.eval_expr(lambda, frame)?;
+                let argument = self.delay(argument, frame);
+                self.call(function, argument, *span)
+            }
+            Expr::Assert {
+                assertion,
+                then,
+                span,
+            } => {
+                if self.eval_bool(assertion, frame, *span)? {
+                    self.eval_expr(then, frame)
+                } else {
+                    Err(Error::AssertionFailed { span: *span })
+                }
+            }
+            Expr::IfElse {
+                cond,
+                then,
+                els,
+                span,
+            } => {
+                if self.eval_bool(cond, frame, *span)? {
+                    self.eval_expr(then, frame)
+                } else {
+                    self.eval_expr(els, frame)
+                }
+            }
+            Expr::Lambda(lambda) => match lambda.param {
+                // A transparent lambda's captures are all in the current frame
+                // already, so it can be evaluated right here.
+                None => self.eval_expr(lambda.body, frame),
+                Some(_) => {
+                    let captures = frame.capture(lambda);
+                    Ok(Value::Lambda(Rc::new(Closure::new(lambda, captures))))
+                }
+            },
+            Expr::LetIn { vars, body } => {
+                let mut pending = Vec::new();
+                for &var in vars.iter() {
+                    let expr = self.variables[var]
+                        .expr
+                        .expect("let-bound variable without expression");
+                    frame.bind_recursive(var, expr, &mut pending);
+                }
+                frame.finish_bindings(pending);
+
+                self.eval_expr(body, frame)
+            }
+            Expr::List(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|element| self.delay(element, frame))
+                    .collect();
+                Ok(Value::List(Rc::new(elements)))
+            }
+            Expr::Set { entries, dynamic } => {
+                let mut set = BTreeMap::new();
+                for entry in entries.iter() {
+                    let value = self.delay(entry.value, frame);
+                    set.insert(entry.name.to_string(), value);
+                }
+
+                // Spans of the dynamic entries, for reporting duplicates and
+                // for `unsafeGetAttrPos`
+                let mut dynamic_spans = Vec::new();
+                for entry in dynamic.iter() {
+                    let name = match self.eval_expr(entry.name, frame)? {
+                        Value::String(name) => attr_name_string(name, entry.span)?,
+                        // Entries with a `null` name are omitted
+                        Value::Null => continue,
+                        other => {
+                            return Err(Error::TypeMismatch {
+                                expected: Type::String,
+                                found: other.type_(),
+                                span: entry.span,
+                            });
+                        }
+                    };
+                    if set.contains_key(&*name) {
+                        let first = entries
+                            .iter()
+                            .find(|first| first.name == &*name)
+                            .map(|first| first.span)
+                            .or_else(|| {
+                                dynamic_spans
+                                    .iter()
+                                    .find(|(dynamic, _)| dynamic == &*name)
+                                    .map(|&(_, span)| span)
+                            })
+                            .expect("duplicate attribute has no span");
+                        return Err(Error::DuplicateAttribute {
+                            name: name.to_string(),
+                            span: entry.span,
+                            first,
+                        });
+                    }
+                    dynamic_spans.push((name.to_string(), entry.span));
+                    let value = self.delay(entry.value, frame);
+                    set.insert(name.to_string(), value);
+                }
+
+                let set = Set::with_positions(set, *entries, dynamic_spans);
+                Ok(Value::Set(Rc::new(set)))
+            }
+            Expr::BinOp {
+                op,
+                left,
+                right,
+                span,
+            } => self.eval_binop(*op, left, right, frame, *span),
+            Expr::UnOp { op, operand, span } => match (op, self.eval_expr(operand, frame)?) {
+                (UnOp::Not, Value::Bool(b)) => Ok(Value::Bool(!b)),
+                (UnOp::Negate, Value::Int(i)) => Ok(Value::Int(i.wrapping_neg())),
+                (UnOp::Negate, Value::Float(f)) => Ok(Value::Float(-f)),
+                (UnOp::Not, other) => Err(Error::TypeMismatch {
+                    expected: Type::Bool,
+                    found: other.type_(),
+                    span: *span,
+                }),
+                (UnOp::Negate, other) => Err(Error::TypeMismatch {
+                    expected: Type::Int,
+                    found: other.type_(),
+                    span: *span,
+                }),
+            },
+            Expr::HasAttr { set, attr, span } => {
+                let mut value = self.eval_expr(set, frame)?;
+                for (index, part) in attr.parts.iter().enumerate() {
+                    let name = self.attr_name(*part, frame, *span)?;
+                    let thunk = match value {
+                        Value::Set(ref set) => set.get(&*name).cloned(),
+                        _ => None,
+                    };
+
+                    match thunk {
+                        // The value of the last attribute isn't needed
+                        Some(thunk) => if index + 1 < attr.parts.len() {
+                            value = self.force(&thunk)?;
+                        },
+                        None => return Ok(Value::Bool(false)),
+                    }
+                }
+
+                Ok(Value::Bool(true))
+            }
+            Expr::IndexSet {
+                set,
+                attr,
+                default,
+                span,
+            } => {
+                let mut value = self.eval_expr(set, frame)?;
+                for part in attr.parts.iter() {
+                    let name = self.attr_name(*part, frame, *span)?;
+                    let thunk = match (value, default) {
+                        (Value::Set(set), _) => match set.get(&*name) {
+                            Some(thunk) => thunk.clone(),
+                            None => match default {
+                                Some(default) => return self.eval_expr(default, frame),
+                                None => {
+                                    let suggestions = utils::similar_names(&name, set.keys().map(|key| &**key))
+                                        .into_iter()
+                                        .map(|name| name.to_string())
+                                        .collect();
+                                    return Err(Error::MissingAttribute {
+                                        name: name.to_string(),
+                                        suggestions,
+                                        span: *span,
+                                    });
+                                }
+                            },
+                        },
+                        // With a default, selecting from a non-set is not an error
+                        (_, Some(default)) => return self.eval_expr(default, frame),
+                        (other, None) => {
+                            return Err(Error::TypeMismatch {
+                                expected: Type::Set,
+                                found: other.type_(),
+                                span: *span,
+                            });
+                        }
+                    };
+                    value = self.force(&thunk)?;
+                }
+
+                Ok(value)
+            }
+            Expr::NixPath { name, span } => {
+                let path = self.find_file(name, &self.config.nix_path, *span)?;
+                Ok(Value::Path(path))
+            }
+            Expr::Interpolate { parts, span } => {
+                let mut string = NixString::new();
+                for part in parts.iter() {
+                    let value = self.eval_expr(part, frame)?;
+                    string.push_bytes(&self.coerce_to_string(value, *span)?);
+                }
+
+                Ok(Value::String(string))
+            }
+            Expr::With { namespace, body } => {
+                let expr = self.variables[*namespace]
+                    .expr
+                    .expect("`with` namespace without expression");
+                let thunk = self.delay(expr, frame);
+                frame.vars.insert(*namespace, thunk);
+
+                self.eval_expr(body, frame)
+            }
+            Expr::WithVariable {
+                name,
+                namespaces,
+                span,
+            } => {
+                for &namespace in namespaces.iter() {
+                    let thunk = self.variable(namespace, frame);
+                    let value = match self.force(&thunk)? {
+                        Value::Set(set) => set.get(*name).cloned(),
+                        other => {
+                            return Err(Error::TypeMismatch {
+                                expected: Type::Set,
+                                found: other.type_(),
+                                span: self.variables[namespace].decl_span,
+                            });
+                        }
+                    };
+                    if let Some(value) = value {
+                        return self.force(&value);
+                    }
+                }
+
+                Err(Error::UndefinedVariable {
+                    name: name.to_string(),
+                    span: *span,
+                })
+            }
+        }
+    }
+
+    /// Evaluates an expression that must result in a boolean.
+    fn eval_bool(&mut self, expr: &'a Expr<'a>, frame: &mut Frame<'a>, span: Span) -> Result<bool, Error> {
+        let value = self.eval_expr(expr, frame)?;
+        value.as_bool().ok_or_else(|| Error::TypeMismatch {
+            expected: Type::Bool,
+            found: value.type_(),
+            span,
+        })
+    }
+
+    /// Creates a thunk that evaluates `expr` in `frame` when forced.
+    ///
+    /// `expr` must be an expression that can be instantiated without
+    /// evaluating anything, which the `Builder` ensures for all lazily
+    /// evaluated expressions.
+    fn delay(&mut self, expr: &'a Expr<'a>, frame: &Frame<'a>) -> Thunk<'a> {
+        match expr {
+            Expr::Value(value) => Rc::new(Closure::evaluated((*value).clone())),
+            Expr::Variable(var) => self.variable(*var, frame),
+            Expr::Lambda(lambda) => {
+                let closure = Rc::new(Closure::new(lambda, frame.capture(lambda)));
+                match lambda.param {
+                    None => closure,
+                    Some(_) => Rc::new(Closure::evaluated(Value::Lambda(closure))),
+                }
+            }
+            // `Builder::translate_lazy` only leaves literals, variables and
+            // lambdas unwrapped, everything else is put in a transparent lambda
+            _ => unreachable!("expression cannot be evaluated lazily: {:?}", expr),
+        }
+    }
+
+    /// Returns the compiled POSIX extended regular expression `pattern`.
+    ///
+    /// Compiled regexes are cached, since the same patterns tend to be used
+    /// many times (eg. by nixpkgs' `lib`).
+    pub(crate) fn regex(&mut self, pattern: &[u8], span: Span) -> Result<Rc<Regex>, Error> {
+        if let Some(regex) = self.regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        let regex = Rc::new(Regex::new(pattern).map_err(|message| Error::InvalidRegex {
+            pattern: String::from_utf8_lossy(pattern).into_owned(),
+            message,
+            span,
+        })?);
+        self.regexes.insert(pattern.to_vec(), regex.clone());
+        Ok(regex)
+    }
+
+    /// Creates a thunk that calls `function` with `argument` when forced.
+    ///
+    /// This is used by primops returning lazily evaluated values, like the
+    /// elements of the list returned by `map`. `span` is the span of the call.
+    pub(crate) fn lazy_call(&mut self, function: Thunk<'a>, argument: Thunk<'a>, span: Span) -> Thunk<'a> {
+        let (function_var, argument_var) = self.call_vars;
+        let arenas = self.arenas;
+        let lambda = *self
+            .calls
+            .entry(span)
+            .or_insert_with(|| Lambda::call(arenas, function_var, argument_var, span));
+        Rc::new(Closure::new(lambda, vec![function, argument]))
+    }
+
+    /// Forces evaluation of a thunk, returning its value.
+    ///
+    /// The thunk is evaluated at most once, the result is stored in it. If
+    /// evaluation fails, the thunk is reset so that it can be forced again.
+    ///
+    /// Forcing a thunk that is currently being evaluated results in an
+    /// `InfiniteRecursion` error.
+    pub fn force(&mut self, thunk: &Thunk<'a>) -> Result<Value<'a>, Error> {
+        if let Some(value) = thunk.value() {
+            return Ok(value);
+        }
+
+        let (lambda, captures) = thunk
+            .begin_evaluation()
+            .map_err(|lambda| Error::InfiniteRecursion { span: lambda.span })?;
+        let mut frame = Frame::enter(lambda, &captures);
+        match self.eval_expr(lambda.body, &mut frame) {
+            Ok(value) => {
+                thunk.set_value(value.clone());
+                Ok(value)
+            }
+            Err(e) => {
+                thunk.abort_evaluation(captures);
+                Err(e)
+            }
+        }
+    }
+
+    /// Evaluates a binary operation.
+    fn eval_binop(
+        &mut self,
+        op: BinOp,
+        left: &'a Expr<'a>,
+        right: &'a Expr<'a>,
+        frame: &mut Frame<'a>,
+        span: Span,
+    ) -> Result<Value<'a>, Error> {
+        // The logical operators only evaluate their right operand if needed
+        match op {
+            BinOp::And => {
+                let value = self.eval_bool(left, frame, span)? && self.eval_bool(right, frame, span)?;
+                return Ok(Value::Bool(value));
+            }
+            BinOp::Or => {
+                let value = self.eval_bool(left, frame, span)? || self.eval_bool(right, frame, span)?;
+                return Ok(Value::Bool(value));
+            }
+            BinOp::Implication => {
+                let value = !self.eval_bool(left, frame, span)? || self.eval_bool(right, frame, span)?;
+                return Ok(Value::Bool(value));
+            }
+            _ => {}
+        }
+
+        let left = self.eval_expr(left, frame)?;
+        let right = self.eval_expr(right, frame)?;
+        let invalid = Error::InvalidOperands {
+            op,
+            left: left.type_(),
+            right: right.type_(),
+            span,
+        };
+
+        match (op, left, right) {
+            (BinOp::Equal, left, right) => Ok(Value::Bool(self.equal(left, right)?)),
+            (BinOp::NotEqual, left, right) => Ok(Value::Bool(!self.equal(left, right)?)),
+            (BinOp::Less, left, right) => {
+                let ordering = self.compare(left, right, op, span)?;
+                Ok(Value::Bool(ordering == Some(Ordering::Less)))
+            }
+            (BinOp::LessOrEq, left, right) => {
+                let ordering = self.compare(left, right, op, span)?;
+                Ok(Value::Bool(ordering.map_or(false, |o| o != Ordering::Greater)))
+            }
+            (BinOp::More, left, right) => {
+                let ordering = self.compare(left, right, op, span)?;
+                Ok(Value::Bool(ordering == Some(Ordering::Greater)))
+            }
+            (BinOp::MoreOrEq, left, right) => {
+                let ordering = self.compare(left, right, op, span)?;
+                Ok(Value::Bool(ordering.map_or(false, |o| o != Ordering::Less)))
+            }
+            (BinOp::Add, Value::String(mut left), right) => {
+                left.push_bytes(&self.coerce_to_string(right, span)?);
+                Ok(Value::String(left))
+            }
+            (BinOp::Add, Value::Path(left), right) => {
+                let right = self.coerce_to_string(right, span)?;
+                Ok(Value::Path(concat_path(&left, &right.to_string_lossy())))
+            }
+            (BinOp::Concat, Value::List(left), Value::List(right)) => {
+                let mut list = Vec::with_capacity(left.len() + right.len());
+                list.extend(left.iter().cloned());
+                list.extend(right.iter().cloned());
+                Ok(Value::List(Rc::new(list)))
+            }
+            (BinOp::Update, Value::Set(left), Value::Set(right)) => {
+                Ok(Value::Set(Rc::new(left.update(&right))))
+            }
+            (BinOp::Add, Value::Int(left), Value::Int(right)) => Ok(Value::Int(left.wrapping_add(right))),
+            (BinOp::Sub, Value::Int(left), Value::Int(right)) => Ok(Value::Int(left.wrapping_sub(right))),
+            (BinOp::Mul, Value::Int(left), Value::Int(right)) => Ok(Value::Int(left.wrapping_mul(right))),
+            (BinOp::Div, Value::Int(_), Value::Int(0)) => Err(Error::DivisionByZero { span }),
+            (BinOp::Div, Value::Int(left), Value::Int(right)) => Ok(Value::Int(left.wrapping_div(right))),
+            (op, left, right) => {
+                // Mixing integers and floats results in a float
+                let (left, right) = match (left.as_number(), right.as_number()) {
+                    (Some(left), Some(right)) => (left, right),
+                    _ => return Err(invalid),
+                };
+                match op {
+                    BinOp::Add => Ok(Value::Float(left + right)),
+                    BinOp::Sub => Ok(Value::Float(left - right)),
+                    BinOp::Mul => Ok(Value::Float(left * right)),
+                    BinOp::Div if right == 0.0 => Err(Error::DivisionByZero { span }),
+                    BinOp::Div => Ok(Value::Float(left / right)),
+                    _ => Err(invalid),
+                }
+            }
+        }
+    }
+
+    /// Compares two values for structural equality.
+    ///
+    /// Elements of lists and sets are forced as needed. Functions are never
+    /// equal to anything.
+    pub(crate) fn equal(&mut self, left: Value<'a>, right: Value<'a>) -> Result<bool, Error> {
+        Ok(match (left, right) {
+            (Value::String(left), Value::String(right)) => left == right,
+            (Value::Int(left), Value::Int(right)) => left == right,
+            (Value::Path(left), Value::Path(right)) => left == right,
+            (Value::Bool(left), Value::Bool(right)) => left == right,
+            (Value::Null, Value::Null) => true,
+            (Value::List(left), Value::List(right)) => {
+                if left.len() != right.len() {
+                    return Ok(false);
+                }
+                for (left, right) in left.iter().zip(right.iter()) {
+                    let (left, right) = (self.force(left)?, self.force(right)?);
+                    if !self.equal(left, right)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            (Value::Set(left), Value::Set(right)) => {
+                if left.len() != right.len() || !left.keys().eq(right.keys()) {
+                    return Ok(false);
+                }
+                for (left, right) in left.values().zip(right.values()) {
+                    let (left, right) = (self.force(left)?, self.force(right)?);
+                    if !self.equal(left, right)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            (left, right) => match (left.as_number(), right.as_number()) {
+                (Some(left), Some(right)) => left == right,
+                _ => false,
+            },
+        })
+    }
+
+    /// Determines the ordering of two values for the comparison operator
+    /// `op`.
+    ///
+    /// Returns `None` if the values are unordered (this happens with `NaN`).
+    fn compare(
+        &mut self,
+        left: Value<'a>,
+        right: Value<'a>,
+        op: BinOp,
+        span: Span,
+    ) -> Result<Option<Ordering>, Error> {
+        match (left, right) {
+            (Value::Int(left), Value::Int(right)) => Ok(Some(left.cmp(&right))),
+            (Value::String(left), Value::String(right)) => Ok(Some((*left).cmp(&*right))),
+            (Value::Path(left), Value::Path(right)) => {
+                Ok(Some(left.as_os_str().cmp(right.as_os_str())))
+            }
+            (Value::List(left), Value::List(right)) => {
+                for (left, right) in left.iter().zip(right.iter()) {
+                    let (left, right) = (self.force(left)?, self.force(right)?);
+                    match self.compare(left, right, op, span)? {
+                        Some(Ordering::Equal) => {}
+                        ordering => return Ok(ordering),
+                    }
+                }
+                Ok(Some(left.len().cmp(&right.len())))
+            }
+            (left, right) => match (left.as_number(), right.as_number()) {
+                (Some(left), Some(right)) => Ok(left.partial_cmp(&right)),
+                _ => Err(Error::InvalidOperands {
+                    op,
+                    left: left.type_(),
+                    right: right.type_(),
+                    span,
+                }),
+            },
+        }
+    }
+
+    /// Evaluates the name of an attribute in an attribute path.
+    fn attr_name(
+        &mut self,
+        part: AttrPart<'a>,
+        frame: &mut Frame<'a>,
+        span: Span,
+    ) -> Result<Cow<'a, str>, Error> {
+        match part {
+            AttrPart::Name(name) => Ok(Cow::Borrowed(name)),
+            AttrPart::Dynamic(expr) => match self.eval_expr(expr, frame)? {
+                Value::String(name) => Ok(Cow::Owned(attr_name_string(name, span)?)),
+                other => Err(Error::TypeMismatch {
+                    expected: Type::String,
+                    found: other.type_(),
+                    span,
+                }),
+            },
+        }
+    }
+
+    /// Converts a value to a string like string interpolation does.
+    ///
+    /// Sets can be converted if they have a `__toString` function or an
+    /// `outPath` attribute. Paths are converted to their absolute path (there
+    /// is no store they could be copied to).
+    pub(crate) fn coerce_to_string(&mut self, value: Value<'a>, span: Span) -> Result<NixString, Error> {
+        match value {
+            Value::String(string) => Ok(string),
+            Value::Path(path) => Ok(path.to_string_lossy().as_ref().into()),
+            Value::Set(ref set) if set.contains_key("__toString") => {
+                let function = self.force(&set["__toString"])?;
+                let set = Rc::new(Closure::evaluated(value.clone()));
+                let string = self.call(function, set, span)?;
+                self.coerce_to_string(string, span)
+            }
+            Value::Set(ref set) if set.contains_key("outPath") => {
+                let path = self.force(&set["outPath"])?;
+                self.coerce_to_string(path, span)
+            }
+            other => Err(Error::CannotCoerce {
+                from: other.type_(),
+                span,
+            }),
+        }
+    }
+
+    /// Calls `function` with `argument`.
+    ///
+    /// Besides functions, sets with a `__functor` attribute can also be
+    /// called. The functor is called with the set itself and the argument.
+    pub(crate) fn call(&mut self, function: Value<'a>, argument: Thunk<'a>, span: Span) -> Result<Value<'a>, Error> {
+        match function {
+            Value::Lambda(closure) => {
+                let (lambda, captures) = closure
+                    .code()
+                    .expect("function closure has been evaluated");
+                let mut frame = Frame::enter(lambda, &captures);
+                match lambda.param {
+                    Some(LambdaParameter::Ident(var)) => {
+                        frame.vars.insert(var, argument);
+                    }
+                    Some(LambdaParameter::Pattern(pattern)) => {
+                        self.bind_pattern(&pattern, argument, &mut frame, lambda.span, span)?;
+                    }
+                    // Transparent lambdas are evaluated when they're
+                    // instantiated, so they never become `Value::Lambda`s
+                    None => unreachable!("transparent lambda used as function"),
+                }
+
+                self.eval_expr(lambda.body, &mut frame)
+            }
+            Value::Builtin { primop, mut args } => {
+                args.push(argument);
+                if args.len() == primop.arity() {
+                    primop.call(self, &args, span)
+                } else {
+                    Ok(Value::Builtin { primop, args })
+                }
+            }
+            Value::Set(ref set) if set.contains_key("__functor") => {
+                let functor = self.force(&set["__functor"])?;
+                let set = Rc::new(Closure::evaluated(function.clone()));
+                let function = self.call(functor, set, span)?;
+                self.call(function, argument, span)
+            }
+            other => Err(Error::TypeMismatch {
+                expected: Type::Lambda,
+                found: other.type_(),
+                span,
+            }),
+        }
+    }
+
+    /// Binds the variables of a `Pattern` to the attributes of the argument
+    /// set.
+    ///
+    /// `lambda_span` is the span of the called lambda, while `span` is the
+    /// span of the call.
+    fn bind_pattern(
+        &mut self,
+        pattern: &Pattern<'a>,
+        argument: Thunk<'a>,
+        frame: &mut Frame<'a>,
+        lambda_span: Span,
+        span: Span,
+    ) -> Result<(), Error> {
+        let set = match self.force(&argument)? {
+            Value::Set(set) => set,
+            other => {
+                return Err(Error::TypeMismatch {
+                    expected: Type::Set,
+                    found: other.type_(),
+                    span,
+                });
+            }
+        };
+
+        let mut pending = Vec::new();
+        for entry in pattern.entries {
+            let name = self.variables[entry.var].name;
+            match (set.get(name), entry.default) {
+                (Some(value), _) => {
+                    frame.vars.insert(entry.var, value.clone());
+                }
+                (None, Some(default)) => frame.bind_recursive(entry.var, default, &mut pending),
+                (None, None) => {
+                    return Err(Error::MissingArgument {
+                        name: name.to_string(),
+                        span,
+                        lambda_span,
+                    });
+                }
+            }
+        }
+        frame.finish_bindings(pending);
+
+        if !pattern.ellipsis {
+            let unexpected = set.keys().find(|name| {
+                !pattern
+                    .entries
+                    .iter()
+                    .any(|entry| self.variables[entry.var].name == name.as_str())
+            });
+            if let Some(name) = unexpected {
+                return Err(Error::UnexpectedArgument {
+                    name: name.clone(),
+                    span,
+                    lambda_span,
+                });
+            }
+        }
+
+        if let Some(var) = pattern.bind {
+            frame.vars.insert(var, argument);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the thunk bound to a variable.
+    fn variable(&mut self, var: Variable, frame: &Frame<'a>) -> Thunk<'a> {
+        if let Some(thunk) = frame.vars.get(&var) {
+            return thunk.clone();
+        }
+
+        // Globals aren't captured, since they don't depend on any frame
+        if let Some(thunk) = self.globals.get(&var) {
+            return thunk.clone();
+        }
+        let expr = self.variables[var]
+            .expr
+            .expect("variable is neither in scope nor a global");
+        let thunk = self.delay(expr, &Frame::new());
+        self.globals.insert(var, thunk.clone());
+        thunk
+    }
+
+    /// Prints an evaluation error pointing into the source code, returning
+    /// `Error::AlreadyPrinted`.
+    ///
+    /// Errors that don't point into the source code are returned unchanged.
+    pub(crate) fn report(&mut self, error: Error) -> Error {
+        match error.to_diagnostic() {
+            Some(diag) => {
+                ::utils::DiagnosticEmitter::emit_diagnostics(self, &[diag]);
+                Error::AlreadyPrinted
+            }
+            None => error,
+        }
+    }
+}
+
+/// The runtime variable bindings of a lambda (or file) body that is being
+/// evaluated.
+///
+/// A frame contains the variables captured by the lambda, its parameters, and
+/// all variables bound by `let` expressions inside the body.
+struct Frame<'a> {
+    vars: HashMap<Variable, Thunk<'a>>,
+}
+
+impl<'a> Frame<'a> {
+    fn new() -> Self {
+        Self {
+            vars: HashMap::new(),
+        }
+    }
+
+    /// Creates the frame for evaluating the body of `lambda`, given the
+    /// variables captured by its closure.
+    fn enter(lambda: &Lambda<'a>, captures: &[Thunk<'a>]) -> Self {
+        Self {
+            vars: lambda
+                .captures
+                .iter()
+                .cloned()
+                .zip(captures.iter().cloned())
+                .collect(),
+        }
+    }
+
+    /// Binds `var` to a thunk evaluating `expr`, which may refer to other
+    /// variables bound at the same time (or to `var` itself).
+    ///
+    /// Since the closures must exist before they can be captured, their
+    /// captures are collected by `finish_bindings` once all variables of the
+    /// group are bound.
+    ///
+    /// This creates reference cycles between the closures. Forcing a closure
+    /// drops its captures, but unforced closures and values referring back to
+    /// their own binding (like recursive functions) are never freed.
+    fn bind_recursive(
+        &mut self,
+        var: Variable,
+        expr: &'a Expr<'a>,
+        pending: &mut Vec<(Thunk<'a>, &'a Lambda<'a>)>,
+    ) {
+        let thunk = match expr {
+            Expr::Value(value) => Rc::new(Closure::evaluated((*value).clone())),
+            Expr::Lambda(lambda) => {
+                let thunk = Rc::new(Closure::new(lambda, Vec::new()));
+                pending.push((thunk.clone(), lambda));
+                thunk
+            }
+            // `Builder::translate_thunk` wraps every binding that isn't a
+            // literal in a transparent lambda
+            _ => unreachable!("binding is not a thunk: {:?}", expr),
+        };
+        self.vars.insert(var, thunk);
+    }
+
+    /// Fills in the captures of the closures created by `bind_recursive`.
+    fn finish_bindings(&self, pending: Vec<(Thunk<'a>, &'a Lambda<'a>)>) {
+        for (thunk, lambda) in pending {
+            thunk.set_captures(self.capture(lambda));
+        }
+    }
+
+    /// Collects the variables captured by `lambda` when it is instantiated in
+    /// this frame.
+    fn capture(&self, lambda: &Lambda<'a>) -> Vec<Thunk<'a>> {
+        lambda
+            .captures
+            .iter()
+            .map(|var| {
+                self.vars
+                    .get(var)
+                    .expect("captured variable not in scope")
+                    .clone()
+            })
+            .collect()
+    }
+}
+
+/// Appends `suffix` to `path`, like adding a string to a path does.
+///
+/// The result is normalized, so `/a/b + "/../c"` results in `/a/c`.
+fn concat_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut joined = path.as_os_str().to_owned();
+    joined.push(suffix);
+
+    let mut result = PathBuf::new();
+    for component in Path::new(&joined).components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            component => result.push(component.as_os_str()),
         }
     }
+    result
+}
+
+/// Splits an attribute path like `a."b.c".0` into its components, along with
+/// their start and end offsets.
+fn split_attr_path(path: &str) -> Vec<(String, usize, usize)> {
+    let mut components = Vec::new();
+    let mut name = String::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in path.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => {
+                components.push((name.split_off(0), start, i));
+                start = i + 1;
+            }
+            c => name.push(c),
+        }
+    }
+    if !path.is_empty() {
+        components.push((name, start, path.len()));
+    }
+    components
+}
+
+/// Converts a string to an attribute name, which has to be valid UTF-8.
+pub(crate) fn attr_name_string(name: NixString, span: Span) -> Result<String, Error> {
+    name.into_string().map_err(|name| Error::InvalidUtf8 {
+        string: name.to_string(),
+        span: Some(span),
+    })
+}
+
+/// Replaces the variable called `name`, or appends it if there is none.
+fn replace_or_push<N: PartialEq>(vars: &mut Vec<(N, Variable)>, name: N, var: Variable) {
+    match vars.iter_mut().find(|(existing, _)| *existing == name) {
+        Some(entry) => entry.1 = var,
+        None => vars.push((name, var)),
+    }
 }
 
 impl<'a> ::utils::DiagnosticEmitter for EvalContext<'a> {
@@ -106,10 +1407,230 @@ pub enum Error {
     #[fail(display = "i/o error: {}", _0)]
     Io(#[fail(cause)] io::Error),
 
+    #[fail(display = "value is {} while {} was expected", found, expected)]
+    TypeMismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+    },
+
+    #[fail(display = "assertion failed")]
+    AssertionFailed { span: Span },
+
+    #[fail(display = "infinite recursion encountered")]
+    InfiniteRecursion { span: Span },
+
+    #[fail(display = "function called without required argument `{}`", name)]
+    MissingArgument {
+        name: String,
+        span: Span,
+        lambda_span: Span,
+    },
+
+    #[fail(display = "function called with unexpected argument `{}`", name)]
+    UnexpectedArgument {
+        name: String,
+        span: Span,
+        lambda_span: Span,
+    },
+
+    #[fail(display = "cannot apply `{}` to {} and {}", op, left, right)]
+    InvalidOperands {
+        op: BinOp,
+        left: Type,
+        right: Type,
+        span: Span,
+    },
+
+    #[fail(display = "division by zero")]
+    DivisionByZero { span: Span },
+
+    #[fail(display = "cannot import `{}`: {}", path, error)]
+    ImportFailed {
+        path: String,
+        #[fail(cause)]
+        error: io::Error,
+        span: Span,
+    },
+
+    #[fail(display = "string `{}` is not an absolute path", path)]
+    NotAnAbsolutePath { path: String, span: Span },
+
+    #[fail(display = "file `{}` was not found in the Nix search path", name)]
+    NotInSearchPath {
+        name: String,
+        /// The search path entries that were tried.
+        searched: Vec<String>,
+        span: Span,
+    },
+
+    #[fail(display = "cannot coerce {} to a string", from)]
+    CannotCoerce { from: Type, span: Span },
+
+    #[fail(display = "cannot convert {} to {}", from, to)]
+    CannotConvert {
+        from: Type,
+        to: &'static str,
+        span: Span,
+    },
+
+    #[fail(display = "cannot convert the primop `{}` to {}", name, to)]
+    CannotConvertPrimOp { name: String, to: &'static str },
+
+    #[fail(display = "cannot copy `{}` to the store, there is no store", path)]
+    CannotCopyToStore { path: String },
+
+    #[fail(display = "string `{}` is not valid UTF-8", string)]
+    InvalidUtf8 {
+        string: String,
+        /// `None` if the string was converted while printing a value.
+        span: Option<Span>,
+    },
+
+    #[fail(display = "attribute `{}` missing", name)]
+    MissingAttribute {
+        name: String,
+        /// Names of existing attributes that are similar to `name`.
+        suggestions: Vec<String>,
+        span: Span,
+    },
+
+    #[fail(display = "list index {} is out of bounds for a list of length {}", index, len)]
+    IndexOutOfBounds { index: i64, len: usize, span: Span },
+
+    #[fail(display = "`{}` called on an empty list", function)]
+    EmptyList { function: &'static str, span: Span },
+
+    #[fail(display = "cannot create a list of negative length {}", length)]
+    NegativeLength { length: i64, span: Span },
+
+    #[fail(display = "negative start position {} in `substring`", start)]
+    NegativeStart { start: i64, span: Span },
+
+    #[fail(
+        display = "`replaceStrings` called with {} strings to replace but {} replacements",
+        from,
+        to
+    )]
+    ReplacementCount { from: usize, to: usize, span: Span },
+
+    #[fail(display = "invalid regular expression `{}`: {}", pattern, message)]
+    InvalidRegex {
+        pattern: String,
+        message: String,
+        span: Span,
+    },
+
+    #[fail(
+        display = "cannot auto-call a function that has an argument without a default value (`{}`)",
+        name
+    )]
+    CannotAutoCall { name: String, span: Span },
+
+    #[fail(display = "undefined variable `{}`", name)]
+    UndefinedVariable { name: String, span: Span },
+
+    #[fail(display = "{}", message)]
+    Thrown { message: String, span: Span },
+
+    #[fail(display = "evaluation aborted with the following error message: '{}'", message)]
+    Aborted { message: String, span: Span },
+
+    #[fail(display = "dynamic attribute `{}` already defined", name)]
+    DuplicateAttribute {
+        name: String,
+        span: Span,
+        first: Span,
+    },
+
     #[fail(display = "(this should not be printed)")]
     AlreadyPrinted,
 }
 
+impl Error {
+    /// Returns the span in the source code this error points to.
+    fn span(&self) -> Option<Span> {
+        match self {
+            Error::TypeMismatch { span, .. }
+            | Error::AssertionFailed { span }
+            | Error::InfiniteRecursion { span }
+            | Error::MissingArgument { span, .. }
+            | Error::UnexpectedArgument { span, .. }
+            | Error::InvalidOperands { span, .. }
+            | Error::DivisionByZero { span }
+            | Error::ImportFailed { span, .. }
+            | Error::NotAnAbsolutePath { span, .. }
+            | Error::NotInSearchPath { span, .. }
+            | Error::CannotCoerce { span, .. }
+            | Error::CannotConvert { span, .. }
+            | Error::MissingAttribute { span, .. }
+            | Error::UndefinedVariable { span, .. }
+            | Error::IndexOutOfBounds { span, .. }
+            | Error::EmptyList { span, .. }
+            | Error::NegativeLength { span, .. }
+            | Error::NegativeStart { span, .. }
+            | Error::ReplacementCount { span, .. }
+            | Error::InvalidRegex { span, .. }
+            | Error::CannotAutoCall { span, .. }
+            | Error::Thrown { span, .. }
+            | Error::Aborted { span, .. }
+            | Error::DuplicateAttribute { span, .. } => Some(*span),
+            Error::InvalidUtf8 { span, .. } => *span,
+            Error::Io(_)
+            | Error::CannotConvertPrimOp { .. }
+            | Error::CannotCopyToStore { .. }
+            | Error::AlreadyPrinted => None,
+        }
+    }
+
+    /// Converts this error to a diagnostic, if it points into the source
+    /// code.
+    fn to_diagnostic(&self) -> Option<Diagnostic> {
+        let label = match self {
+            Error::MissingAttribute { suggestions, .. } if !suggestions.is_empty() => {
+                let names = suggestions
+                    .iter()
+                    .map(|name| format!("`{}`", name))
+                    .collect::<Vec<_>>();
+                Some(format!("did you mean {}?", names.join(" or ")))
+            }
+            Error::NotInSearchPath { searched, .. } => Some(if searched.is_empty() {
+                "the search path is empty (set `NIX_PATH` or pass `-I`)".to_string()
+            } else {
+                format!("searched {}", searched.join(", "))
+            }),
+            _ => None,
+        };
+        let mut spans = vec![SpanLabel {
+            span: self.span()?,
+            label,
+            style: SpanStyle::Primary,
+        }];
+
+        match self {
+            Error::MissingArgument { lambda_span, .. }
+            | Error::UnexpectedArgument { lambda_span, .. } => spans.push(SpanLabel {
+                span: *lambda_span,
+                label: Some("function defined here".to_string()),
+                style: SpanStyle::Secondary,
+            }),
+            Error::DuplicateAttribute { first, .. } => spans.push(SpanLabel {
+                span: *first,
+                label: Some("first defined here".to_string()),
+                style: SpanStyle::Secondary,
+            }),
+            _ => {}
+        }
+
+        Some(Diagnostic {
+            level: Level::Error,
+            message: self.to_string(),
+            code: None,
+            spans,
+        })
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::Io(e)
@@ -0,0 +1,82 @@
+//! An evaluator for the Nix expression language.
+//!
+//! Expressions are evaluated by an `EvalContext`, which owns everything that
+//! is shared between the evaluated files (like the source code and the
+//! variables declared in them). The ASTs of the evaluated files are allocated
+//! in `Arenas` that have to outlive the context.
+//!
+//! Evaluated values can be converted to Rust types using `FromValue`, or
+//! deserialized into any type implementing `serde::Deserialize` using
+//! `from_value`.
+//!
+//! # Example
+//!
+//! ```
+//! extern crate nxt;
+//!
+//! use nxt::{Arenas, Config, EvalContext, Source, Value};
+//! use std::path::Path;
+//!
+//! # fn main() -> Result<(), nxt::Error> {
+//! let arenas = Arenas::new();
+//! let mut ctx = EvalContext::new(Config::default(), &arenas);
+//! let value = ctx.eval(Source::Other {
+//!     source: "let x = 20; in { answer = x * 2 + 2; }",
+//!     name: "<example>",
+//!     search_path: Path::new("."),
+//! })?;
+//!
+//! // Values are evaluated lazily, so attribute values have to be forced
+//! if let Value::Set(set) = value {
+//!     let answer = ctx.force(&set["answer"])?;
+//!     assert_eq!(answer.to_string(), "42");
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+#![doc(html_root_url = "https://docs.rs/nxt/0.1.0")]
+#![warn(missing_debug_implementations)]
+
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate failure;
+#[macro_use]
+extern crate serde;
+extern crate codemap;
+extern crate codemap_diagnostic;
+extern crate console;
+extern crate directories;
+extern crate hashbrown;
+extern crate num_traits;
+extern crate rnix;
+extern crate rowan;
+extern crate shawshank;
+extern crate tendril;
+extern crate toolshed;
+extern crate typed_arena;
+
+mod ast;
+mod builtins;
+mod config;
+mod convert;
+mod dump;
+mod eval;
+mod parser;
+mod print;
+mod profile;
+mod utils;
+mod value;
+
+pub use ast::Arenas;
+pub use builtins::PrimOp;
+pub use config::{parse_nix_path, Config, SearchPathEntry};
+pub use convert::{from_value, Deserializer, Error as ConversionError, FromValue, IntoValue};
+pub use dump::{SyntaxTree, TreeKind};
+pub use eval::{Error, EvalContext, Source};
+pub use parser::{is_incomplete, Error as ParseError};
+pub use print::{Format, Printer};
+pub use profile::enable as enable_profiling;
+pub use utils::{ColorConfig, ErrorAlreadyPrinted, InvalidColorConfig};
+pub use value::{Closure, NixString, Set, Thunk, Type, Value};
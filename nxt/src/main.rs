@@ -1,44 +1,29 @@
-#![doc(html_root_url = "https://docs.rs/nxt/0.1.0")]
-#![warn(missing_debug_implementations)]
-
 #[macro_use]
 extern crate log;
 #[macro_use]
 extern crate failure;
-extern crate codemap;
-extern crate codemap_diagnostic;
 extern crate console;
-extern crate directories;
 extern crate env_logger;
-extern crate hashbrown;
-extern crate num_traits;
-extern crate rnix;
-extern crate rowan;
-extern crate shawshank;
+extern crate nxt;
+extern crate rustyline;
 extern crate structopt;
-extern crate tendril;
-extern crate toolshed;
-extern crate typed_arena;
-
-mod ast;
-mod config;
-mod eval;
-mod parser;
-mod profile;
-mod utils;
-mod value;
+
+mod repl;
 
 use failure::Error;
 use log::LevelFilter;
 use structopt::StructOpt;
 
-use config::Config;
-use eval::EvalContext;
-use eval::Source;
-use std::cmp;
-use std::env;
+use console::Term;
+use nxt::{
+    Arenas, ColorConfig, Config, EvalContext, Format, IntoValue, Printer, SearchPathEntry,
+    Source, TreeKind,
+};
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use ast::Arenas;
+use std::{cmp, env};
 
 #[derive(StructOpt)]
 #[structopt(about = "A Nix expression evaluator")]
@@ -72,7 +57,17 @@ struct Opts {
 
     /// When to use colored console output (always, never, or auto).
     #[structopt(long = "color", default_value = "auto")]
-    color: utils::ColorConfig,
+    color: ColorConfig,
+
+    /// Add an entry (`path` or `prefix=path`) to the front of the Nix search
+    /// path used for `<name>` paths.
+    #[structopt(short = "I", number_of_values = 1, parse(from_str))]
+    include: Vec<SearchPathEntry>,
+
+    /// Report all syntax errors in a file instead of only the most relevant
+    /// one.
+    #[structopt(long = "all-syntax-errors")]
+    all_syntax_errors: bool,
 
     #[structopt(flatten)]
     cmd: Subcommand,
@@ -83,9 +78,125 @@ enum Subcommand {
     #[structopt(name = "eval")]
     #[structopt(about = "Evaluate a Nix expression")]
     Eval {
-        /// The expression to evaluate.
-        expr: String,
+        /// Evaluate nested values too, instead of printing them as `«thunk»`.
+        #[structopt(long = "strict")]
+        strict: bool,
+
+        /// Print the result as JSON (like `builtins.toJSON`).
+        #[structopt(long = "json", conflicts_with = "xml")]
+        json: bool,
+
+        /// Print the result as XML (like `nix-instantiate --eval --xml`).
+        #[structopt(long = "xml")]
+        xml: bool,
+
+        /// Print paths in JSON output as they are, instead of failing because
+        /// they cannot be copied to the store.
+        #[structopt(long = "verbatim-paths", requires = "json")]
+        verbatim_paths: bool,
+
+        /// Evaluate the Nix file at this path (`-` for standard input).
+        #[structopt(
+            short = "f",
+            long = "file",
+            parse(from_os_str),
+            conflicts_with = "expr"
+        )]
+        file: Option<PathBuf>,
+
+        /// Select an attribute from the result (eg. `foo.bar.baz`).
+        #[structopt(short = "A", long = "attr")]
+        attr: Option<String>,
+
+        /// Pass the value of a Nix expression as an argument to functions that
+        /// are called automatically.
+        #[structopt(
+            long = "arg",
+            number_of_values = 2,
+            raw(value_names = r#"&["NAME", "EXPR"]"#)
+        )]
+        arg: Vec<String>,
+
+        /// Pass a string as an argument to functions that are called
+        /// automatically.
+        #[structopt(
+            long = "argstr",
+            number_of_values = 2,
+            raw(value_names = r#"&["NAME", "VALUE"]"#)
+        )]
+        argstr: Vec<String>,
+
+        /// The expression to evaluate (`-` to read it from standard input).
+        #[structopt(required_unless = "file")]
+        expr: Option<String>,
     },
+
+    #[structopt(name = "parse")]
+    #[structopt(about = "Print the syntax tree of a Nix expression")]
+    Parse {
+        /// Print the parse tree produced by rnix instead of the desugared AST
+        /// that is evaluated.
+        #[structopt(long = "raw")]
+        raw: bool,
+
+        /// Print the tree as JSON.
+        #[structopt(long = "json")]
+        json: bool,
+
+        /// Parse the Nix file at this path (`-` for standard input).
+        #[structopt(
+            short = "f",
+            long = "file",
+            parse(from_os_str),
+            conflicts_with = "expr"
+        )]
+        file: Option<PathBuf>,
+
+        /// The expression to parse (`-` to read it from standard input).
+        #[structopt(required_unless = "file")]
+        expr: Option<String>,
+    },
+
+    #[structopt(name = "repl")]
+    #[structopt(about = "Start an interactive Nix REPL")]
+    Repl,
+}
+
+fn read_stdin() -> Result<String, Error> {
+    let mut source = String::new();
+    io::stdin().read_to_string(&mut source)?;
+    Ok(source)
+}
+
+/// Calls `f` with the Nix expression given by the `--file` option or the
+/// `expr` argument.
+fn with_source<T, F>(file: Option<PathBuf>, expr: Option<String>, f: F) -> Result<T, Error>
+where
+    F: FnOnce(Source) -> Result<T, nxt::Error>,
+{
+    let current_dir = env::current_dir()?;
+    let result = match (file, expr) {
+        (Some(ref path), _) if path != Path::new("-") => f(Source::File { path })?,
+        (Some(_), _) => f(Source::Other {
+            source: &read_stdin()?,
+            name: "<stdin>",
+            search_path: &current_dir,
+        })?,
+        (None, Some(expr)) => {
+            let (source, name) = if expr == "-" {
+                (read_stdin()?, "<stdin>")
+            } else {
+                (expr, "<cmdline>")
+            };
+            f(Source::Other {
+                source: &source,
+                name,
+                search_path: &current_dir,
+            })?
+        }
+        (None, None) => unreachable!("an expression is required without `--file`"),
+    };
+    Ok(result)
 }
 
 fn run(opts: Opts) -> Result<(), Error> {
@@ -115,25 +226,94 @@ fn run(opts: Opts) -> Result<(), Error> {
     debug!("logging enabled at {:?} level", filter);
 
     if opts.profile {
-        profile::enable();
+        nxt::enable_profiling();
     }
 
-    let config = Config { color: opts.color };
+    let mut nix_path = opts.include;
+    if let Ok(var) = env::var("NIX_PATH") {
+        nix_path.extend(nxt::parse_nix_path(&var));
+    }
+    let config = Config {
+        color: opts.color,
+        nix_path,
+        all_syntax_errors: opts.all_syntax_errors,
+    };
 
     match opts.cmd {
-        Subcommand::Eval { expr } => {
+        Subcommand::Eval {
+            strict,
+            json,
+            xml,
+            verbatim_paths,
+            file,
+            attr,
+            arg,
+            argstr,
+            expr,
+        } => {
+            let arenas = Arenas::new();
+            let mut eval = EvalContext::new(config, &arenas);
+            let current_dir = env::current_dir()?;
+
+            let mut args = BTreeMap::new();
+            for pair in arg.chunks(2) {
+                let value = eval.eval(Source::Other {
+                    source: &pair[1],
+                    name: &format!("<--arg {}>", pair[0]),
+                    search_path: &current_dir,
+                })?;
+                args.insert(pair[0].clone(), value.into_thunk());
+            }
+            for pair in argstr.chunks(2) {
+                args.insert(pair[0].clone(), pair[1].as_str().into_thunk());
+            }
+
+            let value = with_source(file, expr, |source| eval.eval(source))?;
+            let attr = attr.as_ref().map_or("", |attr| &**attr);
+            let value = eval.select_attr_path(value, attr, &args)?;
+
+            let format = if json {
+                Format::Json
+            } else if xml {
+                Format::Xml
+            } else {
+                Format::Nix
+            };
+            let (_, width) = Term::stdout().size();
+            let printer = Printer::new()
+                .format(format)
+                .strict(strict)
+                .width(width as usize)
+                .verbatim_paths(verbatim_paths);
+            let output = printer.print(&mut eval, &value)?;
+            println!("{}", output.trim_end());
+
+            Ok(())
+        }
+        Subcommand::Parse {
+            raw,
+            json,
+            file,
+            expr,
+        } => {
             let arenas = Arenas::new();
             let mut eval = EvalContext::new(config, &arenas);
-            let value = eval.eval(Source::Other {
-                source: &expr,
-                name: "<cmdline>",
-                search_path: &env::current_dir()?,
-            })?;
+            let kind = if raw {
+                TreeKind::Raw
+            } else {
+                TreeKind::Lowered
+            };
 
-            println!("{}", value);
+            let tree = with_source(file, expr, |source| eval.syntax_tree(source, kind))?;
+            if json {
+                println!("{}", tree.to_json());
+            } else {
+                print!("{}", tree);
+            }
 
             Ok(())
         }
+        Subcommand::Repl => repl::run(config),
     }
 }
 
@@ -143,7 +323,12 @@ fn main() {
     match run(opts) {
         Ok(()) => {}
         Err(e) => {
-            if e.downcast_ref::<utils::ErrorAlreadyPrinted>().is_none() {
+            let already_printed = e.downcast_ref::<nxt::ErrorAlreadyPrinted>().is_some()
+                || match e.downcast_ref::<nxt::Error>() {
+                    Some(nxt::Error::AlreadyPrinted) => true,
+                    _ => false,
+                };
+            if !already_printed {
                 eprintln!("error: {}", e);
             }
             exit(1);
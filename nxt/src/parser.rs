@@ -4,6 +4,7 @@
 //! useful helpers to deal with parse error extraction and rendering.
 
 use profile::profile;
+use utils::IntoDiagnostics;
 
 use codemap::{File, Span, SpanLoc};
 use codemap_diagnostic::{Diagnostic, Level, SpanLabel, SpanStyle};
@@ -11,6 +12,7 @@ use rnix::parser::{NodeType, ParseError, Types};
 use rnix::tokenizer::Token;
 use rnix::types::*;
 use rowan::{OwnedRoot, TreeRoot};
+use std::cmp::Reverse;
 use std::fmt;
 use std::sync::Arc;
 
@@ -20,6 +22,8 @@ pub struct Error {
     span: Span,
     span_loc: SpanLoc,
     message: String,
+    /// Additional spans to highlight, along with a label.
+    notes: Vec<(Span, String)>,
 }
 
 impl Error {
@@ -36,12 +40,21 @@ impl Error {
         M: Into<String>,
         R: TreeRoot<Types>,
     {
-        let range = node.node().range();
-        let span = source.span.subspan(
-            range.start().to_usize() as u64,
-            range.end().to_usize() as u64,
-        );
+        let span = node_span(&source, node.node());
+        Self::at_span(source, span, message)
+    }
 
+    /// Creates an error pointing at a span inside a source file.
+    ///
+    /// # Parameters
+    ///
+    /// * `source`: Source file the error occurred in.
+    /// * `span`: The span to highlight. Must be inside of `source`.
+    /// * `message`: The error message.
+    pub fn at_span<M>(source: Arc<File>, span: Span, message: M) -> Self
+    where
+        M: Into<String>,
+    {
         Self {
             span_loc: SpanLoc {
                 begin: source.find_line_col(span.low()),
@@ -50,21 +63,22 @@ impl Error {
             },
             span,
             message: message.into(),
+            notes: Vec::new(),
         }
     }
 
+    /// Adds a labeled span to highlight in addition to the main span.
+    pub fn with_note<M>(mut self, span: Span, label: M) -> Self
+    where
+        M: Into<String>,
+    {
+        self.notes.push((span, label.into()));
+        self
+    }
+
     fn from_inner(source: Arc<File>, error: ParseError) -> Self {
         let span = error_span(&source, &error);
-
-        Self {
-            span_loc: SpanLoc {
-                begin: source.find_line_col(span.low()),
-                end: source.find_line_col(span.high()),
-                file: source,
-            },
-            span,
-            message: error_fmt(&error),
-        }
+        Self::at_span(source, span, error_fmt(&error))
     }
 }
 
@@ -76,28 +90,92 @@ impl fmt::Display for Error {
 
 impl Into<Diagnostic> for Error {
     fn into(self) -> Diagnostic {
+        let mut spans = vec![SpanLabel {
+            span: self.span,
+            label: Some(self.message),
+            style: SpanStyle::Primary,
+        }];
+        spans.extend(self.notes.into_iter().map(|(span, label)| SpanLabel {
+            span,
+            label: Some(label),
+            style: SpanStyle::Secondary,
+        }));
+
         Diagnostic {
             level: Level::Error,
             message: format!("could not parse {}", self.span_loc.file.name()),
             code: None,
-            spans: vec![SpanLabel {
-                span: self.span,
-                label: Some(self.message),
-                style: SpanStyle::Primary,
-            }],
+            spans,
         }
     }
 }
 
-/// Takes a list of `ParseErrors` and tries to extract a human-readable parsing
-/// error.
+/// All syntax errors reported for a file.
+///
+/// Depending on the mode passed to `parse`, this contains either the single
+/// most relevant error, or all errors that aren't caused by other errors.
+#[derive(Debug)]
+pub struct Errors(Vec<Error>);
+
+impl Errors {
+    /// Returns an iterator over the errors, in source order.
+    pub fn iter(&self) -> impl Iterator<Item = &Error> {
+        self.0.iter()
+    }
+}
+
+impl From<Error> for Errors {
+    fn from(error: Error) -> Self {
+        Errors(vec![error])
+    }
+}
+
+impl fmt::Display for Errors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for error in &self.0 {
+            error.fmt(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoDiagnostics for Errors {
+    fn into_diagnostics(self) -> Vec<Diagnostic> {
+        self.0.into_iter().map(Into::into).collect()
+    }
+}
+
+/// Takes a list of `ParseErrors` and converts them to human-readable parsing
+/// errors.
+///
+/// If `all` is `false`, only the error that's most likely to be of value to
+/// the user is returned (see `best_error`). Otherwise, all independent errors
+/// are returned (see `independent_errors`).
+fn extract_errors(source: &Arc<File>, errors: Vec<ParseError>, all: bool) -> Result<(), Errors> {
+    if errors.is_empty() {
+        return Ok(());
+    }
+
+    trace!("{} errors:", errors.len());
+    for error in &errors {
+        trace!("{}", Error::from_inner(source.clone(), error.clone()));
+    }
+
+    if all {
+        Err(Errors(independent_errors(source, errors)))
+    } else {
+        Err(best_error(source, errors).into())
+    }
+}
+
+/// Extracts the most relevant error from a non-empty list of `ParseErrors`.
 ///
 /// `rnix` will most likely return a whole list of errors, many of which just
 /// say "unexpected end of file" even though that's not really accurate. That's
 /// why this code ranks errors based on their type and other available
 /// information, and then sorts the list of errors to obtain the error that's
 /// most likely to be of highest value to a user.
-fn extract_error(source: &Arc<File>, mut errors: Vec<ParseError>) -> Result<(), Error> {
+fn best_error(source: &Arc<File>, mut errors: Vec<ParseError>) -> Error {
     fn rank_error(e: &ParseError) -> u32 {
         match e {
             ParseError::Unexpected(node) => {
@@ -113,20 +191,47 @@ fn extract_error(source: &Arc<File>, mut errors: Vec<ParseError>) -> Result<(),
         }
     }
 
-    if errors.is_empty() {
-        return Ok(());
-    }
-
     errors.sort_by_key(rank_error); // (should be a stable sort)
 
-    trace!("{} errors:", errors.len());
-    for error in &errors {
-        trace!("{}", Error::from_inner(source.clone(), error.clone()));
-    }
-
     // Pick the best error, or the first "good" error
     let error = errors.pop().unwrap();
-    Err(Error::from_inner(source.clone(), error))
+    Error::from_inner(source.clone(), error)
+}
+
+/// Extracts all errors from a non-empty list of `ParseErrors` that aren't
+/// caused by another error.
+///
+/// `rnix` reports an "unexpected end of file" error for every construct that
+/// is still open when it gives up, even if the input doesn't actually end
+/// early. Those errors are only kept if there are no other errors, and then
+/// only the most informative one. Of the remaining errors, the ones pointing
+/// into (or at the same input as) an earlier error are dropped.
+fn independent_errors(source: &Arc<File>, errors: Vec<ParseError>) -> Vec<Error> {
+    let (unexpected, eof): (Vec<_>, Vec<_>) = errors.into_iter().partition(|error| match error {
+        ParseError::Unexpected(_) => true,
+        ParseError::UnexpectedEOF | ParseError::UnexpectedEOFWanted(_) => false,
+    });
+    if unexpected.is_empty() {
+        return vec![best_error(source, eof)];
+    }
+
+    let mut errors = unexpected
+        .into_iter()
+        .map(|error| Error::from_inner(source.clone(), error))
+        .collect::<Vec<_>>();
+    // Sort outer errors before the ones nested in them
+    errors.sort_by_key(|error| (error.span.low(), Reverse(error.span.high())));
+
+    let mut independent: Vec<Error> = Vec::with_capacity(errors.len());
+    for error in errors {
+        let nested = independent.last().map_or(false, |last| {
+            last.span.low() <= error.span.low() && error.span.high() <= last.span.high()
+        });
+        if !nested {
+            independent.push(error);
+        }
+    }
+    independent
 }
 
 fn error_fmt(error: &ParseError) -> String {
@@ -142,14 +247,7 @@ fn error_fmt(error: &ParseError) -> String {
 
 fn error_span(source: &File, error: &ParseError) -> Span {
     match error {
-        ParseError::Unexpected(node) => {
-            // convert range to span
-            let range = node.range();
-            source.span.subspan(
-                range.start().to_usize() as u64,
-                range.end().to_usize() as u64,
-            )
-        }
+        ParseError::Unexpected(node) => node_span(source, node),
         ParseError::UnexpectedEOFWanted(_) | ParseError::UnexpectedEOF => {
             // put the span at the end of the input
             source.span.subspan(source.span.len(), source.span.len())
@@ -157,19 +255,49 @@ fn error_span(source: &File, error: &ParseError) -> Span {
     }
 }
 
+/// Returns the span covered by a parse tree node.
+pub fn node_span<R: TreeRoot<Types>>(source: &File, node: &rnix::parser::Node<R>) -> Span {
+    let range = node.range();
+    source.span.subspan(
+        range.start().to_usize() as u64,
+        range.end().to_usize() as u64,
+    )
+}
+
 /// Parses a Nix expression.
-pub fn parse(file: &Arc<File>) -> Result<rnix::parser::Node<OwnedRoot<Types>>, Error> {
-    profile("parsing", file.name(), || parse_impl(file))
+///
+/// If `all_errors` is `true`, all independent syntax errors are returned
+/// instead of only the most relevant one.
+pub fn parse(
+    file: &Arc<File>,
+    all_errors: bool,
+) -> Result<rnix::parser::Node<OwnedRoot<Types>>, Errors> {
+    profile("parsing", file.name(), || parse_impl(file, all_errors))
 }
 
-fn parse_impl(expr: &Arc<File>) -> Result<rnix::parser::Node<OwnedRoot<Types>>, Error> {
+fn parse_impl(
+    expr: &Arc<File>,
+    all_errors: bool,
+) -> Result<rnix::parser::Node<OwnedRoot<Types>>, Errors> {
     let ast = rnix::parse(expr.source());
 
-    extract_error(expr, ast.errors())?;
+    extract_errors(expr, ast.errors(), all_errors)?;
 
     Ok(ast.into_node().first_child().unwrap())
 }
 
+/// Returns whether `source` is an incomplete Nix expression, which might
+/// become valid if more input is appended.
+///
+/// This is the case when all parse errors are about the input ending early.
+pub fn is_incomplete(source: &str) -> bool {
+    let errors = rnix::parse(source).errors();
+    !errors.is_empty() && errors.iter().all(|error| match error {
+        ParseError::UnexpectedEOF | ParseError::UnexpectedEOFWanted(_) => true,
+        ParseError::Unexpected(_) => false,
+    })
+}
+
 /// A raw expression parse tree.
 ///
 /// This is a slim wrapper around `rnix`'s types that does the downcasting for
@@ -204,13 +332,14 @@ pub enum RawExpr<R: TreeRoot<Types>> {
 impl<R: TreeRoot<Types>> RawExpr<R> {
     /// Converts a raw AST node to an `Expr` node.
     ///
-    /// If `node` is not a valid expression node, this function will panic.
-    pub fn from(node: rnix::parser::Node<R>) -> Self {
+    /// If `node` is not a valid expression node, this returns an error
+    /// pointing at it. `source` is the file `node` was parsed from.
+    pub fn from(source: &Arc<File>, node: rnix::parser::Node<R>) -> Result<Self, Error> {
         macro_rules! match_expr {
             ( $($node:tt),* else($elvar:ident) $el:expr ) => {
                 match node.kind() {
                     $(
-                    NodeType::$node => RawExpr::$node($node::cast(node).unwrap()),
+                    NodeType::$node => Ok(RawExpr::$node($node::cast(node).unwrap())),
                     )*
 
                     $elvar => $el
@@ -225,10 +354,14 @@ impl<R: TreeRoot<Types>> RawExpr<R> {
             else(kind) {
                 match kind {
                     // Ident = NodeType::Token(Token::Ident(_))
-                    NodeType::Token(Token::Ident) => RawExpr::Ident(Ident::cast(node).unwrap()),
+                    NodeType::Token(Token::Ident) => Ok(RawExpr::Ident(Ident::cast(node).unwrap())),
                     // Value = ???
-                    NodeType::Token(t) if t.is_value() => RawExpr::Value(Value::cast(node).unwrap()),
-                    _ => panic!("unexpected AST node kind: {:?} (expected expression)", kind),
+                    NodeType::Token(t) if t.is_value() => Ok(RawExpr::Value(Value::cast(node).unwrap())),
+                    _ => Err(Error::at_span(
+                        source.clone(),
+                        node_span(source, &node),
+                        format!("unexpected {:?} (expected an expression)", kind),
+                    )),
                 }
             }
         )
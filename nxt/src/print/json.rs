@@ -0,0 +1,146 @@
+//! Conversion of values to JSON, following the semantics of
+//! `builtins.toJSON`.
+
+use super::text;
+use eval::{Error, EvalContext};
+use value::{Type, Value};
+
+use codemap::Span;
+use std::fmt::Write;
+
+/// Appends the JSON representation of `value` to `out`, forcing all nested
+/// values.
+///
+/// Sets with a `__toString` attribute are converted to a string, and sets
+/// with an `outPath` attribute (like derivations) are converted to the value
+/// of that attribute. Functions cannot be converted.
+///
+/// Nix copies paths to the store and writes the resulting store path. Since
+/// there is no store, paths are rejected unless `verbatim_paths` is set, in
+/// which case they are written as they are.
+///
+/// `span` is the location the conversion is attributed to in error messages.
+pub fn write_json<'a>(
+    ctx: &mut EvalContext<'a>,
+    value: &Value<'a>,
+    span: Span,
+    verbatim_paths: bool,
+    out: &mut String,
+) -> Result<(), Error> {
+    JsonWriter {
+        ctx,
+        span,
+        verbatim_paths,
+        ancestors: Vec::new(),
+    }
+    .value(value, out)
+}
+
+struct JsonWriter<'c, 'a: 'c> {
+    ctx: &'c mut EvalContext<'a>,
+    span: Span,
+    verbatim_paths: bool,
+    /// Lists and sets that are currently being converted, to detect cycles.
+    ancestors: Vec<*const ()>,
+}
+
+impl<'c, 'a> JsonWriter<'c, 'a> {
+    fn value(&mut self, value: &Value<'a>, out: &mut String) -> Result<(), Error> {
+        match value {
+            Value::String(s) => write_string(text(s)?, out),
+            Value::Path(path) if self.verbatim_paths => write_string(&path.to_string_lossy(), out),
+            Value::Path(path) => {
+                return Err(Error::CannotCopyToStore {
+                    path: path.display().to_string(),
+                });
+            }
+            Value::Int(_) | Value::Float(_) | Value::Bool(_) | Value::Null => {
+                write!(out, "{}", value).unwrap();
+            }
+            Value::List(list) => {
+                self.enter(&**list as *const _ as *const ())?;
+                out.push('[');
+                for (index, element) in list.iter().enumerate() {
+                    if index > 0 {
+                        out.push(',');
+                    }
+                    let element = self.ctx.force(element)?;
+                    self.value(&element, out)?;
+                }
+                out.push(']');
+                self.ancestors.pop();
+            }
+            Value::Set(set) => {
+                if set.contains_key("__toString") {
+                    let string = self.ctx.coerce_to_string(value.clone(), self.span)?;
+                    write_string(text(&string)?, out);
+                    return Ok(());
+                }
+                if let Some(out_path) = set.get("outPath") {
+                    let out_path = self.ctx.force(out_path)?;
+                    return self.value(&out_path, out);
+                }
+
+                self.enter(&**set as *const _ as *const ())?;
+                out.push('{');
+                for (index, (name, attr)) in set.iter().enumerate() {
+                    if index > 0 {
+                        out.push(',');
+                    }
+                    write_string(name, out);
+                    out.push(':');
+                    let attr = self.ctx.force(attr)?;
+                    self.value(&attr, out)?;
+                }
+                out.push('}');
+                self.ancestors.pop();
+            }
+            Value::Lambda(closure) => {
+                let span = closure.code().map(|(lambda, _)| lambda.span);
+                return Err(Error::CannotConvert {
+                    from: Type::Lambda,
+                    to: "JSON",
+                    span: span.unwrap_or(self.span),
+                });
+            }
+            Value::Builtin { primop, .. } => {
+                // Primops aren't defined in the source code, so there's no
+                // span pointing at them
+                return Err(Error::CannotConvertPrimOp {
+                    name: primop.name().to_string(),
+                    to: "JSON",
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts converting the list or set identified by `id`, which must not
+    /// contain itself.
+    fn enter(&mut self, id: *const ()) -> Result<(), Error> {
+        if self.ancestors.contains(&id) {
+            return Err(Error::InfiniteRecursion { span: self.span });
+        }
+        self.ancestors.push(id);
+        Ok(())
+    }
+}
+
+/// Appends a JSON string literal to `out`.
+pub fn write_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
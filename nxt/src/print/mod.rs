@@ -0,0 +1,343 @@
+//! Pretty-printing of values in Nix syntax, JSON or XML.
+//!
+//! For Nix syntax, values are first converted to a tree of `Doc`s, which is
+//! then laid out: lists and sets are printed on a single line if they fit into
+//! the configured width, and with one element per line otherwise.
+
+mod json;
+mod xml;
+
+pub use self::json::{write_json, write_string};
+pub use self::xml::write_xml;
+
+use eval::{Error, EvalContext};
+use value::{NixString, Thunk, Value};
+
+/// The syntax values are printed in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    /// Nix expression syntax.
+    Nix,
+    /// JSON, following the semantics of `builtins.toJSON`.
+    ///
+    /// Converting to JSON always forces all nested values.
+    Json,
+    /// The XML format used by `nix-instantiate --eval --xml`.
+    Xml,
+}
+
+/// Formats values.
+///
+/// In Nix syntax, unevaluated thunks are printed as `«thunk»` unless strict
+/// printing is enabled, in which case they are forced. Lists and sets
+/// containing themselves are printed as `«repeated»` when they are
+/// encountered again.
+#[derive(Debug, Clone)]
+pub struct Printer {
+    format: Format,
+    strict: bool,
+    width: usize,
+    verbatim_paths: bool,
+}
+
+impl Printer {
+    /// Creates a non-strict printer for Nix syntax with a width of 80
+    /// columns.
+    pub fn new() -> Self {
+        Self {
+            format: Format::Nix,
+            strict: false,
+            width: 80,
+            verbatim_paths: false,
+        }
+    }
+
+    /// Sets the output format.
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Sets whether nested thunks are forced before printing them.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Sets the number of columns Nix syntax output should fit into.
+    pub fn width(mut self, width: usize) -> Self {
+        self.width = width;
+        self
+    }
+
+    /// Sets whether paths are written verbatim when converting to JSON.
+    ///
+    /// `builtins.toJSON` copies paths to the store and writes the store path
+    /// instead. There is no store to copy them to, so by default, converting
+    /// a path to JSON is an error.
+    pub fn verbatim_paths(mut self, verbatim_paths: bool) -> Self {
+        self.verbatim_paths = verbatim_paths;
+        self
+    }
+
+    /// Formats `value`.
+    ///
+    /// Errors encountered while forcing thunks are printed, and
+    /// `Error::AlreadyPrinted` is returned.
+    pub fn print<'a>(&self, ctx: &mut EvalContext<'a>, value: &Value<'a>) -> Result<String, Error> {
+        let mut out = String::new();
+        match self.format {
+            Format::Nix => {
+                let doc = DocBuilder {
+                    strict: self.strict,
+                    ctx,
+                    ancestors: Vec::new(),
+                }
+                .value(value)?;
+                self.layout(&doc, 0, &mut out);
+            }
+            Format::Json => {
+                let span = ctx.globals_span();
+                write_json(ctx, value, span, self.verbatim_paths, &mut out).map_err(|e| ctx.report(e))?;
+            }
+            Format::Xml => {
+                write_xml(ctx, value, self.strict, &mut out).map_err(|e| ctx.report(e))?;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Appends `doc` to `out`, breaking blocks that don't fit into the width.
+    fn layout(&self, doc: &Doc, indent: usize, out: &mut String) {
+        let (open, items, close) = match doc {
+            Doc::Block { open, items, close } if !items.is_empty() => (open, items, close),
+            _ => return doc.flat(out),
+        };
+
+        let line_start = out.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let column = out[line_start..].chars().count();
+        if column + doc.flat_len() <= self.width {
+            return doc.flat(out);
+        }
+
+        out.push_str(open);
+        out.push('\n');
+        for item in items {
+            push_indent(out, indent + 2);
+            out.push_str(&item.prefix);
+            self.layout(&item.doc, indent + 2, out);
+            out.push_str(item.suffix);
+            out.push('\n');
+        }
+        push_indent(out, indent);
+        out.push_str(close);
+    }
+}
+
+impl Default for Printer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A formatted value.
+enum Doc {
+    /// Text that is never broken up.
+    Atom(String),
+    /// A list or set.
+    Block {
+        open: &'static str,
+        items: Vec<Item>,
+        close: &'static str,
+    },
+}
+
+/// An element of a list or an attribute of a set.
+struct Item {
+    /// The text before the value (eg. `name = `).
+    prefix: String,
+    doc: Doc,
+    /// The text after the value (eg. `;`).
+    suffix: &'static str,
+}
+
+impl Doc {
+    /// Returns the number of characters the doc takes up on a single line.
+    fn flat_len(&self) -> usize {
+        match self {
+            Doc::Atom(text) => text.chars().count(),
+            Doc::Block { open, items, close } => {
+                let items = items
+                    .iter()
+                    .map(|item| {
+                        // Each item is preceded by a space
+                        1 + item.prefix.chars().count() + item.doc.flat_len() + item.suffix.len()
+                    })
+                    .sum::<usize>();
+                open.len() + items + 1 + close.len()
+            }
+        }
+    }
+
+    /// Appends the doc to `out` on a single line.
+    fn flat(&self, out: &mut String) {
+        match self {
+            Doc::Atom(text) => out.push_str(text),
+            Doc::Block { open, items, close } => {
+                out.push_str(open);
+                for item in items {
+                    out.push(' ');
+                    out.push_str(&item.prefix);
+                    item.doc.flat(out);
+                    out.push_str(item.suffix);
+                }
+                out.push(' ');
+                out.push_str(close);
+            }
+        }
+    }
+}
+
+/// Converts values to `Doc`s.
+struct DocBuilder<'c, 'a: 'c> {
+    strict: bool,
+    ctx: &'c mut EvalContext<'a>,
+    /// Lists and sets that are currently being converted, to detect cycles.
+    ancestors: Vec<*const ()>,
+}
+
+impl<'c, 'a> DocBuilder<'c, 'a> {
+    fn value(&mut self, value: &Value<'a>) -> Result<Doc, Error> {
+        Ok(match value {
+            Value::String(s) => Doc::Atom(quote(text(s)?)),
+            Value::List(list) => {
+                let id = &**list as *const _ as *const ();
+                self.block(id, "[", "]", list.iter().map(|thunk| (String::new(), thunk, "")))?
+            }
+            Value::Set(set) => {
+                let id = &**set as *const _ as *const ();
+                let attrs = set
+                    .iter()
+                    .map(|(name, thunk)| (format!("{} = ", attr_name(name)), thunk, ";"));
+                self.block(id, "{", "}", attrs)?
+            }
+            Value::Lambda(closure) => match closure.code() {
+                Some((lambda, _)) => {
+                    let loc = self.ctx.codemap().look_up_span(lambda.span);
+                    Doc::Atom(format!("«lambda @ {}:{}»", loc.file.name(), loc.begin.line + 1))
+                }
+                None => Doc::Atom("«lambda»".to_string()),
+            },
+            Value::Builtin { primop, args } => {
+                if args.is_empty() {
+                    Doc::Atom(format!("«primop {}»", primop.name()))
+                } else {
+                    Doc::Atom(format!("«primop-app {}»", primop.name()))
+                }
+            }
+            Value::Int(_) | Value::Float(_) | Value::Path(_) | Value::Bool(_) | Value::Null => {
+                Doc::Atom(value.to_string())
+            }
+        })
+    }
+
+    fn thunk(&mut self, thunk: &Thunk<'a>) -> Result<Doc, Error> {
+        match thunk.value() {
+            Some(value) => self.value(&value),
+            None if self.strict => {
+                let value = self.ctx.force(thunk).map_err(|e| self.ctx.report(e))?;
+                self.value(&value)
+            }
+            None => Ok(Doc::Atom("«thunk»".to_string())),
+        }
+    }
+
+    /// Converts the elements of a list or set identified by `id`.
+    fn block<'t, I>(
+        &mut self,
+        id: *const (),
+        open: &'static str,
+        close: &'static str,
+        elements: I,
+    ) -> Result<Doc, Error>
+    where
+        I: Iterator<Item = (String, &'t Thunk<'a>, &'static str)>,
+        'a: 't,
+    {
+        if self.ancestors.contains(&id) {
+            return Ok(Doc::Atom("«repeated»".to_string()));
+        }
+
+        self.ancestors.push(id);
+        let items = elements
+            .map(|(prefix, thunk, suffix)| {
+                Ok(Item {
+                    prefix,
+                    doc: self.thunk(thunk)?,
+                    suffix,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>();
+        self.ancestors.pop();
+
+        Ok(Doc::Block {
+            open,
+            items: items?,
+            close,
+        })
+    }
+}
+
+/// Quotes and escapes a string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => quoted.push_str("\\$"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Returns the contents of a string that is printed, which has to be valid
+/// UTF-8.
+fn text(s: &NixString) -> Result<&str, Error> {
+    s.to_str().ok_or_else(|| Error::InvalidUtf8 {
+        string: s.to_string(),
+        span: None,
+    })
+}
+
+/// Returns `name` as it has to be written in a set, quoting it if it's not a
+/// valid identifier.
+fn attr_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+    ];
+
+    let mut chars = name.chars();
+    let is_ident = match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '\'' || c == '-')
+        }
+        _ => false,
+    };
+    if is_ident && !KEYWORDS.contains(&name) {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
+fn push_indent(out: &mut String, indent: usize) {
+    out.extend((0..indent).map(|_| ' '));
+}
@@ -0,0 +1,206 @@
+//! Conversion of values to XML, in the format produced by
+//! `nix-instantiate --eval --xml`.
+
+use super::text;
+use ast::LambdaParameter;
+use eval::{Error, EvalContext};
+use value::{Thunk, Value};
+
+use std::fmt::Write;
+
+/// Appends the XML document representing `value` to `out`.
+///
+/// If `strict` is `false`, unevaluated thunks are written as
+/// `<unevaluated />`.
+pub fn write_xml<'a>(
+    ctx: &mut EvalContext<'a>,
+    value: &Value<'a>,
+    strict: bool,
+    out: &mut String,
+) -> Result<(), Error> {
+    out.push_str("<?xml version='1.0' encoding='utf-8'?>\n");
+    let mut writer = XmlWriter {
+        ctx,
+        strict,
+        out,
+        indent: 0,
+        drv_paths: Vec::new(),
+    };
+    writer.open("expr", &[]);
+    writer.value(value)?;
+    writer.close("expr");
+    Ok(())
+}
+
+struct XmlWriter<'c, 'a: 'c> {
+    ctx: &'c mut EvalContext<'a>,
+    strict: bool,
+    out: &'c mut String,
+    indent: usize,
+    /// The `drvPath`s of the derivations written so far.
+    ///
+    /// Derivations are only written in full the first time.
+    drv_paths: Vec<String>,
+}
+
+impl<'c, 'a> XmlWriter<'c, 'a> {
+    fn value(&mut self, value: &Value<'a>) -> Result<(), Error> {
+        match value {
+            Value::String(s) => self.empty("string", &[("value", text(s)?)]),
+            Value::Int(i) => self.empty("int", &[("value", &*i.to_string())]),
+            Value::Float(f) => self.empty("float", &[("value", &*f.to_string())]),
+            Value::Path(path) => self.empty("path", &[("value", &*path.to_string_lossy())]),
+            Value::Bool(b) => self.empty("bool", &[("value", &*b.to_string())]),
+            Value::Null => self.empty("null", &[]),
+            Value::List(list) => {
+                self.open("list", &[]);
+                for element in list.iter() {
+                    self.thunk(element)?;
+                }
+                self.close("list");
+            }
+            Value::Set(set) => {
+                let is_derivation = match set.get("type") {
+                    Some(ty) => match self.ctx.force(ty)? {
+                        Value::String(ty) => ty.as_bytes() == b"derivation",
+                        _ => false,
+                    },
+                    None => false,
+                };
+
+                if is_derivation {
+                    let mut attrs = Vec::new();
+                    let mut repeated = false;
+                    for &name in &["drvPath", "outPath"] {
+                        if let Some(path) = set.get(name) {
+                            if let Value::String(path) = self.ctx.force(path)? {
+                                let path = path.to_string();
+                                if name == "drvPath" {
+                                    repeated = self.drv_paths.iter().any(|p| **p == *path);
+                                    self.drv_paths.push(path.clone());
+                                }
+                                attrs.push((name, path));
+                            }
+                        }
+                    }
+                    let attrs = attrs.iter().map(|(k, v)| (*k, &**v)).collect::<Vec<_>>();
+
+                    self.open("derivation", &attrs);
+                    if repeated {
+                        self.empty("repeated", &[]);
+                    } else {
+                        self.attrs(value)?;
+                    }
+                    self.close("derivation");
+                } else {
+                    self.attrs(value)?;
+                }
+            }
+            Value::Lambda(closure) => {
+                self.open("function", &[]);
+                if let Some((lambda, _)) = closure.code() {
+                    match lambda.param {
+                        Some(LambdaParameter::Ident(var)) => {
+                            let name = self.ctx.variable_name(var);
+                            self.empty("varpat", &[("name", name)]);
+                        }
+                        Some(LambdaParameter::Pattern(pattern)) => {
+                            let mut attrs = Vec::new();
+                            if pattern.ellipsis {
+                                attrs.push(("ellipsis", "1"));
+                            }
+                            if let Some(bind) = pattern.bind {
+                                attrs.push(("name", self.ctx.variable_name(bind)));
+                            }
+                            self.open("attrspat", &attrs);
+                            for entry in pattern.entries {
+                                let name = self.ctx.variable_name(entry.var);
+                                self.empty("attr", &[("name", name)]);
+                            }
+                            self.close("attrspat");
+                        }
+                        None => {}
+                    }
+                }
+                self.close("function");
+            }
+            Value::Builtin { .. } => self.empty("unevaluated", &[]),
+        }
+        Ok(())
+    }
+
+    /// Writes the attributes of a set.
+    fn attrs(&mut self, value: &Value<'a>) -> Result<(), Error> {
+        let set = match value {
+            Value::Set(set) => set,
+            _ => unreachable!(),
+        };
+        self.open("attrs", &[]);
+        for (name, attr) in set.iter() {
+            self.open("attr", &[("name", &**name)]);
+            self.thunk(attr)?;
+            self.close("attr");
+        }
+        self.close("attrs");
+        Ok(())
+    }
+
+    fn thunk(&mut self, thunk: &Thunk<'a>) -> Result<(), Error> {
+        match thunk.value() {
+            Some(value) => self.value(&value),
+            None if self.strict => {
+                let value = self.ctx.force(thunk)?;
+                self.value(&value)
+            }
+            None => {
+                self.empty("unevaluated", &[]);
+                Ok(())
+            }
+        }
+    }
+
+    fn open(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.start_tag(name, attrs);
+        self.out.push_str(">\n");
+        self.indent += 2;
+    }
+
+    fn close(&mut self, name: &str) {
+        self.indent -= 2;
+        self.write_indent();
+        writeln!(self.out, "</{}>", name).unwrap();
+    }
+
+    fn empty(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.start_tag(name, attrs);
+        self.out.push_str(" />\n");
+    }
+
+    fn start_tag(&mut self, name: &str, attrs: &[(&str, &str)]) {
+        self.write_indent();
+        write!(self.out, "<{}", name).unwrap();
+        for (attr, value) in attrs {
+            write!(self.out, " {}=\"{}\"", attr, escape(value)).unwrap();
+        }
+    }
+
+    fn write_indent(&mut self) {
+        self.out.extend((0..self.indent).map(|_| ' '));
+    }
+}
+
+/// Escapes an attribute value.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            c if (c as u32) < 0x20 => write!(escaped, "&#x{:x};", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
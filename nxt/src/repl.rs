@@ -0,0 +1,295 @@
+//! The interactive `nxt repl`.
+//!
+//! A single `EvalContext` is kept alive for the whole session, so imported
+//! files are only evaluated once. Variables bound in the REPL are passed to
+//! every evaluated line as its scope.
+
+use console::Term;
+use failure::Error;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::{Editor, Helper};
+
+use nxt::{self, Arenas, Config, EvalContext, IntoValue, Printer, Source, Thunk, Value};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+const HELP: &str = "\
+The following commands are available:
+
+  <expr>        Evaluate and print an expression
+  <x> = <expr>  Bind an expression to a variable
+  :l <path>     Load a Nix file and add its attributes to the scope
+  :p <expr>     Evaluate and print an expression, including nested values
+  :t <expr>     Print the type of an expression
+  :q            Exit the REPL
+  :?            Print this help";
+
+/// Runs the REPL until the user exits it.
+pub fn run(config: Config) -> Result<(), Error> {
+    let arenas = Arenas::new();
+    let repl = Rc::new(RefCell::new(Repl {
+        ctx: EvalContext::new(config, &arenas),
+        scope: BTreeMap::new(),
+        search_path: env::current_dir()?,
+    }));
+
+    let mut editor = Editor::new();
+    editor.set_helper(Some(ReplHelper { repl: repl.clone() }));
+
+    println!("Welcome to nxt. Type :? for help.");
+    println!();
+
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "nxt> " } else { "...> " };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if expression(&input).map_or(false, nxt::is_incomplete) {
+                    continue;
+                }
+
+                let input = mem::replace(&mut input, String::new());
+                editor.add_history_entry(input.trim_end());
+                if !repl.borrow_mut().handle(input.trim()) {
+                    break;
+                }
+            }
+            // Ctrl-C discards the current input
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the Nix expression contained in a line of input, if any.
+fn expression(input: &str) -> Option<&str> {
+    let input = input.trim_start();
+    if input.starts_with(":p") || input.starts_with(":t") {
+        Some(&input[2..])
+    } else if input.starts_with(':') {
+        None
+    } else if let Some((_, expr)) = binding(input) {
+        Some(expr)
+    } else {
+        Some(input)
+    }
+}
+
+/// Splits a binding `name = expr` into the name and the expression.
+fn binding(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    let name_len = input
+        .char_indices()
+        .find(|&(_, c)| !is_ident_char(c))
+        .map_or(input.len(), |(i, _)| i);
+    let (name, rest) = input.split_at(name_len);
+    let rest = rest.trim_start();
+    let starts_like_ident = name.chars().next().map_or(false, |c| c.is_alphabetic() || c == '_');
+    if starts_like_ident && rest.starts_with('=') && !rest.starts_with("==") {
+        Some((name, &rest[1..]))
+    } else {
+        None
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '\'' || c == '-'
+}
+
+/// Prints the error in `result`, unless it was already printed.
+fn check<T>(result: Result<T, nxt::Error>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(nxt::Error::AlreadyPrinted) => None,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            None
+        }
+    }
+}
+
+struct Repl<'a> {
+    ctx: EvalContext<'a>,
+    /// Variables bound in the REPL or loaded with `:l`.
+    scope: BTreeMap<String, Thunk<'a>>,
+    /// The directory relative paths in the input are resolved against.
+    search_path: PathBuf,
+}
+
+impl<'a> Repl<'a> {
+    /// Handles a line of input.
+    ///
+    /// Returns `false` if the REPL should exit.
+    fn handle(&mut self, input: &str) -> bool {
+        if input.is_empty() {
+            return true;
+        }
+
+        let (command, arg) = if input.starts_with(':') {
+            let end = input.find(char::is_whitespace).unwrap_or(input.len());
+            (&input[..end], input[end..].trim())
+        } else {
+            ("", input)
+        };
+
+        match command {
+            "" => match binding(input) {
+                Some((name, expr)) => {
+                    if let Some(value) = self.eval(expr) {
+                        self.scope.insert(name.to_string(), value.into_thunk());
+                    }
+                }
+                None => self.print(input, false),
+            },
+            ":p" => self.print(arg, true),
+            ":t" => {
+                if let Some(value) = self.eval(arg) {
+                    println!("{}", value.type_());
+                }
+            }
+            ":l" => self.load(Path::new(arg)),
+            ":q" => return false,
+            ":?" => println!("{}", HELP),
+            _ => eprintln!("error: unknown command `{}` (type :? for help)", command),
+        }
+        true
+    }
+
+    /// Evaluates `expr` in the REPL's scope.
+    fn eval(&mut self, expr: &str) -> Option<Value<'a>> {
+        let source = Source::Other {
+            source: expr,
+            name: "<repl>",
+            search_path: &self.search_path,
+        };
+        let result = self.ctx.eval_in_scope(source, &self.scope);
+        check(result)
+    }
+
+    fn print(&mut self, expr: &str, strict: bool) {
+        if let Some(value) = self.eval(expr) {
+            let (_, width) = Term::stdout().size();
+            let printer = Printer::new().strict(strict).width(width as usize);
+            let result = printer.print(&mut self.ctx, &value);
+            if let Some(output) = check(result) {
+                println!("{}", output);
+            }
+        }
+    }
+
+    /// Evaluates a Nix file and adds the attributes of the resulting set to
+    /// the scope.
+    fn load(&mut self, path: &Path) {
+        let value = self.ctx.eval(Source::File { path });
+        let value = match check(value) {
+            Some(value) => value,
+            None => return,
+        };
+        let value = self.ctx.auto_call(value, &BTreeMap::new());
+        match check(value) {
+            Some(Value::Set(set)) => {
+                for (name, value) in set.iter() {
+                    self.scope.insert(name.clone(), value.clone());
+                }
+                println!("Added {} variables.", set.len());
+            }
+            Some(other) => eprintln!(
+                "error: `{}` evaluates to {}, not a set",
+                path.display(),
+                other.type_()
+            ),
+            None => {}
+        }
+    }
+
+    /// Returns the names of all variables visible in the REPL.
+    fn variable_names(&self) -> BTreeSet<String> {
+        let globals = self.ctx.global_names().map(String::from);
+        self.scope.keys().cloned().chain(globals).collect()
+    }
+
+    /// Returns the attribute names of the set at `path` (eg. `a.b`), without
+    /// printing any errors.
+    fn attr_names(&mut self, path: &str) -> Vec<String> {
+        let mut names = path.split('.');
+        let first = names.next().unwrap_or("");
+        let mut value = match self.scope.get(first) {
+            Some(thunk) => self.ctx.force(thunk).ok(),
+            // Global variables can be evaluated without errors
+            None if self.ctx.global_names().any(|name| name == first) => {
+                let source = Source::Other {
+                    source: first,
+                    name: "<completion>",
+                    search_path: &self.search_path,
+                };
+                self.ctx.eval(source).ok()
+            }
+            None => None,
+        };
+
+        for name in names {
+            let thunk = match value {
+                Some(Value::Set(ref set)) => set.get(name).cloned(),
+                _ => None,
+            };
+            value = thunk.and_then(|thunk| self.ctx.force(&thunk).ok());
+        }
+
+        match value {
+            Some(Value::Set(set)) => set.keys().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Completes variable and attribute names.
+struct ReplHelper<'a> {
+    repl: Rc<RefCell<Repl<'a>>>,
+}
+
+impl<'a> Completer for ReplHelper<'a> {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize) -> Result<(usize, Vec<String>), ReadlineError> {
+        // Find the start of the (possibly dotted) name before the cursor
+        let start = line[..pos]
+            .char_indices()
+            .rev()
+            .find(|&(_, c)| !is_ident_char(c) && c != '.')
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        let word = &line[start..pos];
+
+        let mut repl = self.repl.borrow_mut();
+        let (start, prefix, names) = match word.rfind('.') {
+            Some(dot) => (start + dot + 1, &word[dot + 1..], repl.attr_names(&word[..dot])),
+            None => (start, word, repl.variable_names().into_iter().collect()),
+        };
+        let candidates = names
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl<'a> Hinter for ReplHelper<'a> {
+    fn hint(&self, _line: &str, _pos: usize) -> Option<String> {
+        None
+    }
+}
+
+impl<'a> Highlighter for ReplHelper<'a> {}
+
+impl<'a> Helper for ReplHelper<'a> {}
@@ -1,5 +1,7 @@
 use codemap_diagnostic::{Diagnostic, Emitter};
 
+use std::cmp;
+use std::mem;
 use std::str::FromStr;
 use std::ops::Index;
 use std::ops::IndexMut;
@@ -25,6 +27,20 @@ impl<'a> DiagnosticEmitter for Emitter<'a> {
 #[fail(display = "(this should not be printed)")]
 pub struct ErrorAlreadyPrinted;
 
+/// Trait for errors that can be converted to a list of diagnostics.
+pub trait IntoDiagnostics {
+    fn into_diagnostics(self) -> Vec<Diagnostic>;
+}
+
+impl<E> IntoDiagnostics for E
+where
+    E: Into<Diagnostic>,
+{
+    fn into_diagnostics(self) -> Vec<Diagnostic> {
+        vec![self.into()]
+    }
+}
+
 pub trait ResultExt<T> {
     fn print_diagnostic<M>(self, emitter: &mut M) -> Result<T, ErrorAlreadyPrinted>
     where
@@ -33,14 +49,14 @@ pub trait ResultExt<T> {
 
 impl<T, E> ResultExt<T> for Result<T, E>
 where
-    E: Into<Diagnostic>,
+    E: IntoDiagnostics,
 {
     fn print_diagnostic<M>(self, emitter: &mut M) -> Result<T, ErrorAlreadyPrinted>
     where
         M: DiagnosticEmitter,
     {
         self.map_err(|e| {
-            emitter.emit_diagnostics(&[e.into()]);
+            emitter.emit_diagnostics(&e.into_diagnostics());
             ErrorAlreadyPrinted
         })
     }
@@ -97,6 +113,15 @@ impl<T, I> IndexVec<T, I> where I: Into<usize> {
     pub fn with_capacity(capacity: usize) -> Self {
         IndexVec(Vec::with_capacity(capacity), PhantomData)
     }
+
+    /// Appends an element, which will be stored at index `self.len()`.
+    pub fn push(&mut self, value: T) {
+        self.0.push(value);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 }
 
 impl<T, I> Index<I> for IndexVec<T, I> where I: Into<usize> {
@@ -112,3 +137,43 @@ impl<T, I> IndexMut<I> for IndexVec<T, I> where I: Into<usize> {
         &mut self.0[index.into()]
     }
 }
+
+/// Returns the candidates that are most similar to `name`, best match first.
+///
+/// This is used to suggest alternatives when a name can't be found. Only
+/// candidates with a small edit distance are returned.
+pub fn similar_names<'a, I>(name: &str, candidates: I) -> Vec<&'a str>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    const MAX_SUGGESTIONS: usize = 3;
+
+    let max_distance = cmp::max(1, name.chars().count() / 3);
+    let mut similar = candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= max_distance)
+        .collect::<Vec<_>>();
+    similar.sort();
+    similar
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Computes the Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, a) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, &b) in b.iter().enumerate() {
+            let substitution = previous[j] + if a == b { 0 } else { 1 };
+            current[j + 1] = cmp::min(substitution, cmp::min(previous[j + 1], current[j]) + 1);
+        }
+        mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
@@ -1,14 +1,21 @@
 //! Defines dynamically typed Nix expression values.
 
-use ast::{Lambda, Expr};
+use ast::{Lambda, SetEntry};
+use builtins::PrimOp;
 
+use codemap::Span;
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
-use std::fmt;
+use std::iter::FromIterator;
+use std::ops::Deref;
 use std::path::PathBuf;
-use tendril::StrTendril;
+use std::rc::Rc;
+use std::{fmt, mem, str};
+use tendril::ByteTendril;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Type {
     String,
     Int,
@@ -18,20 +25,60 @@ pub enum Type {
     Null,
     List,
     Set,
+    Lambda,
 }
 
+impl Type {
+    /// Returns the name `builtins.typeOf` uses for this type.
+    pub fn name(self) -> &'static str {
+        match self {
+            Type::String => "string",
+            Type::Int => "int",
+            Type::Float => "float",
+            Type::Path => "path",
+            Type::Bool => "bool",
+            Type::Null => "null",
+            Type::List => "list",
+            Type::Set => "set",
+            Type::Lambda => "lambda",
+        }
+    }
+}
+
+/// Formats the type the way Nix refers to it in error messages ("a string",
+/// "an integer", ...).
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Type::String => "a string",
+            Type::Int => "an integer",
+            Type::Float => "a float",
+            Type::Path => "a path",
+            Type::Bool => "a boolean",
+            Type::Null => "null",
+            Type::List => "a list",
+            Type::Set => "a set",
+            Type::Lambda => "a function",
+        })
+    }
+}
+
+/// A possibly unevaluated value, shared by everything referring to it.
+pub type Thunk<'a> = Rc<Closure<'a>>;
+
 /// The value a Nix expression was evaluated to.
 ///
 /// A Nix expression will remain in "unevaluated" state until its value is
 /// needed, since Nix is a lazily evaluated language. Such unevaluated
-/// `Expr`s might be referred to by already evaluated `Value`s.
+/// expressions are stored as `Thunk`s and might be referred to by already
+/// evaluated `Value`s (eg. the elements of a list).
 #[derive(Debug, Clone)]
 pub enum Value<'a> {
     /// A string or URI.
     ///
     /// The unquoted URI notation just results in a string, there is no separate
     /// URI type.
-    String(StrTendril),
+    String(NixString),
 
     /// A signed integer.
     ///
@@ -56,9 +103,20 @@ pub enum Value<'a> {
 
     Null, // TODO null tracking
 
-    List(Vec<&'a Expr<'a>>),
+    List(Rc<Vec<Thunk<'a>>>),
 
-    Set(BTreeMap<String, &'a Expr<'a>>),
+    Set(Rc<Set<'a>>),
+
+    /// A function, represented by the closure created when instantiating the
+    /// lambda expression.
+    Lambda(Rc<Closure<'a>>),
+
+    /// A built-in function, along with the arguments it has been applied to
+    /// so far.
+    Builtin {
+        primop: Rc<PrimOp>,
+        args: Vec<Thunk<'a>>,
+    },
 }
 
 impl<'a> Value<'a> {
@@ -72,6 +130,16 @@ impl<'a> Value<'a> {
             Value::Null => Type::Null,
             Value::List(_) => Type::List,
             Value::Set(_) => Type::Set,
+            Value::Lambda(_) | Value::Builtin { .. } => Type::Lambda,
+        }
+    }
+
+    /// If this value is an integer or float, returns it as a float.
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Int(i) => Some(*i as f64),
+            Value::Float(f) => Some(*f),
+            _ => None,
         }
     }
 
@@ -94,10 +162,249 @@ impl<'a> fmt::Display for Value<'a> {
             Value::Path(p) => p.display().fmt(f),
             Value::Bool(b) => b.fmt(f),
             Value::Null => f.write_str("null"),
-            Value::List(vec) => f.debug_list().entries(vec.iter()).finish(),
-            Value::Set(map) => f.debug_map().entries(map.iter()).finish(),
+            Value::List(list) => {
+                f.write_str("[ ")?;
+                for element in list.iter() {
+                    write!(f, "{} ", element)?;
+                }
+                f.write_str("]")
+            }
+            Value::Set(set) => {
+                f.write_str("{ ")?;
+                for (name, value) in set.iter() {
+                    write!(f, "{} = {}; ", name, value)?;
+                }
+                f.write_str("}")
+            }
+            Value::Lambda(_) => f.write_str("<LAMBDA>"),
+            Value::Builtin { .. } => f.write_str("<PRIMOP>"),
+        }
+    }
+}
+
+/// The attributes of an attribute set, sorted by name.
+///
+/// Sets created by set expressions (and updates of them with `//`) also know
+/// where their attributes are defined (for `builtins.unsafeGetAttrPos`).
+#[derive(Debug, Clone, Default)]
+pub struct Set<'a> {
+    attrs: BTreeMap<String, Thunk<'a>>,
+    /// The `name = value;` entries of the set expression.
+    entries: &'a [SetEntry<'a>],
+    /// The spans of the names of other attributes, like the dynamic
+    /// attributes of the set expression.
+    positions: Vec<(String, Span)>,
+}
+
+impl<'a> Set<'a> {
+    /// Creates the set resulting from a set expression.
+    ///
+    /// `dynamic` contains the names of the dynamic attributes, along with
+    /// their spans.
+    pub(crate) fn with_positions(
+        attrs: BTreeMap<String, Thunk<'a>>,
+        entries: &'a [SetEntry<'a>],
+        dynamic: Vec<(String, Span)>,
+    ) -> Self {
+        Set {
+            attrs,
+            entries,
+            positions: dynamic,
+        }
+    }
+
+    /// Creates the set resulting from `self // other`.
+    ///
+    /// The attributes of `other` take precedence, along with their positions.
+    pub(crate) fn update(&self, other: &Set<'a>) -> Self {
+        let mut attrs = self.attrs.clone();
+        attrs.extend(other.attrs.iter().map(|(name, value)| (name.clone(), value.clone())));
+        let mut positions = self
+            .all_positions()
+            .into_iter()
+            .filter(|(name, _)| !other.attrs.contains_key(name))
+            .collect::<Vec<_>>();
+        positions.extend(other.all_positions());
+        Set {
+            attrs,
+            entries: &[],
+            positions,
         }
     }
+
+    /// Returns the span of the name of the attribute `name`, if the set was
+    /// created by a set expression defining it.
+    pub(crate) fn attr_pos(&self, name: &str) -> Option<Span> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name == name)
+            .map(|entry| entry.span)
+            .or_else(|| {
+                self.positions
+                    .iter()
+                    .find(|(attr, _)| attr == name)
+                    .map(|&(_, span)| span)
+            })
+    }
+
+    /// Returns the names of all attributes with known positions, along with
+    /// the spans of the names.
+    fn all_positions(&self) -> Vec<(String, Span)> {
+        let entries = self.entries.iter().map(|entry| (entry.name.to_string(), entry.span));
+        entries.chain(self.positions.iter().cloned()).collect()
+    }
+
+    /// Returns the attributes, without the positions.
+    pub fn into_attrs(self) -> BTreeMap<String, Thunk<'a>> {
+        self.attrs
+    }
+}
+
+impl<'a> Deref for Set<'a> {
+    type Target = BTreeMap<String, Thunk<'a>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.attrs
+    }
+}
+
+impl<'a> From<BTreeMap<String, Thunk<'a>>> for Set<'a> {
+    fn from(attrs: BTreeMap<String, Thunk<'a>>) -> Self {
+        Set {
+            attrs,
+            ..Set::default()
+        }
+    }
+}
+
+impl<'a> FromIterator<(String, Thunk<'a>)> for Set<'a> {
+    fn from_iter<I>(iter: I) -> Self
+    where
+        I: IntoIterator<Item = (String, Thunk<'a>)>,
+    {
+        BTreeMap::from_iter(iter).into()
+    }
+}
+
+/// The contents of a string.
+///
+/// Like in Nix, strings are sequences of bytes. They are usually UTF-8, but
+/// `substring` and friends operate on bytes and can split characters, so
+/// invalid UTF-8 is only rejected when a string has to be turned into text
+/// (eg. when it is used as an attribute name or converted to JSON).
+#[derive(Clone)]
+pub struct NixString(ByteTendril);
+
+impl NixString {
+    /// Creates an empty string.
+    pub fn new() -> Self {
+        NixString(ByteTendril::new())
+    }
+
+    /// Returns the bytes of the string.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the string as a `str`, if it is valid UTF-8.
+    pub fn to_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
+    }
+
+    /// Converts the string into a `String`, or returns it unchanged if it
+    /// isn't valid UTF-8.
+    pub fn into_string(self) -> Result<String, NixString> {
+        match self.to_str() {
+            Some(string) => Ok(string.to_string()),
+            None => Err(self),
+        }
+    }
+
+    /// Returns the string as a `str`, replacing invalid UTF-8 with U+FFFD.
+    pub fn to_string_lossy(&self) -> Cow<str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    /// Appends `bytes` to the string.
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.0.push_slice(bytes);
+    }
+
+    /// Appends `string` to the string.
+    pub fn push_str(&mut self, string: &str) {
+        self.push_bytes(string.as_bytes());
+    }
+}
+
+impl Default for NixString {
+    fn default() -> Self {
+        NixString::new()
+    }
+}
+
+impl Deref for NixString {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl<'s> From<&'s [u8]> for NixString {
+    fn from(bytes: &[u8]) -> Self {
+        NixString(ByteTendril::from_slice(bytes))
+    }
+}
+
+impl<'s> From<&'s str> for NixString {
+    fn from(string: &str) -> Self {
+        string.as_bytes().into()
+    }
+}
+
+impl From<Vec<u8>> for NixString {
+    fn from(bytes: Vec<u8>) -> Self {
+        bytes.as_slice().into()
+    }
+}
+
+impl From<String> for NixString {
+    fn from(string: String) -> Self {
+        string.as_bytes().into()
+    }
+}
+
+impl PartialEq for NixString {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl Eq for NixString {}
+
+/// Strings are ordered by their bytes, like in Nix.
+impl PartialOrd for NixString {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for NixString {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+
+impl fmt::Display for NixString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_string_lossy().fmt(f)
+    }
+}
+
+impl fmt::Debug for NixString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.to_string_lossy().fmt(f)
+    }
 }
 
 /// A lazily evaluated computation along with its captured environment.
@@ -111,25 +418,163 @@ impl<'a> fmt::Display for Value<'a> {
 /// An instantiated closure can be evaluated directly if it takes no arguments
 /// (hence becoming "transparent"), or as part of a matching `Apply` expression
 /// (yielding an error if the arguments don't match).
-#[derive(Debug)]
 pub struct Closure<'a> {
     /// Interior mutability is used to update the closure when it's evaluated.
     inner: RefCell<ClosureInner<'a>>,
 }
 
-#[derive(Debug)]
 enum ClosureInner<'a> {
     /// Closure has been forced already.
     Evaluated(Value<'a>),
 
+    /// Closure is currently being evaluated ("blackholed").
+    ///
+    /// Forcing a closure in this state means that its value depends on
+    /// itself, so evaluation would never terminate.
+    Evaluating {
+        /// The lambda this closure was created from.
+        lambda: &'a Lambda<'a>,
+    },
+
     /// Closure has not been evaluated yet.
     ///
     /// The closure can be evaluated once and moved to the `Evaluated` state.
     Unevaluated {
         /// Captured free variables from outside the lambda.
-        captures: Vec<Closure<'a>>,
+        ///
+        /// These are in the same order as the variables in `lambda.captures`.
+        captures: Vec<Thunk<'a>>,
 
         /// The lambda this closure was created from.
         lambda: &'a Lambda<'a>,
     },
 }
+
+impl<'a> Closure<'a> {
+    /// Creates an unevaluated closure by instantiating `lambda`.
+    pub fn new(lambda: &'a Lambda<'a>, captures: Vec<Thunk<'a>>) -> Self {
+        Self {
+            inner: RefCell::new(ClosureInner::Unevaluated { captures, lambda }),
+        }
+    }
+
+    /// Creates a closure that is already evaluated to `value`.
+    pub fn evaluated(value: Value<'a>) -> Self {
+        Self {
+            inner: RefCell::new(ClosureInner::Evaluated(value)),
+        }
+    }
+
+    /// Returns the value of this closure if it has already been evaluated.
+    pub fn value(&self) -> Option<Value<'a>> {
+        match &*self.inner.borrow() {
+            ClosureInner::Evaluated(value) => Some(value.clone()),
+            ClosureInner::Evaluating { .. } | ClosureInner::Unevaluated { .. } => None,
+        }
+    }
+
+    /// Returns whether this closure is currently being evaluated.
+    pub fn is_evaluating(&self) -> bool {
+        match &*self.inner.borrow() {
+            ClosureInner::Evaluating { .. } => true,
+            ClosureInner::Evaluated(_) | ClosureInner::Unevaluated { .. } => false,
+        }
+    }
+
+    /// Returns the lambda and the captured variables needed to evaluate this
+    /// closure, or `None` if it has already been evaluated.
+    ///
+    /// This does not change the closure's state, so it is used for closures
+    /// that are called as functions instead of being forced.
+    pub fn code(&self) -> Option<(&'a Lambda<'a>, Vec<Thunk<'a>>)> {
+        match &*self.inner.borrow() {
+            ClosureInner::Evaluated(_) | ClosureInner::Evaluating { .. } => None,
+            ClosureInner::Unevaluated { captures, lambda } => Some((*lambda, captures.clone())),
+        }
+    }
+
+    /// Moves an unevaluated closure to the `Evaluating` state, returning the
+    /// lambda and captured variables needed to evaluate it.
+    ///
+    /// If the closure is already being evaluated, returns its lambda as an
+    /// error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the closure has already been evaluated.
+    pub fn begin_evaluation(&self) -> Result<(&'a Lambda<'a>, Vec<Thunk<'a>>), &'a Lambda<'a>> {
+        let mut inner = self.inner.borrow_mut();
+        let lambda = match &*inner {
+            ClosureInner::Unevaluated { lambda, .. } => *lambda,
+            ClosureInner::Evaluating { lambda } => return Err(*lambda),
+            ClosureInner::Evaluated(_) => panic!("closure has already been evaluated"),
+        };
+
+        match mem::replace(&mut *inner, ClosureInner::Evaluating { lambda }) {
+            ClosureInner::Unevaluated { captures, .. } => Ok((lambda, captures)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Moves a closure in the `Evaluating` state back to the `Unevaluated`
+    /// state after its evaluation failed.
+    pub fn abort_evaluation(&self, captures: Vec<Thunk<'a>>) {
+        let mut inner = self.inner.borrow_mut();
+        let lambda = match &*inner {
+            ClosureInner::Evaluating { lambda } => *lambda,
+            _ => panic!("closure is not being evaluated"),
+        };
+
+        *inner = ClosureInner::Unevaluated { captures, lambda };
+    }
+
+    /// Sets the captured variables of an unevaluated closure.
+    ///
+    /// Bindings in `let` expressions and recursive sets can refer to
+    /// themselves, so their closures need to exist before their captures can
+    /// be collected.
+    pub fn set_captures(&self, new_captures: Vec<Thunk<'a>>) {
+        match &mut *self.inner.borrow_mut() {
+            ClosureInner::Unevaluated { captures, .. } => *captures = new_captures,
+            ClosureInner::Evaluated(_) | ClosureInner::Evaluating { .. } => {
+                panic!("cannot set captures of evaluated closure")
+            }
+        }
+    }
+
+    /// Stores the result of evaluating this closure.
+    ///
+    /// This drops the captured variables, since they aren't needed anymore.
+    pub fn set_value(&self, value: Value<'a>) {
+        *self.inner.borrow_mut() = ClosureInner::Evaluated(value);
+    }
+}
+
+/// Closures can refer to themselves through their captures, so this only
+/// prints the value if the closure was already evaluated.
+impl<'a> fmt::Debug for Closure<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.inner.borrow() {
+            ClosureInner::Evaluated(value) => f.debug_tuple("Evaluated").field(value).finish(),
+            ClosureInner::Evaluating { lambda } => {
+                f.debug_tuple("Evaluating").field(&lambda.span).finish()
+            }
+            ClosureInner::Unevaluated { lambda, .. } => {
+                f.debug_tuple("Unevaluated").field(&lambda.span).finish()
+            }
+        }
+    }
+}
+
+/// Displays the closure's value, or `<CODE>` if it hasn't been evaluated yet
+/// (like Nix does).
+impl<'a> fmt::Display for Closure<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.inner.borrow() {
+            ClosureInner::Evaluated(value) => value.fmt(f),
+            ClosureInner::Evaluating { .. } | ClosureInner::Unevaluated { .. } => {
+                f.write_str("<CODE>")
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use std::process::{Command, Output};
+
+/// Runs the `nxt` binary with `args`.
+fn nxt(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_nxt"))
+        .arg("--color=never")
+        .args(args)
+        .output()
+        .expect("failed to run nxt")
+}
+
+/// Returns the number of syntax errors `nxt` reports on stderr.
+fn syntax_errors(output: &Output) -> usize {
+    String::from_utf8_lossy(&output.stderr)
+        .matches("could not parse")
+        .count()
+}
+
+#[test]
+fn all_syntax_errors() {
+    let source = "{ a = ); b = 1; c = ]; }";
+
+    let output = nxt(&["eval", source]);
+    assert!(!output.status.success());
+    assert_eq!(syntax_errors(&output), 1);
+
+    let output = nxt(&["--all-syntax-errors", "eval", source]);
+    assert!(!output.status.success());
+    assert_eq!(syntax_errors(&output), 2);
+
+    // Errors caused by the input ending early are only reported if there are
+    // no other errors
+    let output = nxt(&["--all-syntax-errors", "eval", "{ a = ); b = ["]);
+    assert_eq!(syntax_errors(&output), 1);
+    let output = nxt(&["--all-syntax-errors", "eval", "{ a = ["]);
+    assert_eq!(syntax_errors(&output), 1);
+}
@@ -0,0 +1,98 @@
+extern crate nxt;
+#[macro_use]
+extern crate serde_derive;
+
+use nxt::{from_value, Arenas, Config, EvalContext, FromValue, Source, Value};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+fn eval<'a>(ctx: &mut EvalContext<'a>, expr: &str) -> Value<'a> {
+    ctx.eval(Source::Other {
+        source: expr,
+        name: "<test>",
+        search_path: Path::new("."),
+    })
+    .unwrap_or_else(|_| panic!("failed to evaluate `{}`", expr))
+}
+
+#[test]
+fn from_value_forces_thunks() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+
+    let value = eval(&mut ctx, "[ (1 + 1) 3 ]");
+    assert_eq!(Vec::<u8>::from_value(&mut ctx, value).unwrap(), vec![2, 3]);
+
+    let value = eval(&mut ctx, "{ a = null; b = \"x\"; }");
+    let map = BTreeMap::<String, Option<String>>::from_value(&mut ctx, value).unwrap();
+    assert_eq!(map["a"], None);
+    assert_eq!(map["b"], Some("x".to_string()));
+
+    let value = eval(&mut ctx, "/etc/nixos");
+    assert_eq!(PathBuf::from_value(&mut ctx, value).unwrap(), Path::new("/etc/nixos"));
+
+    let value = eval(&mut ctx, "300");
+    assert!(u8::from_value(&mut ctx, value).is_err());
+
+    let value = eval(&mut ctx, "{ a = [ 1 true ]; }");
+    let error = BTreeMap::<String, Vec<i64>>::from_value(&mut ctx, value).unwrap_err();
+    assert_eq!(error.path(), "a[1]");
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct Server {
+    name: String,
+    port: u16,
+    #[serde(default)]
+    tls: bool,
+    backend: Backend,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+enum Backend {
+    Static,
+    Proxy { upstream: String },
+}
+
+#[test]
+fn deserialize() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+
+    let value = eval(
+        &mut ctx,
+        r#"
+        let port = 8000; in [
+          { name = "static"; port = port + 80; backend = "Static"; }
+          { name = "proxy"; inherit port; tls = true; backend.Proxy.upstream = "localhost"; }
+        ]
+        "#,
+    );
+    let servers: Vec<Server> = from_value(&mut ctx, value).unwrap();
+    assert_eq!(
+        servers,
+        vec![
+            Server {
+                name: "static".to_string(),
+                port: 8080,
+                tls: false,
+                backend: Backend::Static,
+            },
+            Server {
+                name: "proxy".to_string(),
+                port: 8000,
+                tls: true,
+                backend: Backend::Proxy {
+                    upstream: "localhost".to_string(),
+                },
+            },
+        ]
+    );
+
+    let value = eval(
+        &mut ctx,
+        r#"{ servers = [ { name = "a"; port = 1; backend.Proxy.upstream = 2; } ]; }"#,
+    );
+    let error = from_value::<BTreeMap<String, Vec<Server>>>(&mut ctx, value).unwrap_err();
+    assert_eq!(error.path(), "servers[0].backend.Proxy.upstream");
+}
@@ -0,0 +1,811 @@
+extern crate nxt;
+
+use nxt::{
+    parse_nix_path, Arenas, Config, EvalContext, Format, IntoValue, PrimOp, Printer,
+    SearchPathEntry, Source, TreeKind, Value,
+};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs, process};
+
+/// Evaluates `expr` and returns the displayed value, or `None` if evaluation
+/// fails.
+fn try_eval(expr: &str) -> Option<String> {
+    try_eval_with(Config::default(), expr)
+}
+
+/// Like `try_eval`, but uses `config` for the evaluation.
+fn try_eval_with(config: Config, expr: &str) -> Option<String> {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(config, &arenas);
+    let value = ctx.eval(Source::Other {
+        source: expr,
+        name: "<test>",
+        search_path: Path::new("."),
+    });
+    value.ok().map(|value| value.to_string())
+}
+
+/// Creates an empty temporary directory for the test `name`.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("nxt-{}-{}", name, process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn eval(expr: &str) -> String {
+    try_eval(expr).unwrap_or_else(|| panic!("failed to evaluate `{}`", expr))
+}
+
+/// Evaluates `expr`, which has to fail, and returns the reported error.
+///
+/// Errors are printed as diagnostics instead of being returned, so this runs
+/// `nxt eval` to capture them.
+fn eval_error(expr: &str) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_nxt"))
+        .args(&["--color=never", "eval", expr])
+        .output()
+        .expect("failed to run nxt");
+    assert!(!output.status.success(), "`{}` did not fail", expr);
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Evaluates `expr` and formats the value using `printer`.
+fn try_print(expr: &str, printer: Printer) -> Option<String> {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    let value = ctx
+        .eval(Source::Other {
+            source: expr,
+            name: "<test>",
+            search_path: Path::new("."),
+        })
+        .unwrap();
+    printer.print(&mut ctx, &value).ok()
+}
+
+fn print(expr: &str, printer: Printer) -> String {
+    try_print(expr, printer).unwrap_or_else(|| panic!("failed to print `{}`", expr))
+}
+
+#[test]
+fn literals() {
+    assert_eq!(eval("42"), "42");
+    assert_eq!(eval("1.5"), "1.5");
+    assert_eq!(eval("/a/b"), "/a/b");
+}
+
+#[test]
+fn lowering_errors() {
+    assert!(eval_error("x").contains("cannot resolve variable"));
+    // Syntax that isn't an expression is reported instead of panicking
+    let error = eval_error("${\"a\"}");
+    assert!(error.contains("could not parse"), "{}", error);
+    assert!(!error.contains("panicked"), "{}", error);
+}
+
+#[test]
+fn laziness() {
+    // Bindings, list elements and attributes are only evaluated when needed
+    assert_eq!(eval("let x = assert false; 1; in 2"), "2");
+    assert_eq!(eval("[ 1 (assert false; 2) ]"), "[ 1 <CODE> ]");
+    assert_eq!(eval("{ a = assert false; 1; }"), "{ a = <CODE>; }");
+    assert_eq!(try_eval("let x = assert false; 1; in x"), None);
+}
+
+#[test]
+fn functions() {
+    assert_eq!(eval("(x: y: x) 1 2"), "1");
+    assert_eq!(eval("let f = x: [ x x ]; in f 3"), "[ 3 3 ]");
+    assert_eq!(eval("if (x: x) true then 1 else 2"), "1");
+    assert_eq!(try_eval("1 2"), None);
+    assert_eq!(try_eval("if 1 then 2 else 3"), None);
+}
+
+#[test]
+fn bindings() {
+    assert_eq!(eval("let a = 1; b = a; in b"), "1");
+    assert_eq!(eval("let f = n: if n then f false else 2; in f true"), "2");
+}
+
+#[test]
+fn infinite_recursion() {
+    assert!(eval_error("let x = x; in x").contains("infinite recursion encountered"));
+    assert!(eval_error("let a = b; b = a; in a").contains("infinite recursion encountered"));
+    // Bindings that refer to themselves are fine as long as they're not used
+    assert_eq!(eval("let x = x; in 1"), "1");
+}
+
+#[test]
+fn patterns() {
+    assert_eq!(eval("({ a, b }: [ a b ]) { a = 1; b = 2; }"), "[ 1 2 ]");
+    assert_eq!(eval("({ a, b ? a, ... }: b) { a = 1; c = 2; }"), "1");
+    assert_eq!(eval("(args@{ a }: args) { a = 1; }"), "{ a = 1; }");
+    assert_eq!(eval("{ __functor = self: x: x; } 1"), "1");
+    assert!(eval_error("({ a }: a) { }").contains("without required argument `a`"));
+    assert!(eval_error("({ a }: a) { a = 1; b = 2; }").contains("unexpected argument `b`"));
+    assert_eq!(try_eval("({ a }: a) 1"), None);
+}
+
+#[test]
+fn attribute_sets() {
+    assert_eq!(eval("({ b, ... }: b) rec { a = 1; b = a; }"), "1");
+    assert_eq!(eval("({ a, ... }: a) { a.b = 1; a.c = 2; }"), "{ b = 1; c = 2; }");
+    assert_eq!(eval("({ a, ... }: a) { a = { b = 1; }; a.c = 2; }"), "{ b = 1; c = 2; }");
+    assert_eq!(eval("let x = 1; in { inherit x; }"), "{ x = 1; }");
+    assert_eq!(eval("{ ${\"a\"} = 1; ${null} = 2; }"), "{ a = 1; }");
+    assert!(eval_error("{ a = 1; a = 2; }").contains("attribute `a` already defined"));
+    assert!(eval_error("{ a = 1; ${\"a\"} = 2; }").contains("dynamic attribute `a` already defined"));
+    assert!(eval_error("let ${\"a\"} = 1; in a").contains("dynamic attributes are not allowed in `let`"));
+}
+
+#[test]
+fn with() {
+    assert_eq!(eval("with { x = 2; }; x"), "2");
+    // Lexical bindings take precedence over `with`
+    assert_eq!(eval("let x = 1; in with { x = 2; }; x"), "1");
+    assert_eq!(eval("with { x = 1; }; with { x = 2; }; x"), "2");
+    assert_eq!(eval("({ x }: x) (with { x = 1; }; { inherit x; })"), "1");
+    assert!(eval_error("with { }; x").contains("undefined variable `x`"));
+}
+
+#[test]
+fn strings() {
+    assert_eq!(eval("let x = \"b\"; in \"a${x}c\""), "\"abc\"");
+    assert_eq!(eval("\"\\t\\$${\"x\"}\""), "\"\t$x\"");
+    assert_eq!(eval("''\n  a\n    b\n''"), "\"a\n  b\n\"");
+    assert_eq!(eval("''a'''b''${\"c\"}''\\n''"), "\"a''b${\"c\"}\n\"");
+    // Escaped characters are never stripped and end the indentation
+    assert_eq!(eval("''  a''\\n  b''"), "\"a\n  b\"");
+    assert_eq!(eval("''\n  ''\\t a\n    b\n''"), "\"\t a\n  b\n\"");
+    assert_eq!(eval("\"${{ __toString = self: \"s\"; }}\""), "\"s\"");
+    assert_eq!(try_eval("\"${1}\""), None);
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(eval("1 + 2 * 3"), "7");
+    assert_eq!(eval("7 / 2"), "3");
+    assert_eq!(eval("1 + 0.5"), "1.5");
+    assert_eq!(eval("-9223372036854775807 - 1"), "-9223372036854775808");
+    assert_eq!(try_eval("1 / 0"), None);
+    assert_eq!(try_eval("1 + true"), None);
+}
+
+#[test]
+fn comparison() {
+    assert_eq!(eval("[ 1 { a = 2; } ] == [ 1 { a = 2; } ]"), "true");
+    assert_eq!(eval("1 == 1.0"), "true");
+    assert_eq!(eval("[ 1 2 ] < [ 1 3 ]"), "true");
+    assert_eq!(eval("\"abc\" >= \"abd\""), "false");
+    assert_eq!(eval("false -> 1 / 0 == 0"), "true");
+}
+
+#[test]
+fn operators() {
+    assert_eq!(eval("{ a = 1; b = 2; } // { b = 3; }"), "{ a = 1; b = 3; }");
+    assert_eq!(eval("[ 1 ] ++ [ 2 ]"), "[ 1 2 ]");
+    assert_eq!(eval("\"a\" + \"b\""), "\"ab\"");
+    assert_eq!(eval("!true || false && true"), "false");
+    assert_eq!(eval("{ a = 1 / 0; } ? a"), "true");
+    assert_eq!(eval("{ a = 1; } ? a.b"), "false");
+    assert!(eval_error("1 + true").contains("cannot apply `+` to an integer and a boolean"));
+}
+
+#[test]
+fn attribute_selection() {
+    assert_eq!(eval("{ a.b.c = 1; }.a.b.c"), "1");
+    assert_eq!(eval("let n = \"b\"; in { a.b = 2; }.a.${n}"), "2");
+    assert_eq!(eval("{ }.a.b or 3"), "3");
+    assert_eq!(eval("(1).a or 2"), "2");
+    let error = eval_error("{ foo = 1; }.fo");
+    assert!(error.contains("attribute `fo` missing"), "{}", error);
+    assert!(error.contains("did you mean `foo`?"), "{}", error);
+    assert_eq!(try_eval("(1).a"), None);
+}
+
+#[test]
+fn imports() {
+    assert_eq!(eval("import ./tests/imports/main.nix"), "2");
+    assert_eq!(eval("(import ./tests/imports/lib).value"), "1");
+    assert_eq!(
+        eval("let f = ./tests/imports/scoped.nix; in scopedImport { x = 1; y = 2; } f + scopedImport { x = 3; y = 4; } f"),
+        "46"
+    );
+    // The AST is translated separately for every set of names in scope
+    assert_eq!(
+        try_eval("let f = ./tests/imports/scoped.nix; in scopedImport { x = 1; y = 2; } f + scopedImport { x = 1; } f"),
+        None
+    );
+    assert_eq!(try_eval("import ./tests/imports/scoped.nix"), None);
+    assert_eq!(try_eval("import ./tests/imports/missing.nix"), None);
+    assert_eq!(try_eval("import \"tests/imports/main.nix\""), None);
+}
+
+#[test]
+fn import_cycles() {
+    assert_eq!(eval("(import ./tests/imports/lazy.nix).b"), "1");
+    let error = eval_error("import ./tests/imports/cycle.nix");
+    assert!(error.contains("infinite recursion encountered"), "{}", error);
+    assert!(error.contains("cycle.nix:2"), "{}", error);
+}
+
+#[test]
+fn import_cache() {
+    fn eval_in(ctx: &mut EvalContext, expr: &str) -> String {
+        let value = ctx.eval(Source::Other {
+            source: expr,
+            name: "<test>",
+            search_path: Path::new("."),
+        });
+        value.unwrap().to_string()
+    }
+
+    let dir = temp_dir("import_cache");
+    let file = dir.join("value.nix");
+    let expr = format!("import {}", file.display());
+    fs::write(&file, "1").unwrap();
+
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    assert_eq!(eval_in(&mut ctx, &expr), "1");
+    // Imported files are only read once per context
+    fs::write(&file, "2").unwrap();
+    assert_eq!(eval_in(&mut ctx, &expr), "1");
+
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    assert_eq!(eval_in(&mut ctx, &expr), "2");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn search_path() {
+    let dir = temp_dir("search_path");
+    for &(file, value) in &[
+        ("include/foo.nix", "1"),
+        ("env/foo.nix", "2"),
+        ("env/bar.nix", "3"),
+        ("pkgs/default.nix", "4"),
+        ("pkgs/lib/default.nix", "5"),
+    ] {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, value).unwrap();
+    }
+
+    let eval_with_path = |expr: &str| {
+        // Like the command line, `-I` entries come before `NIX_PATH`
+        let mut nix_path = vec![SearchPathEntry::from(&*dir.join("include").to_string_lossy())];
+        nix_path.extend(parse_nix_path(&format!(
+            "nixpkgs={}:{}",
+            dir.join("pkgs").display(),
+            dir.join("env").display()
+        )));
+        let config = Config {
+            nix_path,
+            ..Config::default()
+        };
+        try_eval_with(config, expr)
+    };
+    assert_eq!(eval_with_path("import <foo.nix>"), Some("1".to_string()));
+    assert_eq!(eval_with_path("import <bar.nix>"), Some("3".to_string()));
+    assert_eq!(eval_with_path("import <nixpkgs>"), Some("4".to_string()));
+    assert_eq!(eval_with_path("import <nixpkgs/lib>"), Some("5".to_string()));
+    assert_eq!(eval_with_path("<nixpkgsfoo>"), None);
+    assert_eq!(eval_with_path("<missing.nix>"), None);
+    assert_eq!(try_eval("<foo.nix>"), None);
+
+    assert_eq!(
+        eval_with_path("__nixPath"),
+        Some(format!(
+            "[ {{ path = \"{}\"; prefix = \"\"; }} {{ path = \"{}\"; prefix = \"nixpkgs\"; }} \
+             {{ path = \"{}\"; prefix = \"\"; }} ]",
+            dir.join("include").display(),
+            dir.join("pkgs").display(),
+            dir.join("env").display()
+        ))
+    );
+    let find_file = format!(
+        "import (__findFile [ {{ prefix = \"x\"; path = \"{}\"; }} ] \"x/bar.nix\")",
+        dir.join("env").display()
+    );
+    assert_eq!(eval(&find_file), "3");
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn native_builtins() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    ctx.register_builtin(
+        PrimOp::new("double", 1, |ctx, args, _span| match ctx.force(&args[0])? {
+            Value::Int(i) => Ok(Value::Int(i * 2)),
+            other => Ok(other),
+        })
+        .doc("Doubles an integer."),
+    );
+    ctx.register_builtin(PrimOp::new("ignore", 1, |_, _, _| Ok(Value::Null)));
+    ctx.register_builtin(PrimOp::new("force", 1, |_, _, _| Ok(Value::Null)).strict(true));
+
+    let mut eval = |expr: &str| {
+        ctx.eval(Source::Other {
+            source: expr,
+            name: "<test>",
+            search_path: Path::new("."),
+        })
+        .ok()
+        .map(|value| value.to_string())
+    };
+    assert_eq!(eval("double 21"), Some("42".to_string()));
+    assert_eq!(eval("builtins.double 2"), Some("4".to_string()));
+    assert_eq!(eval("ignore (1 / 0)"), Some("null".to_string()));
+    assert_eq!(eval("force (1 / 0)"), None);
+    assert_eq!(eval("builtins.true"), Some("true".to_string()));
+}
+
+#[test]
+fn native_values() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    let triple = ctx
+        .eval(Source::Other {
+            source: "x: x * 3",
+            name: "<test>",
+            search_path: Path::new("."),
+        })
+        .unwrap();
+    ctx.register_value("triple", triple);
+    ctx.register_value("answer", Value::Int(42));
+
+    let mut eval = |expr: &str| {
+        ctx.eval(Source::Other {
+            source: expr,
+            name: "<test>",
+            search_path: Path::new("."),
+        })
+        .ok()
+        .map(|value| value.to_string())
+    };
+    assert_eq!(eval("triple 2"), Some("6".to_string()));
+    assert_eq!(eval("builtins.triple 3"), Some("9".to_string()));
+    assert_eq!(eval("answer"), Some("42".to_string()));
+    assert_eq!(eval("builtins.answer + 1"), Some("43".to_string()));
+}
+
+#[test]
+fn printing() {
+    assert_eq!(print("{ a = 1 + 1; }", Printer::new()), "{ a = «thunk»; }");
+    assert_eq!(
+        print("{ a = [ 1 \"\\${x}\" ]; \"b c\" = null; }", Printer::new().strict(true)),
+        "{ a = [ 1 \"\\${x}\" ]; \"b c\" = null; }"
+    );
+    assert_eq!(
+        print("let x = { inherit x; }; in x", Printer::new().strict(true)),
+        "{ x = «repeated»; }"
+    );
+    assert_eq!(print("{ f = x: x; }.f", Printer::new()), "«lambda @ <test>:1»");
+    assert_eq!(print("import", Printer::new()), "«primop import»");
+    assert_eq!(
+        print("{ a = [ 1 2 ]; b = 3; }", Printer::new().strict(true).width(12)),
+        "{\n  a = [ 1 2 ];\n  b = 3;\n}"
+    );
+}
+
+#[test]
+fn json_and_xml() {
+    let json = Printer::new().format(Format::Json);
+    assert_eq!(
+        print("{ b = [ 1 2.5 null ]; a = \"x\\n\"; }", json.clone()),
+        "{\"a\":\"x\\n\",\"b\":[1,2.5,null]}"
+    );
+    assert_eq!(print("{ outPath = \"/out\"; x = 1; }", json.clone()), "\"/out\"");
+    assert_eq!(print("{ __toString = s: \"str\"; }", json.clone()), "\"str\"");
+    assert_eq!(try_print("{ f = x: x; }", json.clone()), None);
+    // Paths can't be copied to the store, so they have to be allowed explicitly
+    assert_eq!(try_print("[ /a/b ]", json.clone()), None);
+    assert_eq!(print("[ /a/b ]", json.clone().verbatim_paths(true)), "[\"/a/b\"]");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_nxt"))
+        .args(&["--color=never", "eval", "--json", "{ f = import; }"])
+        .output()
+        .expect("failed to run nxt");
+    let error = String::from_utf8_lossy(&output.stderr);
+    assert!(error.contains("cannot convert the primop `import` to JSON"), "{}", error);
+    let output = Command::new(env!("CARGO_BIN_EXE_nxt"))
+        .args(&["--color=never", "eval", "--json", "--verbatim-paths", "/a/b"])
+        .output()
+        .expect("failed to run nxt");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "\"/a/b\"\n");
+
+    let xml = Printer::new().format(Format::Xml).strict(true);
+    assert_eq!(
+        print("{ a = [ true ]; f = { x, ... }@args: x; }", xml),
+        "<?xml version='1.0' encoding='utf-8'?>\n\
+         <expr>\n\
+         \x20 <attrs>\n\
+         \x20   <attr name=\"a\">\n\
+         \x20     <list>\n\
+         \x20       <bool value=\"true\" />\n\
+         \x20     </list>\n\
+         \x20   </attr>\n\
+         \x20   <attr name=\"f\">\n\
+         \x20     <function>\n\
+         \x20       <attrspat ellipsis=\"1\" name=\"args\">\n\
+         \x20         <attr name=\"x\" />\n\
+         \x20       </attrspat>\n\
+         \x20     </function>\n\
+         \x20   </attr>\n\
+         \x20 </attrs>\n\
+         </expr>\n"
+    );
+}
+
+#[test]
+fn attribute_paths() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    let value = ctx
+        .eval(Source::Other {
+            source: "{ greeting ? \"hello\", name }: { a.\"b.c\" = [ { x = greeting + \" \" + name; } ]; }",
+            name: "<test>",
+            search_path: Path::new("."),
+        })
+        .unwrap();
+
+    let mut args = BTreeMap::new();
+    assert!(ctx.auto_call(value.clone(), &args).is_err());
+
+    args.insert("name".to_string(), "world".into_thunk());
+    let selected = ctx.select_attr_path(value.clone(), "a.\"b.c\".0.x", &args).unwrap();
+    assert_eq!(selected.to_string(), "\"hello world\"");
+    assert!(ctx.select_attr_path(value.clone(), "a.\"b.c\".1", &args).is_err());
+    assert!(ctx.select_attr_path(value, "a.b", &args).is_err());
+}
+
+#[test]
+fn scope_and_incomplete_input() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    let mut scope = BTreeMap::new();
+    scope.insert("x".to_string(), 20.into_thunk());
+    scope.insert("true".to_string(), false.into_thunk());
+    let value = ctx
+        .eval_in_scope(
+            Source::Other {
+                source: "if true then 0 else x * 2 + 2",
+                name: "<test>",
+                search_path: Path::new("."),
+            },
+            &scope,
+        )
+        .unwrap();
+    assert_eq!(value.to_string(), "42");
+
+    assert!(nxt::is_incomplete("{ a = 1;"));
+    assert!(nxt::is_incomplete("let x = [ 1"));
+    assert!(!nxt::is_incomplete("{ a = 1; }"));
+    assert!(!nxt::is_incomplete("{ a = ; }"));
+}
+
+#[test]
+fn syntax_trees() {
+    let arenas = Arenas::new();
+    let mut ctx = EvalContext::new(Config::default(), &arenas);
+    let mut tree = |source, kind| {
+        ctx.syntax_tree(
+            Source::Other {
+                source,
+                name: "<test>",
+                search_path: Path::new("."),
+            },
+            kind,
+        )
+    };
+
+    let lowered = tree("x: y: x + 1", TreeKind::Lowered).unwrap();
+    assert_eq!(lowered.kind(), "Lambda");
+    let text = lowered.to_string();
+    assert!(text.starts_with("Lambda @ 1:1-1:12 captures=[]\n"), "{}", text);
+    assert!(text.contains("param: Binding @ 1:1-1:2"), "{}", text);
+    assert!(text.contains("op=\"+\""), "{}", text);
+    assert!(text.contains("Value type=\"int\" value=1"), "{}", text);
+    let json = lowered.to_json();
+    assert!(json.starts_with("{\"kind\":\"Lambda\",\"location\":{\"line\":1,\"column\":1,"));
+    assert!(json.contains("\"captures\":[{\"kind\":\"Variable\""), "{}", json);
+
+    // Raw trees can be dumped even if there are syntax errors
+    assert!(tree("{ a = ; }", TreeKind::Lowered).is_err());
+    let raw = tree("{ a = ; }", TreeKind::Raw).unwrap();
+    assert!(raw.to_string().contains("text=\"a\""), "{}", raw);
+
+    // `nxt parse` dumps the lowered AST unless `--raw` is passed
+    let parse = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_nxt"))
+            .arg("parse")
+            .args(args)
+            .output()
+            .expect("failed to run nxt");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    assert!(parse(&["1"]).starts_with("Value @ 1:1-1:2"), "{}", parse(&["1"]));
+    assert!(parse(&["--raw", "1"]).contains("text=\"1\""), "{}", parse(&["--raw", "1"]));
+}
+
+#[test]
+fn type_inspection() {
+    assert_eq!(eval("builtins.typeOf 1"), "\"int\"");
+    assert_eq!(eval("builtins.typeOf 1.5"), "\"float\"");
+    assert_eq!(eval("builtins.typeOf true"), "\"bool\"");
+    assert_eq!(eval("builtins.typeOf \"s\""), "\"string\"");
+    assert_eq!(eval("builtins.typeOf ./."), "\"path\"");
+    assert_eq!(eval("builtins.typeOf null"), "\"null\"");
+    assert_eq!(eval("builtins.typeOf []"), "\"list\"");
+    assert_eq!(eval("builtins.typeOf {}"), "\"set\"");
+    assert_eq!(eval("builtins.typeOf (x: x)"), "\"lambda\"");
+    assert_eq!(eval("builtins.typeOf builtins.isInt"), "\"lambda\"");
+
+    assert_eq!(eval("builtins.isInt 1"), "true");
+    assert_eq!(eval("builtins.isInt 1.0"), "false");
+    assert_eq!(eval("builtins.isFloat 1.0"), "true");
+    assert_eq!(eval("builtins.isBool false"), "true");
+    assert_eq!(eval("builtins.isString ./."), "false");
+    assert_eq!(eval("builtins.isPath ./."), "true");
+    assert_eq!(eval("builtins.isList [ 1 ]"), "true");
+    assert_eq!(eval("builtins.isAttrs { }"), "true");
+    assert_eq!(eval("builtins.isFunction builtins.isFunction"), "true");
+    assert_eq!(eval("isNull null && builtins.isNull builtins.null"), "true");
+    assert_eq!(eval("__isInt 1"), "true");
+
+    assert_eq!(try_eval("throw \"no\""), None);
+    assert_eq!(try_eval("abort \"no\""), None);
+    assert_eq!(eval("(x: 1) (throw \"lazy\")"), "1");
+}
+
+#[test]
+fn list_builtins() {
+    let strict = |expr| print(expr, Printer::new().strict(true));
+    assert_eq!(eval("builtins.length [ 1 2 3 ]"), "3");
+    assert_eq!(eval("builtins.length (builtins.genList (x: throw \"no\") 3)"), "3");
+    assert_eq!(eval("builtins.head [ 1 (throw \"no\") ]"), "1");
+    assert_eq!(strict("builtins.tail [ 1 2 3 ]"), "[ 2 3 ]");
+    assert_eq!(eval("builtins.elemAt [ 1 2 3 ] 2"), "3");
+    assert_eq!(try_eval("builtins.elemAt [ 1 2 3 ] 3"), None);
+    assert_eq!(try_eval("builtins.head [ ]"), None);
+    assert_eq!(try_eval("builtins.tail [ ]"), None);
+    assert_eq!(eval("builtins.elem { a = 1; } [ 1 { a = 1; } ]"), "true");
+    assert_eq!(eval("builtins.elem 3 [ 1 2 ]"), "false");
+
+    assert_eq!(strict("map (x: x * 2) [ 1 2 3 ]"), "[ 2 4 6 ]");
+    assert_eq!(eval("builtins.length (map (x: throw \"no\") [ 1 2 ])"), "2");
+    assert_eq!(strict("builtins.filter (x: x > 1) [ 1 2 3 ]"), "[ 2 3 ]");
+    assert_eq!(eval("builtins.foldl' (acc: x: acc + x) 0 [ 1 2 3 ]"), "6");
+    assert_eq!(eval("builtins.foldl' (acc: x: acc - x) 10 [ 1 2 ]"), "7");
+    assert_eq!(strict("builtins.concatLists [ [ 1 ] [ ] [ 2 3 ] ]"), "[ 1 2 3 ]");
+    assert_eq!(strict("builtins.concatMap (x: [ x x ]) [ 1 2 ]"), "[ 1 1 2 2 ]");
+    assert_eq!(strict("builtins.genList (x: x * x) 4"), "[ 0 1 4 9 ]");
+    assert_eq!(try_eval("builtins.genList (x: x) (-1)"), None);
+
+    assert_eq!(strict("builtins.sort (a: b: a < b) [ 3 1 2 ]"), "[ 1 2 3 ]");
+    assert_eq!(
+        strict(
+            "map (x: x.v) (builtins.sort (a: b: a.k < b.k) \
+             [ { k = 2; v = 1; } { k = 1; v = 2; } { k = 2; v = 3; } { k = 1; v = 4; } ])"
+        ),
+        "[ 2 4 1 3 ]"
+    );
+    assert_eq!(
+        strict("builtins.partition (x: x > 2) [ 1 3 2 4 ]"),
+        "{ right = [ 3 4 ]; wrong = [ 1 2 ]; }"
+    );
+    assert_eq!(
+        strict("builtins.groupBy (x: if x > 2 then \"big\" else \"small\") [ 1 3 2 4 ]"),
+        "{ big = [ 3 4 ]; small = [ 1 2 ]; }"
+    );
+    assert_eq!(eval("builtins.all (x: x > 0) [ 1 2 ]"), "true");
+    assert_eq!(eval("builtins.all (x: x > 1) [ 1 (throw \"no\") ]"), "false");
+    assert_eq!(eval("builtins.any (x: x > 1) [ 1 2 ]"), "true");
+    assert_eq!(eval("builtins.any (x: x > 1) [ ]"), "false");
+    assert_eq!(
+        strict("builtins.listToAttrs [ { name = \"a\"; value = 1; } { name = \"a\"; value = 2; } ]"),
+        "{ a = 1; }"
+    );
+    assert_eq!(
+        eval("(builtins.listToAttrs [ { name = \"a\"; value = throw \"no\"; } ]) ? a"),
+        "true"
+    );
+}
+
+#[test]
+fn attribute_set_builtins() {
+    let strict = |expr| print(expr, Printer::new().strict(true));
+    assert_eq!(strict("builtins.attrNames { b = 1; a = 2; }"), "[ \"a\" \"b\" ]");
+    assert_eq!(strict("builtins.attrValues { b = 1; a = 2; }"), "[ 2 1 ]");
+    assert_eq!(eval("builtins.getAttr \"a\" { a = 1; }"), "1");
+    assert_eq!(try_eval("builtins.getAttr \"b\" { a = 1; }"), None);
+    assert_eq!(eval("builtins.hasAttr \"a\" { a = 1; }"), "true");
+    assert_eq!(
+        strict("removeAttrs { a = 1; b = 2; c = 3; } [ \"a\" \"c\" \"d\" ]"),
+        "{ b = 2; }"
+    );
+    assert_eq!(
+        strict("builtins.intersectAttrs { a = 0; c = 0; } { a = 1; b = 2; c = 3; }"),
+        "{ a = 1; c = 3; }"
+    );
+    assert_eq!(
+        strict("builtins.catAttrs \"a\" [ { a = 1; } { b = 2; } { a = 3; } ]"),
+        "[ 1 3 ]"
+    );
+    assert_eq!(
+        strict("builtins.mapAttrs (name: value: name + (if value == 1 then \"1\" else \"?\")) { a = 1; }"),
+        "{ a = \"a1\"; }"
+    );
+    assert_eq!(
+        eval("(builtins.mapAttrs (name: value: throw \"no\") { a = 1; }) ? a"),
+        "true"
+    );
+    assert_eq!(
+        strict("builtins.zipAttrsWith (name: values: values) [ { a = 1; } { a = 2; b = 3; } ]"),
+        "{ a = [ 1 2 ]; b = [ 3 ]; }"
+    );
+    assert_eq!(
+        strict("builtins.functionArgs ({ a, b ? 1, ... }: a)"),
+        "{ a = false; b = true; }"
+    );
+    assert_eq!(strict("builtins.functionArgs (x: x)"), "{ }");
+
+    assert_eq!(
+        strict("builtins.unsafeGetAttrPos \"b\" {\n  a = 1;\n  b = 2;\n}"),
+        "{ column = 3; file = \"<test>\"; line = 3; }"
+    );
+    // `//` keeps the positions of the attributes of both sets
+    assert_eq!(
+        strict("builtins.unsafeGetAttrPos \"c\" ({ a = 1; } // { c = 1; })"),
+        "{ column = 48; file = \"<test>\"; line = 1; }"
+    );
+    assert_eq!(
+        strict("builtins.unsafeGetAttrPos \"a\" ({ a = 1; } // { c = 1; })"),
+        "{ column = 34; file = \"<test>\"; line = 1; }"
+    );
+    assert_eq!(
+        strict("builtins.unsafeGetAttrPos \"a\" ({ a = 1; } // { a = 2; })"),
+        "{ column = 48; file = \"<test>\"; line = 1; }"
+    );
+    assert_eq!(
+        strict("builtins.unsafeGetAttrPos \"a\" ({ a = 1; } // builtins.listToAttrs [ { name = \"a\"; value = 2; } ])"),
+        "null"
+    );
+}
+
+#[test]
+fn string_builtins() {
+    let strict = |expr| print(expr, Printer::new().strict(true));
+    assert_eq!(eval("builtins.stringLength \"abc\""), "3");
+    assert_eq!(eval("builtins.stringLength \"é\""), "2");
+    assert_eq!(eval("builtins.substring 1 2 \"abcd\""), "\"bc\"");
+    assert_eq!(eval("builtins.substring 2 (-1) \"abcd\""), "\"cd\"");
+    assert_eq!(eval("builtins.substring 10 1 \"abcd\""), "\"\"");
+    assert_eq!(try_eval("builtins.substring (-1) 1 \"abcd\""), None);
+    // Substrings can split characters without losing bytes
+    assert_eq!(eval("builtins.stringLength (builtins.substring 0 1 \"é\")"), "1");
+    assert_eq!(
+        eval("with builtins; substring 0 1 \"é\" + substring 1 1 \"é\" == \"é\""),
+        "true"
+    );
+    // Strings that aren't valid UTF-8 can't be attribute names or printed
+    let invalid = "(builtins.substring 0 1 \"é\")";
+    let error = eval_error(&format!("{{ ${{{}}} = 1; }}", invalid));
+    assert!(error.contains("is not valid UTF-8"), "{}", error);
+    assert_eq!(try_eval(&format!("{{ }}.${{{}}} or 1", invalid)), None);
+    assert_eq!(
+        try_eval(&format!("builtins.listToAttrs [ {{ name = {}; value = 1; }} ]", invalid)),
+        None
+    );
+    assert_eq!(try_print(invalid, Printer::new()), None);
+    assert_eq!(try_print(&format!("[ {} ]", invalid), Printer::new().format(Format::Json)), None);
+    assert_eq!(
+        eval("builtins.replaceStrings [ \"o\" \"a\" ] [ \"a\" \"o\" ] \"foobar\""),
+        "\"faabor\""
+    );
+    assert_eq!(eval("builtins.replaceStrings [ \"\" ] [ \"-\" ] \"ab\""), "\"-a-b-\"");
+    assert_eq!(try_eval("builtins.replaceStrings [ \"a\" ] [ ] \"ab\""), None);
+    assert_eq!(
+        eval("builtins.concatStringsSep \", \" [ \"a\" \"b\" { outPath = \"c\"; } ]"),
+        "\"a, b, c\""
+    );
+
+    assert_eq!(eval("toString 1"), "\"1\"");
+    assert_eq!(eval("toString 1.5"), "\"1.500000\"");
+    assert_eq!(eval("toString true + toString false + toString null"), "\"1\"");
+    assert_eq!(eval("toString [ 1 [ ] [ 2 3 ] \"a\" ]"), "\"1 2 3 a\"");
+    assert_eq!(eval("toString { __toString = self: 42; }"), "\"42\"");
+    assert_eq!(try_eval("toString (x: x)"), None);
+
+    assert_eq!(eval("baseNameOf \"/a/b/\""), "\"b\"");
+    assert_eq!(eval("baseNameOf \"a\""), "\"a\"");
+    assert_eq!(eval("dirOf \"/a/b\""), "\"/a\"");
+    assert_eq!(eval("dirOf \"/a\""), "\"/\"");
+    assert_eq!(eval("dirOf \"a\""), "\".\"");
+    assert_eq!(eval("builtins.isPath (dirOf /a/b)"), "true");
+
+    assert_eq!(
+        strict("builtins.splitVersion \"1.2pre3-rc.4\""),
+        "[ \"1\" \"2\" \"pre\" \"3\" \"rc\" \"4\" ]"
+    );
+    assert_eq!(eval("builtins.compareVersions \"1.2\" \"1.10\""), "-1");
+    assert_eq!(eval("builtins.compareVersions \"1.2\" \"1.2\""), "0");
+    assert_eq!(eval("builtins.compareVersions \"1.2\" \"1.2pre1\""), "1");
+    assert_eq!(eval("builtins.compareVersions \"1.2a\" \"1.2\""), "1");
+    assert_eq!(eval("builtins.compareVersions \"1.2\" \"1.2.0\""), "-1");
+    assert_eq!(
+        strict("builtins.parseDrvName \"nix-unstable-0.12pre1\""),
+        "{ name = \"nix-unstable\"; version = \"0.12pre1\"; }"
+    );
+    assert_eq!(
+        strict("builtins.parseDrvName \"hello\""),
+        "{ name = \"hello\"; version = \"\"; }"
+    );
+}
+
+#[test]
+fn regex_builtins() {
+    let strict = |expr| print(expr, Printer::new().strict(true));
+    assert_eq!(eval("builtins.match \"a+\" \"aaa\""), "[ ]");
+    assert_eq!(eval("builtins.match \"a+\" \"aab\""), "null");
+    assert_eq!(
+        strict("builtins.match \"([^/]*)/(.*)\" \"foo/bar/baz\""),
+        "[ \"foo\" \"bar/baz\" ]"
+    );
+    assert_eq!(strict("builtins.match \"(a)|(b)\" \"b\""), "[ null \"b\" ]");
+    assert_eq!(strict("builtins.match \"[[:alpha:]_-]+\\\\.nix\" \"a_b-c.nix\""), "[ ]");
+    assert_eq!(strict("builtins.match \"[]a]*\" \"]a]\""), "[ ]");
+    assert_eq!(try_eval("builtins.match \"(\" \"\""), None);
+
+    assert_eq!(
+        strict("builtins.split \"(a)|b\" \"xaybz\""),
+        "[ \"x\" [ \"a\" ] \"y\" [ null ] \"z\" ]"
+    );
+    assert_eq!(strict("builtins.split \",\" \"abc\""), "[ \"abc\" ]");
+    assert_eq!(
+        strict("builtins.split \"x*\" \"ab\""),
+        "[ \"\" [ ] \"a\" [ ] \"b\" [ ] \"\" ]"
+    );
+    assert_eq!(
+        strict("map (builtins.match \"[0-9]+\") [ \"1\" \"a\" \"23\" ]"),
+        "[ [ ] null [ ] ]"
+    );
+
+    // Like in Nix, the longest match is used, and among matches of the same
+    // length the first one found
+    assert_eq!(strict("builtins.split \"a|ab\" \"xabx\""), "[ \"x\" [ ] \"x\" ]");
+    assert_eq!(
+        strict("builtins.match \"(a|ab)(c|bcd)(d*)\" \"abcd\""),
+        "[ \"a\" \"bcd\" \"\" ]"
+    );
+    assert_eq!(strict("builtins.match \"(a*)*\" \"\""), "[ \"\" ]");
+
+    // Regexes match bytes, not characters
+    assert_eq!(eval("builtins.match \".\" \"é\""), "null");
+    assert_eq!(
+        eval("with builtins; stringLength (head (match \"(.).*\" \"é\"))"),
+        "1"
+    );
+
+    // Long strings and large programs don't need memory for every state
+    let long = "foldl' (s: _: s + s) \"ab\" (genList (x: x) 17)";
+    assert_eq!(
+        eval(&format!(
+            "with builtins; stringLength (head (match \"(([ab]{{255}}){{200}}).*\" ({})))",
+            long
+        )),
+        "51000"
+    );
+    assert_eq!(
+        eval(&format!("with builtins; length (split \"b{{1,255}}\" ({}))", long)),
+        "262145"
+    );
+}
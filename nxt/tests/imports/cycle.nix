@@ -0,0 +1,2 @@
+# Importing a file while it's being evaluated never terminates
+import ./cycle.nix + 1
@@ -0,0 +1,2 @@
+# Only the attribute is evaluated, after the file has been imported
+let self = import ./lazy.nix; in { a = 1; b = self.a; }
@@ -0,0 +1,3 @@
+{
+  value = import ./value.nix;
+}
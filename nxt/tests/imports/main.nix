@@ -0,0 +1,2 @@
+# Relative paths are resolved against the directory of the importing file
+(import ./lib).value + import ./lib/value.nix
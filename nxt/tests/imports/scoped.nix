@@ -0,0 +1,2 @@
+# `x` and `y` have to be provided by `scopedImport`
+x * 10 + y